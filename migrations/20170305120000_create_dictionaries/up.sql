@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS dictionaries (
+	id	INTEGER PRIMARY KEY,
+	name	BLOB
+);
+
+CREATE UNIQUE INDEX IF NOT EXISTS Dictionaries_UniqueName ON dictionaries(name);
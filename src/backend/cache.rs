@@ -0,0 +1,474 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A backend that caches the blobs read from another backend.
+//!
+//! Reading a file back reads neighbouring chunks from the same blob, so caching whole blobs saves
+//! most reads from remote backends. Recently used blobs are kept in memory, up to a limit on their
+//! total size. Blobs pushed out of memory can spill to a local directory, which has a limit of its
+//! own. Stores, replacements and deletes drop the blob from the cache, and keep reads that were
+//! running meanwhile from caching what they read.
+
+use backend::{BackendError, StoreBackend};
+use crypto::CipherText;
+use rustc_serialize::hex::{FromHex, ToHex};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// Reads answered from memory.
+    pub hits: u64,
+    /// Reads answered from the spill directory.
+    pub disk_hits: u64,
+    /// Reads passed on to the backend.
+    pub misses: u64,
+}
+
+/// Least recently used entries, bounded by their total size.
+struct Lru<V> {
+    /// Last use, size and value of each entry.
+    entries: HashMap<Vec<u8>, (u64, usize, V)>,
+    /// Entries by last use.
+    order: BTreeMap<u64, Vec<u8>>,
+    clock: u64,
+    bytes: usize,
+    max_bytes: usize,
+}
+
+impl<V> Lru<V> {
+    fn new(max_bytes: usize) -> Lru<V> {
+        Lru {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            clock: 0,
+            bytes: 0,
+            max_bytes: max_bytes,
+        }
+    }
+
+    fn get(&mut self, name: &[u8]) -> Option<&V> {
+        match self.entries.get_mut(name) {
+            None => None,
+            Some(entry) => {
+                self.clock += 1;
+                let name = self.order.remove(&entry.0).unwrap();
+                self.order.insert(self.clock, name);
+                entry.0 = self.clock;
+                Some(&entry.2)
+            }
+        }
+    }
+
+    /// Add an entry, and return the entries pushed out to make room for it. Entries larger than
+    /// the whole cache are returned right away.
+    fn insert(&mut self, name: Vec<u8>, size: usize, value: V) -> Vec<(Vec<u8>, V)> {
+        self.remove(&name);
+        if size > self.max_bytes {
+            return vec![(name, value)];
+        }
+        self.clock += 1;
+        self.order.insert(self.clock, name.clone());
+        self.entries.insert(name, (self.clock, size, value));
+        self.bytes += size;
+
+        let mut evicted = vec![];
+        while self.bytes > self.max_bytes {
+            let oldest = *self.order.keys().next().unwrap();
+            let name = self.order.remove(&oldest).unwrap();
+            let (_, size, value) = self.entries.remove(&name).unwrap();
+            self.bytes -= size;
+            evicted.push((name, value));
+        }
+        evicted
+    }
+
+    fn remove(&mut self, name: &[u8]) -> Option<V> {
+        self.entries.remove(name).map(|(used, size, value)| {
+            self.order.remove(&used);
+            self.bytes -= size;
+            value
+        })
+    }
+}
+
+struct Cache {
+    memory: Lru<Vec<u8>>,
+    disk: Lru<()>,
+    stats: CacheStats,
+    /// The number of reads in progress of each name being read, and how often the name was
+    /// invalidated since the first of them started.
+    reads: HashMap<Vec<u8>, (usize, u64)>,
+}
+
+impl Cache {
+    /// Note the start of a read of `name`, and return the generation to pass to `end_read`.
+    fn start_read(&mut self, name: &[u8]) -> u64 {
+        let read = self.reads.entry(name.to_vec()).or_insert((0, 0));
+        read.0 += 1;
+        read.1
+    }
+
+    /// Note the end of a read of `name`, and return whether `name` stayed valid during it.
+    fn end_read(&mut self, name: &[u8], generation: u64) -> bool {
+        let (readers, current) = {
+            let read = self.reads.get_mut(name).unwrap();
+            read.0 -= 1;
+            *read
+        };
+        if readers == 0 {
+            self.reads.remove(name);
+        }
+        current == generation
+    }
+}
+
+pub struct CachingBackend<B> {
+    backend: B,
+    cache: Mutex<Cache>,
+    /// Directory that blobs pushed out of memory spill to.
+    spill_dir: Option<PathBuf>,
+}
+
+impl<B: StoreBackend> CachingBackend<B> {
+    /// Cache up to `memory_bytes` of blobs in memory.
+    pub fn new(backend: B, memory_bytes: usize) -> CachingBackend<B> {
+        CachingBackend {
+            backend: backend,
+            cache: Mutex::new(Cache {
+                memory: Lru::new(memory_bytes),
+                disk: Lru::new(0),
+                stats: CacheStats::default(),
+                reads: HashMap::new(),
+            }),
+            spill_dir: None,
+        }
+    }
+
+    /// Cache up to `memory_bytes` of blobs in memory, and up to `disk_bytes` more in `dir`. Blobs
+    /// cached in `dir` by earlier runs may be stale, so they are removed.
+    pub fn with_spill_dir(backend: B,
+                          memory_bytes: usize,
+                          dir: PathBuf,
+                          disk_bytes: usize)
+                          -> Result<CachingBackend<B>, BackendError> {
+        fs::create_dir_all(&dir)?;
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let ours = path.file_name()
+                .and_then(|n| n.to_str())
+                .map_or(false, |n| n.ends_with(".tmp") || n.from_hex().is_ok());
+            if ours {
+                fs::remove_file(&path)?;
+            }
+        }
+
+        let mut backend = CachingBackend::new(backend, memory_bytes);
+        backend.cache.get_mut().unwrap().disk = Lru::new(disk_bytes);
+        backend.spill_dir = Some(dir);
+        Ok(backend)
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats
+    }
+
+    fn spill_path(&self, name: &[u8]) -> Option<PathBuf> {
+        self.spill_dir.as_ref().map(|dir| dir.join(name.to_hex()))
+    }
+
+    fn read_spilled(&self, name: &[u8]) -> Option<Vec<u8>> {
+        let mut data = vec![];
+        let res = fs::File::open(self.spill_path(name).unwrap())
+            .and_then(|mut f| f.read_to_end(&mut data));
+        match res {
+            Ok(_) => Some(data),
+            Err(e) => {
+                warn!("Could not read cached blob {}: {}", name.to_hex(), e);
+                None
+            }
+        }
+    }
+
+    fn write_spilled(&self, name: &[u8], data: &[u8]) -> bool {
+        let path = self.spill_path(name).unwrap();
+        let tmp = path.with_extension("tmp");
+        let res = fs::File::create(&tmp)
+            .and_then(|mut f| f.write_all(data))
+            .and_then(|_| fs::rename(&tmp, &path));
+        if let Err(e) = res {
+            warn!("Could not cache blob {}: {}", name.to_hex(), e);
+            let _ = fs::remove_file(&tmp);
+            return false;
+        }
+        true
+    }
+
+    /// End a read of `name` that started at `generation`, and keep the blob it read in memory
+    /// unless the blob was invalidated meanwhile. Blobs that no longer fit in memory spill.
+    fn end_read(&self, name: &[u8], generation: u64, data: Option<Vec<u8>>) {
+        let evicted = {
+            let mut cache = self.cache.lock().unwrap();
+            let valid = cache.end_read(name, generation);
+            match data {
+                Some(data) if valid => {
+                    let size = data.len();
+                    cache.memory.insert(name.to_vec(), size, data)
+                }
+                _ => return,
+            }
+        };
+        if self.spill_dir.is_none() {
+            return;
+        }
+        for (name, data) in evicted {
+            if data.len() > self.cache.lock().unwrap().disk.max_bytes ||
+               !self.write_spilled(&name, &data) {
+                continue;
+            }
+            let dropped = self.cache.lock().unwrap().disk.insert(name, data.len(), ());
+            for (name, ()) in dropped {
+                let _ = fs::remove_file(self.spill_path(&name).unwrap());
+            }
+        }
+    }
+
+    fn invalidate(&self, name: &[u8]) {
+        let spilled = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(read) = cache.reads.get_mut(name) {
+                read.1 += 1;
+            }
+            cache.memory.remove(name);
+            cache.disk.remove(name).is_some()
+        };
+        if spilled {
+            let _ = fs::remove_file(self.spill_path(name).unwrap());
+        }
+    }
+}
+
+impl<B> Drop for CachingBackend<B> {
+    fn drop(&mut self) {
+        if let Ok(cache) = self.cache.lock() {
+            let stats = cache.stats;
+            if stats != CacheStats::default() {
+                info!("Blob cache: {} hits, {} disk hits, {} misses",
+                      stats.hits,
+                      stats.disk_hits,
+                      stats.misses);
+            }
+        }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for CachingBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let res = self.backend.store(name, data);
+        self.invalidate(name);
+        res
+    }
+
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let res = self.backend.replace(name, data);
+        self.invalidate(name);
+        res
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        let (spilled, generation) = {
+            let mut cache = self.cache.lock().unwrap();
+            if let Some(data) = cache.memory.get(name).cloned() {
+                cache.stats.hits += 1;
+                return Ok(Some(data));
+            }
+            (cache.disk.get(name).is_some(), cache.start_read(name))
+        };
+
+        if spilled {
+            match self.read_spilled(name) {
+                Some(data) => {
+                    self.cache.lock().unwrap().stats.disk_hits += 1;
+                    self.end_read(name, generation, Some(data.clone()));
+                    return Ok(Some(data));
+                }
+                None => self.invalidate(name),
+            }
+        }
+
+        self.cache.lock().unwrap().stats.misses += 1;
+        let res = self.backend.retrieve(name);
+        let data = match res {
+            Ok(Some(ref data)) => Some(data.clone()),
+            _ => None,
+        };
+        self.end_read(name, generation, data);
+        res
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.invalidate(name);
+        self.backend.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        self.backend.list()
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        self.backend.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{MemoryBackend, StoreBackend};
+    use crypto::CipherText;
+    use std::fs;
+    use std::sync::{Arc, Mutex, mpsc};
+    use std::thread;
+    use util::TempDir;
+
+    fn blob(n: u8) -> CipherText {
+        CipherText::new(vec![n; 100])
+    }
+
+    #[test]
+    fn lru_evicts_least_recently_used() {
+        let mut lru = Lru::new(300);
+        assert!(lru.insert(vec![1], 100, 1).is_empty());
+        assert!(lru.insert(vec![2], 100, 2).is_empty());
+        assert!(lru.insert(vec![3], 100, 3).is_empty());
+        assert_eq!(Some(&1), lru.get(&[1]));
+        assert_eq!(vec![(vec![2], 2)], lru.insert(vec![4], 100, 4));
+        assert_eq!(vec![(vec![3], 3), (vec![1], 1)], lru.insert(vec![5], 200, 5));
+        assert_eq!(vec![(vec![6], 6)], lru.insert(vec![6], 301, 6));
+        assert_eq!(Some(5), lru.remove(&[5]));
+        assert_eq!(100, lru.bytes);
+    }
+
+    #[test]
+    fn caches_in_memory() {
+        let backend = CachingBackend::new(MemoryBackend::new(), 250);
+        for n in 1..4 {
+            backend.store(&[n], &blob(n)).unwrap();
+        }
+        for &n in &[1, 2, 1, 2, 3, 1] {
+            assert_eq!(Some(vec![n; 100]), backend.retrieve(&[n]).unwrap());
+        }
+        // Reading 3 pushed out 1, the least recently used blob, so it was read again.
+        assert_eq!(CacheStats { hits: 2, disk_hits: 0, misses: 4 }, backend.stats());
+        assert_eq!(None, backend.retrieve(&[4]).unwrap());
+
+        backend.delete(&[1]).unwrap();
+        assert_eq!(None, backend.retrieve(&[1]).unwrap());
+        backend.replace(&[3], &blob(9)).unwrap();
+        assert_eq!(Some(vec![9; 100]), backend.retrieve(&[3]).unwrap());
+    }
+
+    #[test]
+    fn spills_to_disk() {
+        let dir = TempDir::new("hat-cache");
+        let spill = |dir: &TempDir| {
+            CachingBackend::with_spill_dir(MemoryBackend::new(), 100, dir.path().to_path_buf(), 200)
+                .unwrap()
+        };
+        let backend = spill(&dir);
+        for n in 1..5 {
+            backend.store(&[n], &blob(n)).unwrap();
+            backend.retrieve(&[n]).unwrap();
+        }
+        // 4 is in memory, 2 and 3 on disk, and 1 was dropped.
+        for &n in &[4, 3, 1] {
+            assert_eq!(Some(vec![n; 100]), backend.retrieve(&[n]).unwrap());
+        }
+        assert_eq!(CacheStats { hits: 1, disk_hits: 1, misses: 5 }, backend.stats());
+
+        backend.delete(&[4]).unwrap();
+        assert_eq!(None, backend.retrieve(&[4]).unwrap());
+        drop(backend);
+
+        // Left-over blobs are not trusted by the next run.
+        spill(&dir);
+        assert_eq!(0, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    /// A backend whose reads report that they have read the blob, and then wait until the gate is
+    /// unlocked before returning it.
+    struct GatedBackend {
+        inner: MemoryBackend,
+        started: Mutex<mpsc::Sender<()>>,
+        gate: Mutex<()>,
+    }
+
+    impl StoreBackend for GatedBackend {
+        fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+            self.inner.store(name, data)
+        }
+        fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+            self.inner.replace(name, data)
+        }
+        fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+            let res = self.inner.retrieve(name);
+            self.started.lock().unwrap().send(()).unwrap();
+            let _open = self.gate.lock().unwrap();
+            res
+        }
+        fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+            self.inner.delete(name)
+        }
+        fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+            self.inner.list()
+        }
+        fn flush(&self) -> Result<(), BackendError> {
+            self.inner.flush()
+        }
+    }
+
+    #[test]
+    fn replacing_during_a_read_keeps_the_old_blob_out() {
+        let (started, started_receiver) = mpsc::channel();
+        let backend = Arc::new(CachingBackend::new(GatedBackend {
+                                                       inner: MemoryBackend::new(),
+                                                       started: Mutex::new(started),
+                                                       gate: Mutex::new(()),
+                                                   },
+                                                   1000));
+        backend.store(&[1], &blob(1)).unwrap();
+
+        let reader = {
+            let gate = backend.backend.gate.lock().unwrap();
+            let reader = {
+                let backend = backend.clone();
+                thread::spawn(move || backend.retrieve(&[1]).unwrap())
+            };
+            started_receiver.recv().unwrap();
+            backend.replace(&[1], &blob(2)).unwrap();
+            drop(gate);
+            reader
+        };
+        assert_eq!(Some(vec![1; 100]), reader.join().unwrap());
+
+        // The read that raced with the replacement did not cache the old blob.
+        assert_eq!(Some(vec![2; 100]), backend.retrieve(&[1]).unwrap());
+        assert_eq!(CacheStats { hits: 0, disk_hits: 0, misses: 2 }, backend.stats());
+        assert!(backend.cache.lock().unwrap().reads.is_empty());
+    }
+}
@@ -0,0 +1,282 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A backend that runs external programs to store and fetch blobs.
+//!
+//! Each command is run with `/bin/sh -c` in the backend directory. Commands that take a blob get
+//! its name in hex as `$1`:
+//!
+//!  - `store` reads the blob from stdin. It is also used to replace existing blobs, so it must
+//!    never leave a partial blob in place of a whole one (e.g. write to a temporary file first
+//!    and rename it).
+//!  - `retrieve` writes the blob to stdout, or exits with status 2 if there is no such blob.
+//!  - `delete` removes the blob.
+//!  - `list` writes the names of all blobs to stdout in hex, one per line.
+//!
+//! Any other non-zero exit status is an error. Commands can report failures worth retrying with
+//! status 75 (`EX_TEMPFAIL`), and denied access with status 77 (`EX_NOPERM`).
+
+use backend::{BackendError, StoreBackend};
+use crypto::CipherText;
+use rustc_serialize::hex::{FromHex, ToHex};
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, ExitStatus, Output, Stdio};
+
+
+/// Exit status of the retrieve command for blobs that do not exist.
+pub const MISSING_BLOB_STATUS: i32 = 2;
+
+/// Exit statuses from sysexits.h.
+const EX_TEMPFAIL: i32 = 75;
+const EX_NOPERM: i32 = 77;
+
+fn failure(cmd: &str, status: ExitStatus) -> BackendError {
+    let message = format!("Command '{}' failed: {}", cmd, status);
+    match status.code() {
+        Some(EX_TEMPFAIL) => BackendError::Transient(message),
+        Some(EX_NOPERM) => BackendError::Permission(message),
+        _ => BackendError::Other(message),
+    }
+}
+
+pub struct CommandBackend {
+    dir: PathBuf,
+    store_cmd: String,
+    retrieve_cmd: String,
+    delete_cmd: String,
+    list_cmd: String,
+}
+
+impl CommandBackend {
+    pub fn new(dir: PathBuf,
+               store_cmd: String,
+               retrieve_cmd: String,
+               delete_cmd: String,
+               list_cmd: String)
+               -> CommandBackend {
+        CommandBackend {
+            dir: dir,
+            store_cmd: store_cmd,
+            retrieve_cmd: retrieve_cmd,
+            delete_cmd: delete_cmd,
+            list_cmd: list_cmd,
+        }
+    }
+
+    fn command(&self, cmd: &str, name: Option<&[u8]>) -> Command {
+        let mut c = Command::new("/bin/sh");
+        c.current_dir(&self.dir).arg("-c").arg(cmd).arg("hat");
+        if let Some(name) = name {
+            c.arg(name.to_hex());
+        }
+        // Let the command report problems directly to the user.
+        c.stderr(Stdio::inherit());
+        c
+    }
+
+    fn run(&self,
+           cmd: &str,
+           name: Option<&[u8]>,
+           input: Option<&CipherText>)
+           -> Result<Output, BackendError> {
+        // Commands either read a blob or write one, never both. Their output is only read when
+        // they take no input: a command that writes much while its input is still being fed to
+        // it would otherwise block on a full pipe, and so would we.
+        let (stdin, stdout) = if input.is_some() {
+            (Stdio::piped(), Stdio::null())
+        } else {
+            (Stdio::null(), Stdio::piped())
+        };
+        let mut child = self.command(cmd, name)
+            .stdin(stdin)
+            .stdout(stdout)
+            .spawn()
+            .map_err(|e| BackendError::from_io(&format!("Could not run '{}'", cmd), &e))?;
+
+        if let Some(data) = input {
+            let mut stdin = child.stdin.take().unwrap();
+            for slice in data.slices() {
+                if let Err(e) = stdin.write_all(slice) {
+                    // The command may have exited without reading its input (e.g. with a broken
+                    // pipe); its exit status then says more about what went wrong.
+                    drop(stdin);
+                    if let Ok(status) = child.wait() {
+                        if !status.success() {
+                            return Err(failure(cmd, status));
+                        }
+                    }
+                    return Err(BackendError::from_io(&format!("Could not write to '{}'", cmd),
+                                                     &e));
+                }
+            }
+        }
+
+        child.wait_with_output()
+            .map_err(|e| BackendError::from_io(&format!("Could not run '{}'", cmd), &e))
+    }
+
+    fn run_ok(&self,
+              cmd: &str,
+              name: Option<&[u8]>,
+              input: Option<&CipherText>)
+              -> Result<Vec<u8>, BackendError> {
+        let output = self.run(cmd, name, input)?;
+        if !output.status.success() {
+            return Err(failure(cmd, output.status));
+        }
+        Ok(output.stdout)
+    }
+}
+
+impl StoreBackend for CommandBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.run_ok(&self.store_cmd, Some(name), Some(data)).map(|_| ())
+    }
+
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        let output = self.run(&self.retrieve_cmd, Some(name), None)?;
+        match output.status.code() {
+            Some(0) => Ok(Some(output.stdout)),
+            Some(MISSING_BLOB_STATUS) => Ok(None),
+            _ => Err(failure(&self.retrieve_cmd, output.status)),
+        }
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.run_ok(&self.delete_cmd, Some(name), None).map(|_| ())
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        let out = self.run_ok(&self.list_cmd, None, None)?;
+        let out = String::from_utf8(out)
+            .map_err(|_| format!("Command '{}' listed a name that is not hex", self.list_cmd))?;
+
+        let mut names = vec![];
+        for line in out.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            match line.from_hex() {
+                Ok(name) => names.push(name.into_boxed_slice()),
+                Err(_) => {
+                    return Err(BackendError::Other(format!("Command '{}' listed a name that \
+                                                            is not hex: {}",
+                                                           self.list_cmd,
+                                                           line)))
+                }
+            }
+        }
+        Ok(names)
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{BackendError, StoreBackend};
+    use crypto::CipherText;
+    use std::env;
+    use util::TempDir;
+
+    fn shell_backend() -> (TempDir, CommandBackend) {
+        let dir = TempDir::new("hat-command-backend");
+        let backend = CommandBackend::new(dir.path().to_path_buf(),
+                                          "cat > \"$1\"".to_owned(),
+                                          "[ -e \"$1\" ] || exit 2; cat \"$1\"".to_owned(),
+                                          "rm \"$1\"".to_owned(),
+                                          "ls".to_owned());
+        (dir, backend)
+    }
+
+    #[test]
+    fn shell_script_roundtrip() {
+        let (_dir, backend) = shell_backend();
+        let name = vec![1, 2, 255];
+
+        assert_eq!(None, backend.retrieve(&name).unwrap());
+        backend.store(&name, &CipherText::new(b"hello".to_vec())).unwrap();
+        assert_eq!(Some(b"hello".to_vec()), backend.retrieve(&name).unwrap());
+        assert_eq!(vec![name.clone().into_boxed_slice()], backend.list().unwrap());
+
+        backend.delete(&name).unwrap();
+        assert_eq!(None, backend.retrieve(&name).unwrap());
+        assert!(backend.list().unwrap().is_empty());
+
+        // Deleting again fails in `rm`.
+        assert!(backend.delete(&name).is_err());
+    }
+
+    #[test]
+    fn failing_commands_are_errors() {
+        let backend = CommandBackend::new(env::temp_dir(),
+                                          "exit 1".to_owned(),
+                                          "exit 1".to_owned(),
+                                          "exit 1".to_owned(),
+                                          "echo not-hex".to_owned());
+        assert!(backend.store(&[1], &CipherText::new(vec![1])).is_err());
+        assert!(backend.retrieve(&[1]).is_err());
+        assert!(backend.delete(&[1]).is_err());
+        assert!(backend.list().is_err());
+    }
+
+    #[test]
+    fn exit_statuses_classify_errors() {
+        let backend = CommandBackend::new(env::temp_dir(),
+                                          "cat > /dev/null; exit 75".to_owned(),
+                                          "exit 77".to_owned(),
+                                          "exit 1".to_owned(),
+                                          "true".to_owned());
+        assert!(backend.store(&[1], &CipherText::new(vec![1])).unwrap_err().is_transient());
+        match backend.retrieve(&[1]) {
+            Err(BackendError::Permission(..)) => (),
+            other => panic!("Expected a permission error: {:?}", other),
+        }
+        assert!(!backend.delete(&[1]).unwrap_err().is_transient());
+    }
+
+    #[test]
+    fn store_command_may_exit_without_reading() {
+        let backend = CommandBackend::new(env::temp_dir(),
+                                          "exit 77".to_owned(),
+                                          "exit 1".to_owned(),
+                                          "exit 1".to_owned(),
+                                          "true".to_owned());
+        // Much more than fits in a pipe, so that writing fails once the command has exited.
+        match backend.store(&[1], &CipherText::new(vec![7; 1 << 20])) {
+            Err(BackendError::Permission(..)) => (),
+            other => panic!("Expected a permission error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn store_command_may_write_output() {
+        let (dir, _) = shell_backend();
+        let backend = CommandBackend::new(dir.path().to_path_buf(),
+                                          "tee \"$1\"".to_owned(),
+                                          "cat \"$1\"".to_owned(),
+                                          "rm \"$1\"".to_owned(),
+                                          "ls".to_owned());
+        // Much more than fits in a pipe, which is echoed while we are still writing.
+        let blob = vec![7; 1 << 20];
+        backend.store(&[1], &CipherText::new(blob.clone())).unwrap();
+        assert_eq!(Some(blob), backend.retrieve(&[1]).unwrap());
+    }
+}
@@ -12,29 +12,33 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use backend::StoreBackend;
+use backend::{BackendError, StoreBackend};
 use crypto::CipherText;
 
 pub struct DevNullBackend;
 
 impl StoreBackend for DevNullBackend {
-    fn store(&self, _name: &[u8], _data: &CipherText) -> Result<(), String> {
+    fn store(&self, _name: &[u8], _data: &CipherText) -> Result<(), BackendError> {
         Ok(())
     }
 
-    fn retrieve(&self, _name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn replace(&self, _name: &[u8], _data: &CipherText) -> Result<(), BackendError> {
+        Ok(())
+    }
+
+    fn retrieve(&self, _name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
         Ok(None)
     }
 
-    fn delete(&self, _name: &[u8]) -> Result<(), String> {
+    fn delete(&self, _name: &[u8]) -> Result<(), BackendError> {
         Ok(())
     }
 
-    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
         Ok(vec![])
     }
 
-    fn flush(&self) -> Result<(), String> {
+    fn flush(&self) -> Result<(), BackendError> {
         Ok(())
     }
 }
@@ -0,0 +1,820 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A backend that spreads every blob over several other backends with Reed-Solomon coding.
+//!
+//! A blob is cut into `k` data shards, and `m` parity shards are computed from them. Shard `i` is
+//! stored in backend `i` under the name of the blob, and any `k` intact shards are enough to read
+//! the blob back. This survives the loss of `m` backends while storing only `(k + m) / k` times
+//! the data, where mirroring would store `m + 1` times the data.
+//!
+//! Each shard carries a checksum, so damaged shards are treated as lost, and the length, hash and
+//! generation of its blob. Shards are only combined with shards of the same blob, and the newest
+//! generation that can be read wins, so a blob that was only partly replaced still reads back as
+//! one of its versions. `scrub` rebuilds the lost and outdated shards of all blobs.
+
+use backend::{BackendError, StoreBackend, on_all, worst_error};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crypto::CipherText;
+use rustc_serialize::hex::ToHex;
+use scoped_pool::Pool;
+use sodiumoxide::crypto::hash::sha256;
+use std::cmp;
+use std::collections::BTreeSet;
+
+
+const SHARD_VERSION: u8 = 2;
+
+/// Version, shard index, data shards and parity shards, then the length, generation and hash of
+/// the blob.
+const SHARD_HEADER_LEN: usize = 20 + sha256::DIGESTBYTES;
+
+/// While a blob is being replaced, its new shards are also stored under this prefix to its name.
+const REPLACEMENT_PREFIX: &'static [u8] = b"replacement-";
+
+fn replacement_name(name: &[u8]) -> Vec<u8> {
+    let mut replacement = REPLACEMENT_PREFIX.to_vec();
+    replacement.extend_from_slice(name);
+    replacement
+}
+
+fn is_replacement(name: &[u8]) -> bool {
+    name.starts_with(REPLACEMENT_PREFIX)
+}
+
+pub struct ErasureBackend {
+    backends: Vec<Box<StoreBackend>>,
+    codec: Codec,
+    pool: Pool,
+}
+
+/// The version of a blob that a shard belongs to, as recorded in its header.
+#[derive(Clone, Debug, PartialEq)]
+struct BlobVersion {
+    length: u64,
+    /// Replacing a blob stores it again with a higher generation.
+    generation: u64,
+    hash: Vec<u8>,
+}
+
+/// The intact shards of one version of a blob.
+struct Group {
+    version: BlobVersion,
+    shards: Vec<Option<Vec<u8>>>,
+}
+
+impl Group {
+    fn intact(&self) -> usize {
+        self.shards.iter().filter(|s| s.is_some()).count()
+    }
+}
+
+/// The shards of a blob as found in the backends.
+struct Shards {
+    /// The intact shards, grouped by the version of the blob they belong to.
+    groups: Vec<Group>,
+    /// Backends holding a shard of the blob, intact or not.
+    present: Vec<bool>,
+    /// Shards that exist, but are damaged.
+    damaged: Vec<usize>,
+    /// Backends that could not be read, and their errors.
+    failed: Vec<usize>,
+    errors: Vec<BackendError>,
+}
+
+impl Shards {
+    fn new(backends: usize) -> Shards {
+        Shards {
+            groups: vec![],
+            present: vec![false; backends],
+            damaged: vec![],
+            failed: vec![],
+            errors: vec![],
+        }
+    }
+
+    fn add(&mut self, index: usize, version: BlobVersion, payload: Vec<u8>) {
+        let pos = match self.groups.iter().position(|g| g.version == version) {
+            Some(pos) => pos,
+            None => {
+                let backends = self.present.len();
+                self.groups.push(Group {
+                    version: version,
+                    shards: (0..backends).map(|_| None).collect(),
+                });
+                self.groups.len() - 1
+            }
+        };
+        self.groups[pos].shards[index] = Some(payload);
+    }
+
+    /// The most intact shards of any version of the blob.
+    fn intact(&self) -> usize {
+        self.groups.iter().map(|g| g.intact()).max().unwrap_or(0)
+    }
+}
+
+impl ErasureBackend {
+    /// Spread blobs over `backends`, so that any `data_shards` of them can rebuild each blob.
+    pub fn new(backends: Vec<Box<StoreBackend>>,
+               data_shards: usize)
+               -> Result<ErasureBackend, String> {
+        if data_shards < 1 || data_shards > backends.len() {
+            return Err(format!("Invalid number of data shards {} for {} backends",
+                               data_shards,
+                               backends.len()));
+        }
+        if backends.len() > 255 {
+            return Err(format!("Too many backends for erasure coding: {}", backends.len()));
+        }
+        Ok(ErasureBackend {
+            codec: Codec::new(data_shards, backends.len() - data_shards),
+            pool: Pool::new(backends.len()),
+            backends: backends,
+        })
+    }
+
+    fn shard_len(&self, length: u64) -> usize {
+        let k = self.codec.data_shards as u64;
+        ((length + k - 1) / k) as usize
+    }
+
+    fn encode_shard(&self, index: usize, version: &BlobVersion, payload: &[u8]) -> CipherText {
+        let mut shard = Vec::with_capacity(SHARD_HEADER_LEN + payload.len() + sha256::DIGESTBYTES);
+        shard.push(SHARD_VERSION);
+        shard.push(index as u8);
+        shard.push(self.codec.data_shards as u8);
+        shard.push(self.codec.parity_shards as u8);
+        shard.write_u64::<LittleEndian>(version.length).unwrap();
+        shard.write_u64::<LittleEndian>(version.generation).unwrap();
+        shard.extend_from_slice(&version.hash[..]);
+        shard.extend_from_slice(payload);
+        let checksum = sha256::hash(&shard[..]).0;
+        shard.extend_from_slice(&checksum[..]);
+        CipherText::new(shard)
+    }
+
+    /// All shards of `data`, as generation `generation` of the blob.
+    fn encode(&self, data: &CipherText, generation: u64) -> Vec<CipherText> {
+        let blob = data.to_vec();
+        let version = BlobVersion {
+            length: blob.len() as u64,
+            generation: generation,
+            hash: sha256::hash(&blob[..]).0.to_vec(),
+        };
+        self.codec
+            .encode(&blob[..])
+            .iter()
+            .enumerate()
+            .map(|(i, payload)| self.encode_shard(i, &version, payload))
+            .collect()
+    }
+
+    /// The blob version and payload of shard `index`, unless it is damaged.
+    fn decode_shard(&self, index: usize, mut shard: Vec<u8>) -> Option<(BlobVersion, Vec<u8>)> {
+        if shard.len() < SHARD_HEADER_LEN + sha256::DIGESTBYTES {
+            return None;
+        }
+        let body_len = shard.len() - sha256::DIGESTBYTES;
+        if &sha256::hash(&shard[..body_len]).0[..] != &shard[body_len..] {
+            return None;
+        }
+        let expected = [SHARD_VERSION,
+                        index as u8,
+                        self.codec.data_shards as u8,
+                        self.codec.parity_shards as u8];
+        if shard[..4] != expected[..] {
+            return None;
+        }
+        let version = BlobVersion {
+            length: (&shard[4..12]).read_u64::<LittleEndian>().unwrap(),
+            generation: (&shard[12..20]).read_u64::<LittleEndian>().unwrap(),
+            hash: shard[20..SHARD_HEADER_LEN].to_vec(),
+        };
+        if body_len - SHARD_HEADER_LEN != self.shard_len(version.length) {
+            return None;
+        }
+        shard.truncate(body_len);
+        Some((version, shard.split_off(SHARD_HEADER_LEN)))
+    }
+
+    /// Add the shards stored under `name` to `found`.
+    fn fetch_into(&self, name: &[u8], found: &mut Shards) {
+        let results = on_all(&self.pool, &self.backends, |_, b| b.retrieve(name));
+        for (i, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(None) => (),
+                Ok(Some(shard)) => {
+                    found.present[i] = true;
+                    match self.decode_shard(i, shard) {
+                        Some((version, payload)) => found.add(i, version, payload),
+                        None => {
+                            warn!("Shard {} of blob {} is damaged", i, name.to_hex());
+                            found.damaged.push(i);
+                        }
+                    }
+                }
+                Err(e) => {
+                    debug!("Could not read shard {} of blob {}: {}", i, name.to_hex(), e);
+                    found.failed.push(i);
+                    found.errors.push(e);
+                }
+            }
+        }
+    }
+
+    fn fetch(&self, name: &[u8]) -> Shards {
+        let mut found = Shards::new(self.backends.len());
+        self.fetch_into(name, &mut found);
+        found
+    }
+
+    /// The newest version of the blob that can be rebuilt from `found`, its shards with the data
+    /// shards filled in, and the blob itself. Shards that do not add up to the blob they claim to
+    /// belong to are skipped.
+    fn newest_intact<'a>(&self,
+                         found: &'a Shards)
+                         -> Option<(&'a Group, Vec<Option<Vec<u8>>>, Vec<u8>)> {
+        let mut complete: Vec<&Group> = found.groups
+            .iter()
+            .filter(|g| g.intact() >= self.codec.data_shards)
+            .collect();
+        complete.sort_by(|a, b| b.version.generation.cmp(&a.version.generation));
+        for group in complete {
+            let mut shards = group.shards.clone();
+            self.codec.reconstruct_data(&mut shards);
+            let mut blob = Vec::with_capacity(group.version.length as usize);
+            for shard in &shards[..self.codec.data_shards] {
+                blob.extend_from_slice(shard.as_ref().unwrap());
+            }
+            blob.truncate(group.version.length as usize);
+            if &sha256::hash(&blob[..]).0[..] == &group.version.hash[..] {
+                return Some((group, shards, blob));
+            }
+            warn!("Shards of generation {} do not add up to their blob", group.version.generation);
+        }
+        None
+    }
+
+    /// Store (or replace) shard `i` of `shards` in backend `i` under `name`. Every shard is needed
+    /// to survive the loss of any `m` backends.
+    fn put_all(&self,
+               name: &[u8],
+               shards: &[CipherText],
+               replace: bool)
+               -> Result<(), BackendError> {
+        let put = |i, b: &StoreBackend| if replace {
+            b.replace(name, &shards[i])
+        } else {
+            b.store(name, &shards[i])
+        };
+        let errors: Vec<_> = on_all(&self.pool, &self.backends, put)
+            .into_iter()
+            .filter_map(|r| r.err())
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(worst_error(errors))
+        }
+    }
+
+    fn delete_all(&self, name: &[u8]) -> Result<(), BackendError> {
+        let errors: Vec<_> = on_all(&self.pool, &self.backends, |_, b| b.delete(name))
+            .into_iter()
+            .filter_map(|r| r.err())
+            .filter(|e| match *e {
+                BackendError::NotFound(..) => false,
+                _ => true,
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(worst_error(errors))
+        }
+    }
+
+    /// Rebuild lost, damaged and outdated shards of all blobs. Returns the number of shards
+    /// rebuilt, the number of blobs that could not be fully checked or repaired because a backend
+    /// failed, and the number of blobs with too few intact shards left to rebuild them.
+    pub fn scrub(&self) -> Result<(usize, usize, usize), BackendError> {
+        let mut names = BTreeSet::new();
+        let mut errors = vec![];
+        for listing in on_all(&self.pool, &self.backends, |_, b| b.list()) {
+            match listing {
+                Ok(listing) => names.extend(listing.into_iter().filter(|n| !is_replacement(n))),
+                Err(e) => {
+                    warn!("Could not list the blobs of a backend: {}", e);
+                    errors.push(e);
+                }
+            }
+        }
+        if errors.len() == self.backends.len() {
+            return Err(worst_error(errors));
+        }
+
+        let mut rebuilt = 0;
+        let mut degraded = 0;
+        let mut lost = 0;
+        for name in names {
+            let found = self.fetch(&name);
+            let (group, mut shards) = match self.newest_intact(&found) {
+                Some((group, shards, _)) => (group, shards),
+                None if !found.errors.is_empty() => {
+                    degraded += 1;
+                    continue;
+                }
+                None => {
+                    warn!("Blob {} has only {} of the {} shards needed to rebuild it",
+                          name.to_hex(),
+                          found.intact(),
+                          self.codec.data_shards);
+                    lost += 1;
+                    continue;
+                }
+            };
+
+            self.codec.reconstruct(&mut shards);
+            let mut failed = !found.failed.is_empty();
+            for i in 0..shards.len() {
+                if group.shards[i].is_some() || found.failed.contains(&i) {
+                    continue;
+                }
+                let shard = self.encode_shard(i, &group.version, shards[i].as_ref().unwrap());
+                let result = if found.present[i] {
+                    self.backends[i].replace(&name, &shard)
+                } else {
+                    self.backends[i].store(&name, &shard)
+                };
+                match result {
+                    Ok(()) => rebuilt += 1,
+                    Err(e) => {
+                        warn!("Could not rebuild shard {} of blob {}: {}", i, name.to_hex(), e);
+                        failed = true;
+                    }
+                }
+            }
+            if failed {
+                degraded += 1;
+            }
+        }
+        Ok((rebuilt, degraded, lost))
+    }
+}
+
+impl Drop for ErasureBackend {
+    fn drop(&mut self) {
+        self.pool.shutdown();
+    }
+}
+
+impl StoreBackend for ErasureBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.put_all(name, &self.encode(data, 0), false)
+    }
+
+    /// Replace a blob without ever leaving too few shards of one version to read it: the new
+    /// shards are stored under a replacement name first, and readers fall back to those while the
+    /// shards under the blob name are replaced one backend at a time.
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let replacement = replacement_name(name);
+        let mut found = self.fetch(name);
+        self.fetch_into(&replacement, &mut found);
+        if !found.errors.is_empty() {
+            return Err(worst_error(found.errors));
+        }
+
+        let generation = found.groups.iter().map(|g| g.version.generation + 1).max().unwrap_or(0);
+        let shards = self.encode(data, generation);
+        // This overwrites what an interrupted replace may have left under the replacement name.
+        self.put_all(&replacement, &shards, true)?;
+        self.put_all(name, &shards, true)?;
+        self.delete_all(&replacement)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        let mut found = self.fetch(name);
+        if self.newest_intact(&found).is_none() {
+            // A replace may have been interrupted.
+            self.fetch_into(&replacement_name(name), &mut found);
+        }
+        if let Some((_, _, blob)) = self.newest_intact(&found) {
+            return Ok(Some(blob));
+        }
+
+        if !found.errors.is_empty() {
+            return Err(worst_error(found.errors));
+        }
+        if found.groups.is_empty() && found.damaged.is_empty() {
+            return Ok(None);
+        }
+        Err(BackendError::Corruption(format!("Only {} of the {} shards needed to read blob {} \
+                                              are intact",
+                                             found.intact(),
+                                             self.codec.data_shards,
+                                             name.to_hex())))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.delete_all(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        let mut names = BTreeSet::new();
+        for listing in on_all(&self.pool, &self.backends, |_, b| b.list()) {
+            names.extend(listing?.into_iter().filter(|n| !is_replacement(n)));
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        let errors: Vec<_> = on_all(&self.pool, &self.backends, |_, b| b.flush())
+            .into_iter()
+            .filter_map(|r| r.err())
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(worst_error(errors))
+        }
+    }
+}
+
+
+/// A systematic Reed-Solomon code over GF(2^8). The first `data_shards` shards hold the data as
+/// it is. The parity shards are computed with a Cauchy matrix, so that any `data_shards` rows of
+/// the full encoding matrix can be inverted.
+struct Codec {
+    data_shards: usize,
+    parity_shards: usize,
+    exp: Vec<u8>,
+    log: Vec<u8>,
+    parity_rows: Vec<Vec<u8>>,
+}
+
+impl Codec {
+    fn new(data_shards: usize, parity_shards: usize) -> Codec {
+        assert!(data_shards > 0 && data_shards + parity_shards <= 256);
+
+        // Logarithm tables for the field with generator polynomial x^8 + x^4 + x^3 + x^2 + 1.
+        // The exponent table is doubled, so that products need no modulo.
+        let mut exp = vec![0u8; 510];
+        let mut log = vec![0u8; 256];
+        let mut x = 1usize;
+        for i in 0..255 {
+            exp[i] = x as u8;
+            exp[i + 255] = x as u8;
+            log[x] = i as u8;
+            x <<= 1;
+            if x & 0x100 != 0 {
+                x ^= 0x11d;
+            }
+        }
+
+        let mut codec = Codec {
+            data_shards: data_shards,
+            parity_shards: parity_shards,
+            exp: exp,
+            log: log,
+            parity_rows: vec![],
+        };
+        // Cauchy matrix entries 1 / (x_i + y_j), with x_i = data_shards + i and y_j = j.
+        codec.parity_rows = (0..parity_shards)
+            .map(|i| (0..data_shards).map(|j| codec.inv(((data_shards + i) ^ j) as u8)).collect())
+            .collect();
+        codec
+    }
+
+    fn mul(&self, a: u8, b: u8) -> u8 {
+        if a == 0 || b == 0 {
+            0
+        } else {
+            self.exp[self.log[a as usize] as usize + self.log[b as usize] as usize]
+        }
+    }
+
+    fn inv(&self, a: u8) -> u8 {
+        assert!(a != 0);
+        self.exp[255 - self.log[a as usize] as usize]
+    }
+
+    /// The row of the encoding matrix that computes shard `index` from the data shards.
+    fn row(&self, index: usize) -> Vec<u8> {
+        if index < self.data_shards {
+            (0..self.data_shards).map(|j| if j == index { 1 } else { 0 }).collect()
+        } else {
+            self.parity_rows[index - self.data_shards].clone()
+        }
+    }
+
+    /// The sum of `shards` multiplied by `coefficients`.
+    fn combine(&self, coefficients: &[u8], shards: &[&[u8]], len: usize) -> Vec<u8> {
+        let mut out = vec![0u8; len];
+        for (&c, shard) in coefficients.iter().zip(shards) {
+            if c == 0 {
+                continue;
+            }
+            let log_c = self.log[c as usize] as usize;
+            for (o, &b) in out.iter_mut().zip(shard.iter()) {
+                if b != 0 {
+                    *o ^= self.exp[log_c + self.log[b as usize] as usize];
+                }
+            }
+        }
+        out
+    }
+
+    /// Invert a square matrix with Gauss-Jordan elimination.
+    fn invert(&self, mut m: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+        let n = m.len();
+        let mut inv: Vec<Vec<u8>> = (0..n)
+            .map(|i| (0..n).map(|j| if i == j { 1 } else { 0 }).collect())
+            .collect();
+        for col in 0..n {
+            let pivot = (col..n).find(|&r| m[r][col] != 0).expect("Singular encoding matrix");
+            m.swap(col, pivot);
+            inv.swap(col, pivot);
+            let scale = self.inv(m[col][col]);
+            for j in 0..n {
+                m[col][j] = self.mul(m[col][j], scale);
+                inv[col][j] = self.mul(inv[col][j], scale);
+            }
+            for r in 0..n {
+                let factor = m[r][col];
+                if r == col || factor == 0 {
+                    continue;
+                }
+                for j in 0..n {
+                    let a = self.mul(factor, m[col][j]);
+                    let b = self.mul(factor, inv[col][j]);
+                    m[r][j] ^= a;
+                    inv[r][j] ^= b;
+                }
+            }
+        }
+        inv
+    }
+
+    fn parity(&self, data: &[&[u8]], len: usize) -> Vec<Vec<u8>> {
+        self.parity_rows.iter().map(|row| self.combine(row, data, len)).collect()
+    }
+
+    /// Cut `data` into data shards, padded with zeros to the same length, followed by the parity
+    /// shards.
+    fn encode(&self, data: &[u8]) -> Vec<Vec<u8>> {
+        let len = (data.len() + self.data_shards - 1) / self.data_shards;
+        let mut shards: Vec<Vec<u8>> = (0..self.data_shards)
+            .map(|i| {
+                let start = cmp::min(i * len, data.len());
+                let mut shard = data[start..cmp::min(start + len, data.len())].to_vec();
+                shard.resize(len, 0);
+                shard
+            })
+            .collect();
+        let parity = {
+            let refs: Vec<&[u8]> = shards.iter().map(|s| &s[..]).collect();
+            self.parity(&refs, len)
+        };
+        shards.extend(parity);
+        shards
+    }
+
+    /// Fill in the missing data shards. There must be at least `data_shards` shards present.
+    fn reconstruct_data(&self, shards: &mut Vec<Option<Vec<u8>>>) {
+        let present: Vec<usize> = (0..shards.len())
+            .filter(|&i| shards[i].is_some())
+            .take(self.data_shards)
+            .collect();
+        assert_eq!(self.data_shards, present.len());
+        let len = shards[present[0]].as_ref().unwrap().len();
+
+        if shards[..self.data_shards].iter().any(|s| s.is_none()) {
+            let inv = self.invert(present.iter().map(|&i| self.row(i)).collect());
+            let data: Vec<Vec<u8>> = {
+                let refs: Vec<&[u8]> =
+                    present.iter().map(|&i| &shards[i].as_ref().unwrap()[..]).collect();
+                inv.iter().map(|row| self.combine(row, &refs, len)).collect()
+            };
+            for (shard, rebuilt) in shards.iter_mut().zip(data) {
+                if shard.is_none() {
+                    *shard = Some(rebuilt);
+                }
+            }
+        }
+    }
+
+    /// Fill in all missing shards. There must be at least `data_shards` shards present.
+    fn reconstruct(&self, shards: &mut Vec<Option<Vec<u8>>>) {
+        self.reconstruct_data(shards);
+        if shards[self.data_shards..].iter().any(|s| s.is_none()) {
+            let len = shards[0].as_ref().unwrap().len();
+            let parity = {
+                let refs: Vec<&[u8]> =
+                    shards[..self.data_shards].iter().map(|s| &s.as_ref().unwrap()[..]).collect();
+                self.parity(&refs, len)
+            };
+            for (shard, rebuilt) in shards[self.data_shards..].iter_mut().zip(parity) {
+                if shard.is_none() {
+                    *shard = Some(rebuilt);
+                }
+            }
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{BackendError, MemoryBackend, StoreBackend};
+    use crypto::CipherText;
+    use rand::{self, Rng};
+
+    #[test]
+    fn codec_rebuilds_from_any_data_shards() {
+        let mut rng = rand::thread_rng();
+        for &(k, m) in &[(1, 1), (2, 1), (4, 2), (6, 3), (3, 0)] {
+            let codec = Codec::new(k, m);
+            for &len in &[0, 1, 7, 1000] {
+                let data: Vec<u8> = rng.gen_iter().take(len).collect();
+                let full = codec.encode(&data);
+                assert_eq!(k + m, full.len());
+
+                let mut joined = full[..k].concat();
+                joined.truncate(len);
+                assert_eq!(data, joined);
+
+                for _ in 0..20 {
+                    let mut shards: Vec<_> = full.iter().cloned().map(Some).collect();
+                    for _ in 0..m {
+                        shards[rng.gen_range(0, k + m)] = None;
+                    }
+                    codec.reconstruct(&mut shards);
+                    assert_eq!(full, shards.into_iter().map(|s| s.unwrap()).collect::<Vec<_>>());
+                }
+            }
+        }
+
+        // Every choice of 4 out of 7 shards works.
+        let codec = Codec::new(4, 3);
+        let full = codec.encode(&(0..97).map(|i| (i * 31) as u8).collect::<Vec<_>>());
+        for mask in 0u32..128 {
+            if mask.count_ones() != 4 {
+                continue;
+            }
+            let mut shards: Vec<_> = (0..7)
+                .map(|i| if mask & (1 << i) != 0 { Some(full[i].clone()) } else { None })
+                .collect();
+            codec.reconstruct(&mut shards);
+            assert_eq!(full, shards.into_iter().map(|s| s.unwrap()).collect::<Vec<_>>());
+        }
+    }
+
+    fn erasure(data_shards: usize, parity_shards: usize) -> ErasureBackend {
+        let backends = (0..data_shards + parity_shards)
+            .map(|_| Box::new(MemoryBackend::new()) as Box<StoreBackend>)
+            .collect();
+        ErasureBackend::new(backends, data_shards).unwrap()
+    }
+
+    #[test]
+    fn invalid_settings() {
+        assert!(ErasureBackend::new(vec![], 1).is_err());
+        assert!(ErasureBackend::new(vec![Box::new(MemoryBackend::new())], 0).is_err());
+        assert!(ErasureBackend::new(vec![Box::new(MemoryBackend::new())], 2).is_err());
+    }
+
+    #[test]
+    fn survives_lost_and_damaged_shards() {
+        let backend = erasure(3, 2);
+        let blob: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        backend.store(&[1], &CipherText::new(blob.clone())).unwrap();
+        assert_eq!(Some(blob.clone()), backend.retrieve(&[1]).unwrap());
+        assert_eq!(None, backend.retrieve(&[2]).unwrap());
+        assert_eq!(vec![vec![1].into_boxed_slice()], backend.list().unwrap());
+
+        backend.backends[0].delete(&[1]).unwrap();
+        backend.backends[3].replace(&[1], &CipherText::new(vec![0; 100])).unwrap();
+        assert_eq!(Some(blob.clone()), backend.retrieve(&[1]).unwrap());
+
+        // A third loss is one too many.
+        backend.backends[1].delete(&[1]).unwrap();
+        match backend.retrieve(&[1]) {
+            Err(BackendError::Corruption(..)) => (),
+            other => panic!("Expected a corruption error: {:?}", other),
+        }
+
+        backend.delete(&[1]).unwrap();
+        assert!(backend.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn scrub_rebuilds_shards() {
+        let backend = erasure(2, 2);
+        backend.store(&[1], &CipherText::new(b"hello".to_vec())).unwrap();
+        backend.store(&[2], &CipherText::new(b"world".to_vec())).unwrap();
+        assert_eq!((0, 0, 0), backend.scrub().unwrap());
+
+        backend.backends[1].delete(&[1]).unwrap();
+        backend.backends[2].replace(&[1], &CipherText::new(b"garbage".to_vec())).unwrap();
+        for b in &backend.backends[..3] {
+            b.delete(&[2]).unwrap();
+        }
+        assert_eq!((2, 0, 1), backend.scrub().unwrap());
+
+        // The rebuilt shards are enough on their own.
+        backend.backends[0].delete(&[1]).unwrap();
+        backend.backends[3].delete(&[1]).unwrap();
+        assert_eq!(Some(b"hello".to_vec()), backend.retrieve(&[1]).unwrap());
+    }
+
+    #[test]
+    fn replace_is_never_half_done() {
+        let backend = erasure(2, 2);
+        let old = CipherText::new(b"old contents".to_vec());
+        let new = CipherText::new(b"new contents".to_vec());
+        backend.store(&[1], &old).unwrap();
+
+        // A shard of another blob of the same length is not mixed in.
+        let shards = backend.encode(&new, 1);
+        backend.backends[0].replace(&[1], &shards[0]).unwrap();
+        assert_eq!(Some(old.to_vec()), backend.retrieve(&[1]).unwrap());
+
+        // Once both versions can be read, the newer one wins.
+        backend.backends[1].replace(&[1], &shards[1]).unwrap();
+        assert_eq!(Some(new.to_vec()), backend.retrieve(&[1]).unwrap());
+        backend.backends[0].replace(&[1], &backend.encode(&old, 0)[0]).unwrap();
+        assert_eq!(Some(old.to_vec()), backend.retrieve(&[1]).unwrap());
+
+        // When neither version has enough shards left, the replacement shards are read.
+        backend.backends[2].delete(&[1]).unwrap();
+        backend.backends[3].delete(&[1]).unwrap();
+        assert!(backend.retrieve(&[1]).is_err());
+        for (i, shard) in shards.iter().enumerate() {
+            backend.backends[i].store(&replacement_name(&[1]), shard).unwrap();
+        }
+        assert_eq!(Some(new.to_vec()), backend.retrieve(&[1]).unwrap());
+        assert_eq!(vec![vec![1].into_boxed_slice()], backend.list().unwrap());
+
+        // A complete replace leaves only the new shards, and scrub brings them in line.
+        let newer = CipherText::new(b"newer contents".to_vec());
+        backend.replace(&[1], &newer).unwrap();
+        assert_eq!(Some(newer.to_vec()), backend.retrieve(&[1]).unwrap());
+        assert_eq!((0, 0, 0), backend.scrub().unwrap());
+        backend.backends[3].replace(&[1], &backend.encode(&old, 0)[3]).unwrap();
+        assert_eq!((1, 0, 0), backend.scrub().unwrap());
+        for b in &backend.backends {
+            assert_eq!(None, b.retrieve(&replacement_name(&[1])).unwrap());
+        }
+    }
+
+    /// A backend that is down.
+    struct Unreachable;
+
+    impl StoreBackend for Unreachable {
+        fn store(&self, _: &[u8], _: &CipherText) -> Result<(), BackendError> {
+            Err(BackendError::Transient("unreachable".to_owned()))
+        }
+        fn replace(&self, _: &[u8], _: &CipherText) -> Result<(), BackendError> {
+            Err(BackendError::Transient("unreachable".to_owned()))
+        }
+        fn retrieve(&self, _: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+            Err(BackendError::Transient("unreachable".to_owned()))
+        }
+        fn delete(&self, _: &[u8]) -> Result<(), BackendError> {
+            Err(BackendError::Transient("unreachable".to_owned()))
+        }
+        fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+            Err(BackendError::Transient("unreachable".to_owned()))
+        }
+        fn flush(&self) -> Result<(), BackendError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn scrub_continues_past_failing_backends() {
+        let mut backend = erasure(2, 2);
+        backend.store(&[1], &CipherText::new(b"hello".to_vec())).unwrap();
+        backend.store(&[2], &CipherText::new(b"world".to_vec())).unwrap();
+        backend.backends[0].delete(&[1]).unwrap();
+
+        // Both blobs are degraded, but the lost shard is rebuilt anyway.
+        backend.backends[3] = Box::new(Unreachable);
+        assert_eq!((1, 2, 0), backend.scrub().unwrap());
+        assert!(backend.backends[0].retrieve(&[1]).unwrap().is_some());
+        assert_eq!(Some(b"hello".to_vec()), backend.retrieve(&[1]).unwrap());
+    }
+}
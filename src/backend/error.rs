@@ -0,0 +1,147 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Errors reported by storage backends.
+
+use std::{error, fmt, io};
+
+
+/// What went wrong in a backend, so that callers can retry failures that may go away by
+/// themselves, and report the others.
+#[derive(Clone, Debug, PartialEq)]
+pub enum BackendError {
+    /// The blob does not exist.
+    NotFound(String),
+    /// A failure that may go away when retried, e.g. a timeout or a dropped connection.
+    Transient(String),
+    /// The storage denied access.
+    Permission(String),
+    /// The storage is full, or the account is over its quota.
+    Quota(String),
+    /// The storage returned damaged data.
+    Corruption(String),
+    /// Any other failure; retrying will not help.
+    Other(String),
+}
+
+impl BackendError {
+    pub fn is_transient(&self) -> bool {
+        match *self {
+            BackendError::Transient(..) => true,
+            _ => false,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match *self {
+            BackendError::NotFound(ref m) |
+            BackendError::Transient(ref m) |
+            BackendError::Permission(ref m) |
+            BackendError::Quota(ref m) |
+            BackendError::Corruption(ref m) |
+            BackendError::Other(ref m) => m,
+        }
+    }
+
+    /// Classify an I/O error, prefixing its message with `context`.
+    pub fn from_io(context: &str, e: &io::Error) -> BackendError {
+        // ENOSPC and EDQUOT; io::ErrorKind has no kinds for these.
+        const NO_SPACE: i32 = 28;
+        const QUOTA_EXCEEDED: i32 = 122;
+
+        let message = if context.is_empty() {
+            e.to_string()
+        } else {
+            format!("{}: {}", context, e)
+        };
+        match e.kind() {
+            io::ErrorKind::NotFound => BackendError::NotFound(message),
+            io::ErrorKind::PermissionDenied => BackendError::Permission(message),
+            io::ErrorKind::ConnectionRefused |
+            io::ErrorKind::ConnectionReset |
+            io::ErrorKind::ConnectionAborted |
+            io::ErrorKind::NotConnected |
+            io::ErrorKind::BrokenPipe |
+            io::ErrorKind::TimedOut |
+            io::ErrorKind::Interrupted |
+            io::ErrorKind::WouldBlock => BackendError::Transient(message),
+            io::ErrorKind::UnexpectedEof => BackendError::Corruption(message),
+            _ => {
+                match e.raw_os_error() {
+                    Some(NO_SPACE) | Some(QUOTA_EXCEEDED) => BackendError::Quota(message),
+                    _ => BackendError::Other(message),
+                }
+            }
+        }
+    }
+}
+
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        let kind = match *self {
+            BackendError::NotFound(..) => "Blob not found",
+            BackendError::Transient(..) => "Temporary storage failure",
+            BackendError::Permission(..) => "Storage access denied",
+            BackendError::Quota(..) => "Storage is full",
+            BackendError::Corruption(..) => "Storage returned damaged data",
+            BackendError::Other(..) => "Storage failure",
+        };
+        write!(f, "{}: {}", kind, self.message())
+    }
+}
+
+impl error::Error for BackendError {
+    fn description(&self) -> &str {
+        self.message()
+    }
+}
+
+impl From<io::Error> for BackendError {
+    fn from(e: io::Error) -> BackendError {
+        BackendError::from_io("", &e)
+    }
+}
+
+impl From<String> for BackendError {
+    fn from(s: String) -> BackendError {
+        BackendError::Other(s)
+    }
+}
+
+impl<'a> From<&'a str> for BackendError {
+    fn from(s: &'a str) -> BackendError {
+        BackendError::Other(s.to_owned())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn classify_io_errors() {
+        let e = |kind| BackendError::from(io::Error::new(kind, "oops"));
+        assert_eq!(BackendError::NotFound("oops".to_owned()), e(io::ErrorKind::NotFound));
+        assert!(e(io::ErrorKind::TimedOut).is_transient());
+        assert!(!e(io::ErrorKind::PermissionDenied).is_transient());
+
+        match BackendError::from_io("blob", &io::Error::from_raw_os_error(28)) {
+            BackendError::Quota(ref m) => assert!(m.starts_with("blob: ")),
+            other => panic!("Not a quota error: {:?}", other),
+        }
+    }
+}
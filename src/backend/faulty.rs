@@ -0,0 +1,166 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! A backend that breaks on purpose, for testing how the rest of hat copes.
+//!
+//! It counts the operations passed on to another backend, and from a chosen operation on, either
+//! fails them or ends the process on the spot, as a crash would. A single operation before that
+//! can fail on its own, as a passing network error would.
+
+use backend::{BackendError, StoreBackend};
+use crypto::CipherText;
+use std::process;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+
+/// Exit status of a process ended by `Fault::Crash`.
+pub const CRASH_STATUS: i32 = 86;
+
+#[derive(Clone, Debug)]
+pub enum Fault {
+    /// Fail the operation with this error.
+    Error(BackendError),
+    /// Exit the process without running another line of hat.
+    Crash,
+}
+
+pub struct FaultyBackend<B> {
+    backend: B,
+    operations: AtomicUsize,
+    /// Number of operations that succeed, and what happens to the ones after them.
+    fault: Option<(usize, Fault)>,
+    /// An operation that fails on its own, and its error.
+    error_at: Option<(usize, BackendError)>,
+}
+
+impl<B: StoreBackend> FaultyBackend<B> {
+    /// A backend that only counts operations.
+    pub fn new(backend: B) -> FaultyBackend<B> {
+        FaultyBackend {
+            backend: backend,
+            operations: AtomicUsize::new(0),
+            fault: None,
+            error_at: None,
+        }
+    }
+
+    /// A backend that passes on the first `n` operations, and runs into `fault` on all others.
+    pub fn fail_after(backend: B, n: usize, fault: Fault) -> FaultyBackend<B> {
+        FaultyBackend {
+            backend: backend,
+            operations: AtomicUsize::new(0),
+            fault: Some((n, fault)),
+            error_at: None,
+        }
+    }
+
+    /// Also fail operation `n` alone with `error`, counting from 0.
+    pub fn with_error_at(mut self, n: usize, error: BackendError) -> FaultyBackend<B> {
+        self.error_at = Some((n, error));
+        self
+    }
+
+    /// The number of operations so far, including failed ones.
+    pub fn operations(&self) -> usize {
+        self.operations.load(Ordering::SeqCst)
+    }
+
+    fn check(&self) -> Result<(), BackendError> {
+        let n = self.operations.fetch_add(1, Ordering::SeqCst);
+        match self.fault {
+            Some((after, ref fault)) if n >= after => {
+                match *fault {
+                    Fault::Error(ref e) => Err(e.clone()),
+                    Fault::Crash => process::exit(CRASH_STATUS),
+                }
+            }
+            _ => {
+                match self.error_at {
+                    Some((at, ref e)) if n == at => Err(e.clone()),
+                    _ => Ok(()),
+                }
+            }
+        }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for FaultyBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.check()?;
+        self.backend.store(name, data)
+    }
+
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.check()?;
+        self.backend.replace(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.check()?;
+        self.backend.retrieve(name)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.check()?;
+        self.backend.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        self.check()?;
+        self.backend.list()
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        self.check()?;
+        self.backend.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{BackendError, MemoryBackend, StoreBackend};
+    use crypto::CipherText;
+
+    #[test]
+    fn counts_operations() {
+        let backend = FaultyBackend::new(MemoryBackend::new());
+        backend.store(&[1], &CipherText::new(vec![1])).unwrap();
+        assert_eq!(Some(vec![1]), backend.retrieve(&[1]).unwrap());
+        backend.delete(&[1]).unwrap();
+        assert_eq!(3, backend.operations());
+    }
+
+    #[test]
+    fn fails_after_n_operations() {
+        let error = BackendError::Transient("Connection reset".to_owned());
+        let backend =
+            FaultyBackend::fail_after(MemoryBackend::new(), 1, Fault::Error(error.clone()));
+        backend.store(&[1], &CipherText::new(vec![1])).unwrap();
+        assert_eq!(Err(error.clone()), backend.retrieve(&[1]));
+        assert_eq!(Err(error), backend.delete(&[1]));
+        assert_eq!(3, backend.operations());
+    }
+
+    #[test]
+    fn fails_a_single_operation() {
+        let error = BackendError::Transient("Connection reset".to_owned());
+        let backend = FaultyBackend::new(MemoryBackend::new()).with_error_at(1, error.clone());
+        backend.store(&[1], &CipherText::new(vec![1])).unwrap();
+        assert_eq!(Err(error), backend.retrieve(&[1]));
+        assert_eq!(Ok(Some(vec![1])), backend.retrieve(&[1]));
+    }
+}
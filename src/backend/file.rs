@@ -13,138 +13,513 @@
 // limitations under the License.
 
 
-use backend::StoreBackend;
+use backend::{BackendError, StoreBackend};
 use crypto::CipherText;
+use rand::{self, Rng};
 use rustc_serialize::hex::{FromHex, ToHex};
-use std::collections::BTreeMap;
+use std::collections::BTreeSet;
 use std::fs;
 use std::io;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
+/// Points in `store` where tests can make it stop as if the process had crashed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum CrashPoint {
+    PartialWrite,
+    BeforeRename,
+    BeforeDirSync,
+}
+
+/// Blobs are stored in files named by the blob name in hex. With `n` shard levels, a blob is
+/// stored `n` directories deep, one directory per leading byte of its name (e.g. `ab/cd/abcd...`
+/// for two levels), which keeps directories small in large repositories. Without shard levels,
+/// all blobs share the root directory, as in repositories from before sharding.
 pub struct FileBackend {
     root: PathBuf,
-    read_cache: Mutex<BTreeMap<Vec<u8>, Result<Option<Vec<u8>>, String>>>,
-    max_cache_size: usize,
+    shard_levels: usize,
+    /// The shard levels of the layout that an unfinished migration moves blobs away from, which
+    /// is checked before giving up on a blob.
+    previous_levels: Option<usize>,
+    /// Whether the root holds blobs in the flat layout, which is checked before giving up on a
+    /// blob when the configured layout is sharded.
+    has_flat_blobs: bool,
+    /// Directories with deletions that are not yet durable.
+    dirty_dirs: Mutex<BTreeSet<PathBuf>>,
+    #[cfg(test)]
+    crash_point: Option<CrashPoint>,
+}
+
+/// Marks the names of temporary files, followed by a random number.
+const TEMPORARY_INFIX: &'static str = ".tmp-";
+
+fn sync_dir(dir: &Path) -> Result<(), BackendError> {
+    Ok(fs::File::open(dir).and_then(|dir| dir.sync_all())?)
+}
+
+fn is_shard_dir(name: &str) -> bool {
+    name.len() == 2 && name.from_hex().is_ok()
+}
+
+/// Whether `root` directly holds any blobs. A sharded root only holds shard directories, so this
+/// looks at no more than 256 entries unless it finds a blob.
+fn has_flat_blobs(root: &Path) -> bool {
+    let entries = match fs::read_dir(root) {
+        Ok(entries) => entries,
+        Err(_) => return false,
+    };
+    entries.filter_map(|e| e.ok()).any(|e| {
+        e.file_type().map(|t| !t.is_dir()).unwrap_or(false) &&
+        e.file_name().to_str().map_or(false, |n| n.from_hex().is_ok())
+    })
+}
+
+/// Whether `name` is the name of a temporary file written by `store`.
+fn is_temporary(name: &str) -> bool {
+    name.contains(TEMPORARY_INFIX)
+}
+
+/// Remove the temporary files that stores interrupted by a crash left behind below `dir`, and
+/// return how many there were. Stores that are still running lose theirs, so this is only safe
+/// while nothing else uses the backend.
+fn remove_temporary_files(dir: &Path) -> Result<usize, BackendError> {
+    let mut removed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let name = match file_name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if entry.file_type()?.is_dir() {
+            if is_shard_dir(name) {
+                removed += remove_temporary_files(&entry.path())?;
+            }
+        } else if is_temporary(name) {
+            fs::remove_file(entry.path())?;
+            removed += 1;
+        }
+    }
+    Ok(removed)
+}
+
+/// Call `f` with the path and name of every blob below `dir`. Shard directories of any depth are
+/// visited, so that blobs stored with a different number of levels are found too. Only one
+/// directory listing per level is held open at a time.
+fn walk(dir: &Path,
+        f: &mut FnMut(PathBuf, Vec<u8>) -> Result<(), BackendError>)
+        -> Result<(), BackendError> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let name = match file_name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if entry.file_type()?.is_dir() {
+            if is_shard_dir(name) {
+                walk(&entry.path(), f)?;
+            }
+        } else if let Ok(b) = name.from_hex() {
+            // Temporary files left behind by crashed stores are not valid hex and get skipped.
+            f(entry.path(), b)?;
+        }
+    }
+    Ok(())
 }
 
 impl FileBackend {
+    /// A backend using the flat layout.
     pub fn new(root: PathBuf) -> FileBackend {
+        FileBackend::with_shard_levels(root, 0)
+    }
+
+    pub fn with_shard_levels(root: PathBuf, shard_levels: usize) -> FileBackend {
+        let has_flat_blobs = shard_levels > 0 && has_flat_blobs(&root);
         FileBackend {
             root: root,
-            read_cache: Mutex::new(BTreeMap::new()),
-            max_cache_size: 10,
+            shard_levels: shard_levels,
+            previous_levels: None,
+            has_flat_blobs: has_flat_blobs,
+            dirty_dirs: Mutex::new(BTreeSet::new()),
+            #[cfg(test)]
+            crash_point: None,
         }
     }
 
-    fn guarded_cache_get(&self, name: &[u8]) -> Option<Result<Option<Vec<u8>>, String>> {
-        match self.read_cache.lock() {
-            Err(e) => Some(Err(e.to_string())),
-            Ok(cache) => cache.get(name).cloned(),
+    /// A backend in the middle of migrating its blobs from a layout with `previous_levels` shard
+    /// levels to one with `shard_levels`. Blobs are found in either layout.
+    pub fn migrating(root: PathBuf, shard_levels: usize, previous_levels: usize) -> FileBackend {
+        FileBackend {
+            previous_levels: Some(previous_levels),
+            ..FileBackend::with_shard_levels(root, shard_levels)
         }
     }
 
-    fn get(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
-        use self::io::Read;
+    fn path(&self, name: &[u8]) -> PathBuf {
+        self.path_at(name, self.shard_levels)
+    }
 
-        // Read key:
-        let path = {
-            let mut p = self.root.clone();
-            p.push(&name.to_hex());
-            p
-        };
+    fn path_at(&self, name: &[u8], shard_levels: usize) -> PathBuf {
+        let mut p = self.root.clone();
+        for b in name.iter().take(shard_levels) {
+            p.push(format!("{:02x}", b));
+        }
+        p.push(&name.to_hex());
+        p
+    }
 
-        match fs::File::open(&path) {
-            Err(_) => Ok(None),
-            Ok(mut fd) => {
-                let mut buf = Vec::new();
-                match fd.read_to_end(&mut buf) {
-                    Ok(_) => Ok(Some(buf)),
-                    Err(e) => Err(e.to_string()),
-                }
-            }
+    /// Where else a blob may be while blobs are moved between layouts.
+    fn old_paths(&self, name: &[u8]) -> Vec<PathBuf> {
+        let mut levels = vec![];
+        if let Some(previous) = self.previous_levels {
+            levels.push(previous);
+        }
+        if self.has_flat_blobs && !levels.contains(&0) {
+            levels.push(0);
         }
+        levels.into_iter()
+            .filter(|&l| l != self.shard_levels)
+            .map(|l| self.path_at(name, l))
+            .collect()
     }
 
-    fn guarded_cache_delete(&self, name: &[u8]) {
-        self.read_cache.lock().unwrap().remove(name);
+    /// Move every blob to where the configured layout expects it, and remove the temporary files
+    /// of interrupted stores on the way. Returns the number of blobs moved. Not safe to run while
+    /// the repository is in use.
+    pub fn migrate(&self) -> Result<usize, BackendError> {
+        let removed = remove_temporary_files(&self.root)?;
+        if removed > 0 {
+            info!("Removed {} temporary files of interrupted stores", removed);
+        }
+        let mut moved = 0;
+        walk(&self.root,
+             &mut |path, name| {
+                 let target = self.path(&name);
+                 if path != target {
+                     self.make_shard_dirs(&target)?;
+                     fs::rename(&path, &target)?;
+                     self.dirty_dirs.lock().unwrap().insert(path.parent().unwrap().to_owned());
+                     moved += 1;
+                 }
+                 Ok(())
+             })?;
+        self.flush()?;
+        Ok(moved)
     }
 
-    fn guarded_cache_put(&self, name: Vec<u8>, result: Result<Option<Vec<u8>>, String>) {
-        let mut cache = self.read_cache.lock().unwrap();
-        if cache.len() >= self.max_cache_size {
-            cache.clear();
+    #[cfg(test)]
+    fn crash_at(&self, point: CrashPoint) -> Result<(), BackendError> {
+        if self.crash_point == Some(point) {
+            return Err(BackendError::Other(format!("Simulated crash: {:?}", point)));
         }
-        cache.insert(name, result);
+        Ok(())
     }
-}
 
-impl StoreBackend for FileBackend {
-    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
-        use self::io::Write;
+    #[cfg(not(test))]
+    fn crash_at(&self, _point: CrashPoint) -> Result<(), BackendError> {
+        Ok(())
+    }
 
-        let mut path = self.root.clone();
-        path.push(&name.to_hex());
+    /// Whether a test simulated a crash, which leaves the temporary file behind.
+    #[cfg(test)]
+    fn crashed(&self) -> bool {
+        self.crash_point.is_some()
+    }
 
-        let mut file = match fs::File::create(&path) {
-            Err(e) => return Err(e.to_string()),
-            Ok(f) => f,
-        };
+    #[cfg(not(test))]
+    fn crashed(&self) -> bool {
+        false
+    }
 
-        for r in data.slices() {
-            if let Err(e) = file.write_all(r) {
-                return Err(e.to_string());
-            }
+    /// Create the shard directories leading to `path`, making each of them durable.
+    fn make_shard_dirs(&self, path: &Path) -> Result<(), BackendError> {
+        let parent = path.parent().unwrap();
+        if parent.is_dir() {
+            return Ok(());
+        }
+        fs::create_dir_all(parent)?;
+        let mut dir = parent;
+        while dir != self.root {
+            dir = dir.parent().unwrap();
+            sync_dir(dir)?;
         }
         Ok(())
     }
 
-    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
-        // Check for key in cache:
-        let value_opt = self.guarded_cache_get(name);
-        if let Some(r) = value_opt {
-            return r;
+    /// Write `data` to `tmp`, and move it to `path` once it is safely on disk. A crash leaves
+    /// either the complete blob at `path` or nothing at all.
+    fn write_durably(&self,
+                     tmp: &Path,
+                     path: &Path,
+                     data: &CipherText)
+                     -> Result<(), BackendError> {
+        use self::io::Write;
+        let mut file = fs::File::create(tmp)?;
+        for (i, r) in data.slices().iter().enumerate() {
+            if i > 0 {
+                self.crash_at(CrashPoint::PartialWrite)?;
+            }
+            file.write_all(r)?;
         }
+        file.sync_all()?;
 
-        let res = self.get(name);
+        self.crash_at(CrashPoint::BeforeRename)?;
+        fs::rename(tmp, path)?;
 
-        // Update cache to contain key:
-        self.guarded_cache_put(name.to_vec(), res.clone());
+        // Make the rename itself durable.
+        self.crash_at(CrashPoint::BeforeDirSync)?;
+        sync_dir(path.parent().unwrap())
+    }
 
-        res
+    fn get(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        use self::io::Read;
+
+        let mut paths = vec![self.path(name)];
+        paths.extend(self.old_paths(name));
+        for path in paths {
+            // Only a missing file means a missing blob; any other error is reported as is.
+            let mut fd = match fs::File::open(&path) {
+                Ok(fd) => fd,
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    let context = format!("Could not open {}", path.display());
+                    return Err(BackendError::from_io(&context, &e));
+                }
+            };
+            let mut buf = Vec::new();
+            return match fd.read_to_end(&mut buf) {
+                Ok(_) => Ok(Some(buf)),
+                Err(e) => Err(e.into()),
+            };
+        }
+        Ok(None)
     }
+}
 
-    fn delete(&self, name: &[u8]) -> Result<(), String> {
-        let name = name.to_vec();
-        self.guarded_cache_delete(&name);
+impl StoreBackend for FileBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let path = self.path(name);
+        self.make_shard_dirs(&path)?;
 
-        let path = {
-            let mut p = self.root.clone();
-            p.push(&name.to_hex());
-            p
-        };
+        // Temporary names are not valid hex, so they never show up as blobs.
+        let tmp = path.with_file_name(format!("{}{}{}",
+                                              name.to_hex(),
+                                              TEMPORARY_INFIX,
+                                              rand::thread_rng().next_u64()));
 
-        match fs::remove_file(&path) {
-            Ok(_) => Ok(()),
-            Err(e) => Err(e.to_string()),
+        let res = self.write_durably(&tmp, &path, data);
+        if res.is_err() && !self.crashed() {
+            let _ = fs::remove_file(&tmp);
         }
+        res?;
+
+        // Replacing a blob from another layout must not leave the old copy behind.
+        for old in self.old_paths(name) {
+            if old.exists() {
+                fs::remove_file(&old)?;
+                sync_dir(old.parent().unwrap())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        // Blobs are renamed into place, which replaces the old file atomically.
+        self.store(name, data)
     }
 
-    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
-        let es = &|e: io::Error| e.to_string();
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.get(name)
+    }
 
-        let mut out = vec![];
-        for p in fs::read_dir(&self.root).map_err(es)? {
-            if let Some(name) = p.map_err(es)?.path().file_name() {
-                name.to_str()
-                    .map(|s| s.from_hex().unwrap())
-                    .map(|b| out.push(b.into_boxed_slice()));
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        let mut path = self.path(name);
+        if !path.exists() {
+            if let Some(old) = self.old_paths(name).into_iter().find(|p| p.exists()) {
+                path = old;
+            }
+        }
+
+        match fs::remove_file(&path) {
+            Ok(_) => {
+                self.dirty_dirs.lock().unwrap().insert(path.parent().unwrap().to_owned());
+                Ok(())
             }
+            Err(e) => Err(e.into()),
         }
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        let mut out = vec![];
+        walk(&self.root,
+             &mut |_, name| {
+                 out.push(name.into_boxed_slice());
+                 Ok(())
+             })?;
         Ok(out)
     }
 
-    fn flush(&self) -> Result<(), String> {
+    fn flush(&self) -> Result<(), BackendError> {
+        // Stores are durable once they return; this makes deletes durable too.
+        let mut dirty = self.dirty_dirs.lock().unwrap();
+        for dir in dirty.iter() {
+            sync_dir(dir)?;
+        }
+        dirty.clear();
         Ok(())
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::StoreBackend;
+    use crypto::CipherText;
+    use rustc_serialize::hex::ToHex;
+    use std::fs;
+    use util::TempDir;
+
+    fn blob() -> CipherText {
+        let mut data = CipherText::new(b"hello ".to_vec());
+        data.append(CipherText::new(b"world".to_vec()));
+        data
+    }
+
+    #[test]
+    fn store_and_list() {
+        let dir = TempDir::new("hat-file-backend");
+        let backend = FileBackend::new(dir.path().to_path_buf());
+
+        backend.store(b"blob", &blob()).unwrap();
+        backend.flush().unwrap();
+        assert_eq!(Some(blob().to_vec()), backend.retrieve(b"blob").unwrap());
+        assert_eq!(vec![b"blob".to_vec().into_boxed_slice()], backend.list().unwrap());
+
+        // Only the blob itself is left in the directory.
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[test]
+    fn sharded_layout() {
+        let dir = TempDir::new("hat-file-backend");
+        let backend = FileBackend::with_shard_levels(dir.path().to_path_buf(), 2);
+
+        backend.store(&[0xab, 0xcd, 0xef], &blob()).unwrap();
+        assert!(dir.join("ab/cd/abcdef").is_file());
+        assert_eq!(Some(blob().to_vec()), backend.retrieve(&[0xab, 0xcd, 0xef]).unwrap());
+        assert_eq!(vec![vec![0xab, 0xcd, 0xef].into_boxed_slice()], backend.list().unwrap());
+
+        backend.delete(&[0xab, 0xcd, 0xef]).unwrap();
+        backend.flush().unwrap();
+        assert!(backend.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn read_errors_are_not_missing_blobs() {
+        let dir = TempDir::new("hat-file-backend");
+        let backend = FileBackend::with_shard_levels(dir.path().to_path_buf(), 2);
+        assert_eq!(None, backend.retrieve(&[0xab, 0xcd, 0xef]).unwrap());
+
+        // A file where a shard directory belongs makes opening the blob fail with ENOTDIR.
+        fs::File::create(dir.join("ab")).unwrap();
+        assert!(backend.retrieve(&[0xab, 0xcd, 0xef]).is_err());
+    }
+
+    #[test]
+    fn migrate_from_flat_layout() {
+        let dir = TempDir::new("hat-file-backend");
+        let names: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i, i * 7, 3]).collect();
+        let flat = FileBackend::new(dir.path().to_path_buf());
+        for name in &names {
+            flat.store(name, &blob()).unwrap();
+        }
+
+        // Flat blobs are found through a sharded backend before migrating, and stores replacing
+        // them do not leave the old copies behind.
+        let sharded = FileBackend::with_shard_levels(dir.path().to_path_buf(), 2);
+        assert_eq!(Some(blob().to_vec()), sharded.retrieve(&names[0]).unwrap());
+        sharded.replace(&names[1], &blob()).unwrap();
+        sharded.delete(&names[2]).unwrap();
+        assert_eq!(names.len() - 1, sharded.list().unwrap().len());
+
+        assert_eq!(names.len() - 2, sharded.migrate().unwrap());
+        assert_eq!(0, sharded.migrate().unwrap());
+
+        let sharded = FileBackend::with_shard_levels(dir.path().to_path_buf(), 2);
+        assert!(!sharded.has_flat_blobs);
+        let mut listed: Vec<Vec<u8>> =
+            sharded.list().unwrap().iter().map(|n| n.to_vec()).collect();
+        listed.sort();
+        let mut expected = names.clone();
+        expected.remove(2);
+        assert_eq!(expected, listed);
+        for name in &expected {
+            assert_eq!(Some(blob().to_vec()), sharded.retrieve(name).unwrap());
+            let path = format!("{:02x}/{:02x}/{}", name[0], name[1], name.to_hex());
+            assert!(dir.join(path).is_file());
+        }
+    }
+
+    #[test]
+    fn migrate_between_sharded_layouts() {
+        let dir = TempDir::new("hat-file-backend");
+        let names: Vec<Vec<u8>> = (0..20u8).map(|i| vec![i, i * 7, 3]).collect();
+        let deep = FileBackend::with_shard_levels(dir.path().to_path_buf(), 2);
+        for name in &names {
+            deep.store(name, &blob()).unwrap();
+        }
+
+        // Until the migration finishes, blobs at the previous depth are found, replaced and
+        // deleted like any other.
+        let shallow = FileBackend::migrating(dir.path().to_path_buf(), 1, 2);
+        assert_eq!(Some(blob().to_vec()), shallow.retrieve(&names[0]).unwrap());
+        shallow.replace(&names[1], &blob()).unwrap();
+        shallow.delete(&names[2]).unwrap();
+        shallow.flush().unwrap();
+        assert_eq!(names.len() - 1, shallow.list().unwrap().len());
+        assert!(!dir.join(format!("01/07/{}", names[1].to_hex())).exists());
+
+        assert_eq!(names.len() - 2, shallow.migrate().unwrap());
+        let shallow = FileBackend::with_shard_levels(dir.path().to_path_buf(), 1);
+        for name in names.iter().filter(|n| n[0] != 2) {
+            assert_eq!(Some(blob().to_vec()), shallow.retrieve(name).unwrap());
+            let path = format!("{:02x}/{}", name[0], name.to_hex());
+            assert!(dir.join(path).is_file());
+        }
+    }
+
+    #[test]
+    fn crashes_never_leave_partial_blobs() {
+        for &point in &[CrashPoint::PartialWrite,
+                        CrashPoint::BeforeRename,
+                        CrashPoint::BeforeDirSync] {
+            let dir = TempDir::new("hat-file-backend");
+            let crashing = FileBackend {
+                crash_point: Some(point),
+                ..FileBackend::new(dir.path().to_path_buf())
+            };
+            assert!(crashing.store(b"blob", &blob()).is_err());
+
+            // After a restart, the blob is either complete or missing, and maintenance removes
+            // the temporary file.
+            let backend = FileBackend::new(dir.path().to_path_buf());
+            backend.migrate().unwrap();
+            assert_eq!(backend.list().unwrap().len(),
+                       fs::read_dir(dir.path()).unwrap().count());
+            match backend.retrieve(b"blob").unwrap() {
+                None => assert!(backend.list().unwrap().is_empty(), "{:?}", point),
+                Some(data) => {
+                    assert_eq!(blob().to_vec(), data);
+                    assert_eq!(1, backend.list().unwrap().len());
+                }
+            }
+
+            // Storing it again works.
+            backend.store(b"blob", &blob()).unwrap();
+            let restarted = FileBackend::new(dir.path().to_path_buf());
+            assert_eq!(Some(blob().to_vec()), restarted.retrieve(b"blob").unwrap());
+        }
+    }
+}
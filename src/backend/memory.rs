@@ -13,7 +13,7 @@
 // limitations under the License.
 
 
-use backend::StoreBackend;
+use backend::{BackendError, StoreBackend};
 use crypto::CipherText;
 use std::collections::BTreeMap;
 use std::sync::Mutex;
@@ -27,52 +27,57 @@ impl MemoryBackend {
         MemoryBackend { files: Mutex::new(BTreeMap::new()) }
     }
 
-    fn guarded_insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), String> {
+    fn guarded_insert(&self, key: Vec<u8>, value: Vec<u8>) -> Result<(), BackendError> {
         let mut guarded_files = self.files.lock().unwrap();
         if guarded_files.contains_key(&key) {
-            return Err(format!("Key already exists: '{:?}'", key));
+            return Err(BackendError::Other(format!("Key already exists: '{:?}'", key)));
         }
         guarded_files.insert(key, value);
         Ok(())
     }
 
-    fn guarded_retrieve(&self, key: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn guarded_retrieve(&self, key: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
         match self.files.lock() {
-            Err(e) => Err(e.to_string()),
+            Err(e) => Err(BackendError::Other(e.to_string())),
             Ok(map) => Ok(map.get(key).cloned()),
         }
     }
 
-    fn guarded_delete(&self, key: &[u8]) -> Result<(), String> {
+    fn guarded_delete(&self, key: &[u8]) -> Result<(), BackendError> {
         let mut guarded_files = self.files.lock().unwrap();
         guarded_files.remove(key);
         Ok(())
     }
 
-    fn guarded_list(&self) -> Result<Vec<Box<[u8]>>, String> {
+    fn guarded_list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
         let guarded_files = self.files.lock().unwrap();
         Ok(guarded_files.keys().cloned().map(|x| x.into_boxed_slice()).collect())
     }
 }
 
 impl StoreBackend for MemoryBackend {
-    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
         self.guarded_insert(name.to_vec(), data.to_vec())
     }
 
-    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.files.lock().unwrap().insert(name.to_vec(), data.to_vec());
+        Ok(())
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
         self.guarded_retrieve(name)
     }
 
-    fn delete(&self, name: &[u8]) -> Result<(), String> {
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
         self.guarded_delete(name)
     }
 
-    fn list(&self) -> Result<Vec<Box<[u8]>>, String> {
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
         self.guarded_list()
     }
 
-    fn flush(&self) -> Result<(), String> {
+    fn flush(&self) -> Result<(), BackendError> {
         Ok(())
     }
 }
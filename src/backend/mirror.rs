@@ -0,0 +1,393 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A backend that keeps copies of every blob in several other backends.
+//!
+//! Blobs are stored on all replicas at once, and a store succeeds once a quorum of them has it.
+//! Reads try the replicas in order, so the cheapest replica (e.g. a local disk) should come first.
+//! Each copy carries a checksum, so reads skip damaged copies too. Replicas that missed blobs,
+//! e.g. because they were unreachable for a while, catch up with `repair`.
+
+use backend::{BackendError, StoreBackend, on_all, worst_error};
+use crypto::CipherText;
+use rustc_serialize::hex::ToHex;
+use scoped_pool::Pool;
+use sodiumoxide::crypto::hash::sha256;
+use std::collections::BTreeSet;
+
+
+/// The copy of `data` that goes to the replicas: the blob followed by its checksum.
+fn with_checksum(data: &CipherText) -> CipherText {
+    let mut copy = data.to_vec();
+    let checksum = sha256::hash(&copy[..]).0;
+    copy.extend_from_slice(&checksum[..]);
+    CipherText::new(copy)
+}
+
+/// The blob in `copy`, unless the copy is damaged.
+fn verify_checksum(mut copy: Vec<u8>) -> Option<Vec<u8>> {
+    if copy.len() < sha256::DIGESTBYTES {
+        return None;
+    }
+    let len = copy.len() - sha256::DIGESTBYTES;
+    if &sha256::hash(&copy[..len]).0[..] != &copy[len..] {
+        return None;
+    }
+    copy.truncate(len);
+    Some(copy)
+}
+
+
+pub struct MirrorBackend {
+    replicas: Vec<Box<StoreBackend>>,
+    quorum: usize,
+    pool: Pool,
+}
+
+impl MirrorBackend {
+    /// Copy blobs to all of `replicas`, and consider them stored once `quorum` replicas have them.
+    pub fn new(replicas: Vec<Box<StoreBackend>>, quorum: usize) -> Result<MirrorBackend, String> {
+        if replicas.is_empty() {
+            return Err("A mirror needs at least one replica".to_owned());
+        }
+        if quorum < 1 || quorum > replicas.len() {
+            return Err(format!("Invalid quorum {} for {} replicas", quorum, replicas.len()));
+        }
+        Ok(MirrorBackend {
+            pool: Pool::new(replicas.len()),
+            replicas: replicas,
+            quorum: quorum,
+        })
+    }
+
+    /// Succeed if at least a quorum of the replicas did.
+    fn quorum(&self,
+              what: &str,
+              name: &[u8],
+              results: Vec<Result<(), BackendError>>)
+              -> Result<(), BackendError> {
+        let errors: Vec<_> = results.into_iter().filter_map(|r| r.err()).collect();
+        let acks = self.replicas.len() - errors.len();
+        if acks >= self.quorum {
+            for e in &errors {
+                warn!("Could not {} {} on a replica: {}", what, name.to_hex(), e);
+            }
+            return Ok(());
+        }
+        warn!("Only {} of {} replicas could {} {}",
+              acks,
+              self.replicas.len(),
+              what,
+              name.to_hex());
+        Err(worst_error(errors))
+    }
+
+    /// Read a blob from the first of the replicas selected by `select` that has an intact copy.
+    fn retrieve_from<F>(&self, name: &[u8], select: F) -> Result<Option<Vec<u8>>, BackendError>
+        where F: Fn(usize) -> bool
+    {
+        let mut errors = vec![];
+        for (i, replica) in self.replicas.iter().enumerate().filter(|&(i, _)| select(i)) {
+            match replica.retrieve(name) {
+                Ok(Some(copy)) => {
+                    if let Some(data) = verify_checksum(copy) {
+                        return Ok(Some(data));
+                    }
+                    warn!("Replica {} has a damaged copy of {}", i, name.to_hex());
+                    errors.push(BackendError::Corruption(format!("Damaged copy of {} on \
+                                                                  replica {}",
+                                                                 name.to_hex(),
+                                                                 i)));
+                }
+                Ok(None) => (),
+                Err(e) => {
+                    debug!("Replica {} could not retrieve {}: {}", i, name.to_hex(), e);
+                    errors.push(e);
+                }
+            }
+        }
+        // A replica that failed may well have the blob, so we cannot say that it is missing.
+        if errors.is_empty() {
+            Ok(None)
+        } else {
+            Err(worst_error(errors))
+        }
+    }
+
+    /// Copy blobs to the replicas that are missing them, and return the number of copies made.
+    pub fn repair(&self) -> Result<usize, BackendError> {
+        let mut listings = vec![];
+        for listing in on_all(&self.pool, &self.replicas, |_, r| r.list()) {
+            listings.push(listing?.into_iter().collect::<BTreeSet<_>>());
+        }
+        let all: BTreeSet<_> = listings.iter().flat_map(|l| l.iter().cloned()).collect();
+
+        let mut copies = 0;
+        for name in &all {
+            let missing: Vec<_> = (0..listings.len())
+                .filter(|&i| !listings[i].contains(name))
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+            let copy = match self.retrieve_from(name, |i| listings[i].contains(name))? {
+                Some(data) => with_checksum(&CipherText::new(data)),
+                None => {
+                    warn!("Blob {} disappeared during repair", name.to_hex());
+                    continue;
+                }
+            };
+            for i in missing {
+                self.replicas[i].store(name, &copy)?;
+                copies += 1;
+            }
+        }
+        Ok(copies)
+    }
+}
+
+impl Drop for MirrorBackend {
+    fn drop(&mut self) {
+        self.pool.shutdown();
+    }
+}
+
+impl StoreBackend for MirrorBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let copy = with_checksum(data);
+        let results = on_all(&self.pool, &self.replicas, |_, r| r.store(name, &copy));
+        self.quorum("store", name, results)
+    }
+
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        // Replaces must reach every replica, or reads could still find the old contents.
+        let copy = with_checksum(data);
+        let errors: Vec<_> = on_all(&self.pool, &self.replicas, |_, r| r.replace(name, &copy))
+            .into_iter()
+            .filter_map(|r| r.err())
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(worst_error(errors))
+        }
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.retrieve_from(name, |_| true)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        // Deletes must reach every replica, or `repair` would bring the blob back.
+        let errors: Vec<_> = on_all(&self.pool, &self.replicas, |_, r| r.delete(name))
+            .into_iter()
+            .filter_map(|r| r.err())
+            .filter(|e| match *e {
+                BackendError::NotFound(..) => false,
+                _ => true,
+            })
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(worst_error(errors))
+        }
+    }
+
+    /// List the blobs of the replicas that can be reached. Every stored blob is on a quorum of
+    /// replicas, so all but `quorum - 1` of them are enough to see all blobs.
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        let mut names = BTreeSet::new();
+        let mut errors = vec![];
+        for listing in on_all(&self.pool, &self.replicas, |_, r| r.list()) {
+            match listing {
+                Ok(listing) => names.extend(listing),
+                Err(e) => errors.push(e),
+            }
+        }
+        if errors.len() >= self.quorum {
+            return Err(worst_error(errors));
+        }
+        for e in &errors {
+            warn!("Could not list the blobs of a replica: {}", e);
+        }
+        Ok(names.into_iter().collect())
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        let errors: Vec<_> = on_all(&self.pool, &self.replicas, |_, r| r.flush())
+            .into_iter()
+            .filter_map(|r| r.err())
+            .collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(worst_error(errors))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{BackendError, MemoryBackend, StoreBackend};
+    use crypto::CipherText;
+
+    /// A replica that is unreachable.
+    struct DownBackend;
+
+    impl StoreBackend for DownBackend {
+        fn store(&self, _name: &[u8], _data: &CipherText) -> Result<(), BackendError> {
+            Err(BackendError::Transient("Connection refused".to_owned()))
+        }
+        fn replace(&self, _name: &[u8], _data: &CipherText) -> Result<(), BackendError> {
+            Err(BackendError::Transient("Connection refused".to_owned()))
+        }
+        fn retrieve(&self, _name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+            Err(BackendError::Corruption("Short read".to_owned()))
+        }
+        fn delete(&self, _name: &[u8]) -> Result<(), BackendError> {
+            Err(BackendError::Transient("Connection refused".to_owned()))
+        }
+        fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+            Err(BackendError::Transient("Connection refused".to_owned()))
+        }
+        fn flush(&self) -> Result<(), BackendError> {
+            Ok(())
+        }
+    }
+
+    fn mirror(down: bool, quorum: usize) -> MirrorBackend {
+        let mut replicas: Vec<Box<StoreBackend>> = vec![];
+        if down {
+            replicas.push(Box::new(DownBackend));
+        }
+        replicas.push(Box::new(MemoryBackend::new()));
+        replicas.push(Box::new(MemoryBackend::new()));
+        MirrorBackend::new(replicas, quorum).unwrap()
+    }
+
+    fn blob(data: &[u8]) -> CipherText {
+        CipherText::new(data.to_vec())
+    }
+
+    /// A copy of `data` as stored in a replica.
+    fn copy(data: &[u8]) -> CipherText {
+        with_checksum(&blob(data))
+    }
+
+    #[test]
+    fn invalid_quorum() {
+        assert!(MirrorBackend::new(vec![], 1).is_err());
+        assert!(MirrorBackend::new(vec![Box::new(MemoryBackend::new())], 0).is_err());
+        assert!(MirrorBackend::new(vec![Box::new(MemoryBackend::new())], 2).is_err());
+    }
+
+    #[test]
+    fn stores_need_a_quorum() {
+        let backend = mirror(true, 2);
+        backend.store(&[1], &blob(b"one")).unwrap();
+        for replica in &backend.replicas[1..] {
+            assert_eq!(Some(copy(b"one").to_vec()), replica.retrieve(&[1]).unwrap());
+        }
+
+        let backend = mirror(true, 3);
+        assert!(backend.store(&[1], &blob(b"one")).unwrap_err().is_transient());
+    }
+
+    #[test]
+    fn retrieve_falls_back_to_other_replicas() {
+        let backend = mirror(true, 1);
+        backend.replicas[2].store(&[1], &copy(b"one")).unwrap();
+        // The first replica fails and the second has no copy.
+        assert_eq!(Some(b"one".to_vec()), backend.retrieve(&[1]).unwrap());
+        // Without a copy anywhere, the failure of the first replica is all we know.
+        assert!(backend.retrieve(&[2]).is_err());
+
+        let backend = mirror(false, 1);
+        assert_eq!(None, backend.retrieve(&[2]).unwrap());
+    }
+
+    #[test]
+    fn retrieve_skips_damaged_copies() {
+        let backend = mirror(false, 1);
+        backend.store(&[1], &blob(b"one")).unwrap();
+        backend.replicas[0].replace(&[1], &blob(b"one")).unwrap();
+        assert_eq!(Some(b"one".to_vec()), backend.retrieve(&[1]).unwrap());
+
+        backend.replicas[1].replace(&[1], &blob(b"")).unwrap();
+        match backend.retrieve(&[1]) {
+            Err(BackendError::Corruption(..)) => (),
+            other => panic!("Expected a corruption error: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn replaces_reach_all_replicas() {
+        let backend = mirror(false, 1);
+        backend.store(&[1], &blob(b"one")).unwrap();
+        backend.replace(&[1], &blob(b"two")).unwrap();
+        for replica in &backend.replicas {
+            assert_eq!(Some(copy(b"two").to_vec()), replica.retrieve(&[1]).unwrap());
+        }
+
+        let backend = mirror(true, 1);
+        assert!(backend.replace(&[1], &blob(b"two")).unwrap_err().is_transient());
+    }
+
+    #[test]
+    fn deletes_reach_all_replicas() {
+        let backend = mirror(false, 1);
+        backend.replicas[0].store(&[1], &copy(b"one")).unwrap();
+        backend.delete(&[1]).unwrap();
+        assert_eq!(None, backend.retrieve(&[1]).unwrap());
+
+        let backend = mirror(true, 1);
+        assert!(backend.delete(&[1]).unwrap_err().is_transient());
+    }
+
+    #[test]
+    fn list_and_repair() {
+        let backend = mirror(false, 1);
+        backend.replicas[0].store(&[1], &copy(b"one")).unwrap();
+        backend.replicas[1].store(&[2], &copy(b"two")).unwrap();
+        backend.store(&[3], &blob(b"three")).unwrap();
+
+        let all = vec![vec![1].into_boxed_slice(),
+                       vec![2].into_boxed_slice(),
+                       vec![3].into_boxed_slice()];
+        assert_eq!(all, backend.list().unwrap());
+
+        assert_eq!(2, backend.repair().unwrap());
+        for replica in &backend.replicas {
+            assert_eq!(all, replica.list().unwrap());
+            assert_eq!(Some(copy(b"two").to_vec()), replica.retrieve(&[2]).unwrap());
+        }
+        assert_eq!(0, backend.repair().unwrap());
+
+        // Repairs need to see every replica.
+        assert!(mirror(true, 1).repair().is_err());
+    }
+
+    #[test]
+    fn list_tolerates_fewer_replicas_than_a_quorum_down() {
+        let backend = mirror(true, 2);
+        backend.store(&[1], &blob(b"one")).unwrap();
+        assert_eq!(vec![vec![1].into_boxed_slice()], backend.list().unwrap());
+
+        // With a quorum of one, the replica that is down may hold the only copy of a blob.
+        assert!(mirror(true, 1).list().unwrap_err().is_transient());
+    }
+}
@@ -12,20 +12,98 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+mod cache;
+mod command;
 mod devnull;
+mod erasure;
+mod error;
+#[cfg(test)]
+mod faulty;
 mod file;
 mod memory;
+mod mirror;
+mod pack;
+mod retry;
+mod s3;
+mod sftp;
+mod throttle;
 
 use crypto::CipherText;
+use scoped_pool::Pool;
+use std::sync::Mutex;
 
+pub use self::cache::{CacheStats, CachingBackend};
+pub use self::command::CommandBackend;
 pub use self::devnull::DevNullBackend;
+pub use self::erasure::ErasureBackend;
+pub use self::error::BackendError;
+#[cfg(test)]
+pub use self::faulty::{CRASH_STATUS, Fault, FaultyBackend};
 pub use self::file::FileBackend;
 pub use self::memory::MemoryBackend;
+pub use self::mirror::MirrorBackend;
+pub use self::pack::{DEFAULT_MAX_PACK_SIZE, PackBackend};
+pub use self::retry::{RetryBackend, RetryPolicy};
+pub use self::s3::{DEFAULT_MULTIPART_THRESHOLD, S3Backend};
+pub use self::sftp::SftpBackend;
+pub use self::throttle::{ThrottleLimits, ThrottledBackend, parse_byte_rate, parse_request_rate};
 
 pub trait StoreBackend: Sync + Send + 'static {
-    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), String>;
-    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, String>;
-    fn delete(&self, name: &[u8]) -> Result<(), String>;
-    fn list(&self) -> Result<Vec<Box<[u8]>>, String>;
-    fn flush(&self) -> Result<(), String>;
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError>;
+    /// Overwrite an existing blob with new contents of the same length. This must be atomic:
+    /// whatever happens, the blob can afterwards be read back whole, with either its old or its
+    /// new contents.
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError>;
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError>;
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError>;
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError>;
+    fn flush(&self) -> Result<(), BackendError>;
+}
+
+impl StoreBackend for Box<StoreBackend> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        (**self).store(name, data)
+    }
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        (**self).replace(name, data)
+    }
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        (**self).retrieve(name)
+    }
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        (**self).delete(name)
+    }
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        (**self).list()
+    }
+    fn flush(&self) -> Result<(), BackendError> {
+        (**self).flush()
+    }
+}
+
+/// Run `f` on each of `backends` and its index in parallel, and return the results in the same
+/// order.
+fn on_all<T, F>(pool: &Pool, backends: &[Box<StoreBackend>], f: F) -> Vec<Result<T, BackendError>>
+    where F: Fn(usize, &StoreBackend) -> Result<T, BackendError> + Sync,
+          T: Send
+{
+    let results: Vec<Mutex<Option<Result<T, BackendError>>>> =
+        backends.iter().map(|_| Mutex::new(None)).collect();
+    {
+        let f = &f;
+        let results = &results;
+        pool.scoped(|scope| {
+            for (i, backend) in backends.iter().enumerate() {
+                scope.execute(move || *results[i].lock().unwrap() = Some(f(i, &**backend)));
+            }
+        });
+    }
+    results.into_iter().map(|r| r.into_inner().unwrap().unwrap()).collect()
+}
+
+/// The error to report for a failed operation: a transient one if there is any, since the
+/// operation may then succeed when retried.
+fn worst_error(errors: Vec<BackendError>) -> BackendError {
+    let transient = errors.iter().position(|e| e.is_transient()).unwrap_or(0);
+    errors.into_iter().nth(transient).expect("no errors to report")
 }
@@ -0,0 +1,758 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! A backend that appends blobs to a few large pack files.
+//!
+//! Some media only take appends, and some filesystems handle millions of small files badly. This
+//! backend writes every blob to the end of the current pack, and starts a new pack once that one
+//! is full. A small index next to the packs maps blob names to their place in a pack; it only
+//! grows by appending too. Deletes append a tombstone, and the space of deleted blobs is only
+//! freed when `compact` rewrites the packs. The packs describe themselves, so `rebuild_index` can
+//! recreate a lost index from them.
+//!
+//! A pack entry is either a blob, `"HATB" | name length (u16) | name | length (u64) | data |
+//! sha256(data)`, or a tombstone, `"HATD" | name length (u16) | name`. An index record is
+//! `deleted (u8) | pack (u32) | offset (u64) | length (u64) | name length (u16) | name`, with the
+//! offset of the data of a blob, or of the end of a tombstone. All numbers are little-endian.
+
+use backend::{BackendError, StoreBackend};
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use crypto::CipherText;
+use rustc_serialize::hex::ToHex;
+use sodiumoxide::crypto::hash::sha256;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+
+/// Size at which a new pack is started.
+pub const DEFAULT_MAX_PACK_SIZE: u64 = 1 << 30;
+
+const BLOB_MAGIC: &'static [u8] = b"HATB";
+const TOMBSTONE_MAGIC: &'static [u8] = b"HATD";
+const INDEX_FILE: &'static str = "index";
+const RECORD_HEADER_LEN: usize = 1 + 4 + 8 + 8 + 2;
+
+fn pack_path(dir: &Path, pack: u32) -> PathBuf {
+    dir.join(format!("pack-{:08}", pack))
+}
+
+/// The numbers of the packs in `dir`, in order.
+fn pack_numbers(dir: &Path) -> Result<Vec<u32>, BackendError> {
+    let mut numbers = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let file_name = entry.file_name();
+        let name = match file_name.to_str() {
+            Some(name) => name,
+            None => continue,
+        };
+        if name.starts_with("pack-") {
+            if let Ok(n) = name[5..].parse() {
+                numbers.push(n);
+            }
+        }
+    }
+    numbers.sort();
+    Ok(numbers)
+}
+
+fn sync_dir(dir: &Path) -> Result<(), BackendError> {
+    Ok(fs::File::open(dir).and_then(|dir| dir.sync_all())?)
+}
+
+/// Open `path` for writing at `len`, dropping whatever a failed write left after it.
+fn open_at(path: &Path, len: u64) -> Result<fs::File, BackendError> {
+    let mut file = fs::OpenOptions::new().write(true).create(true).open(path)?;
+    file.set_len(len)?;
+    file.seek(SeekFrom::Start(len))?;
+    if len == 0 {
+        // Make the new file itself durable.
+        sync_dir(path.parent().unwrap())?;
+    }
+    Ok(file)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Location {
+    pack: u32,
+    offset: u64,
+    length: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+struct Record {
+    name: Vec<u8>,
+    location: Location,
+    deleted: bool,
+}
+
+impl Record {
+    /// Where the pack entry of this record ends.
+    fn end(&self) -> u64 {
+        if self.deleted {
+            self.location.offset
+        } else {
+            self.location.offset + self.location.length + sha256::DIGESTBYTES as u64
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(RECORD_HEADER_LEN + self.name.len());
+        out.push(self.deleted as u8);
+        out.write_u32::<LittleEndian>(self.location.pack).unwrap();
+        out.write_u64::<LittleEndian>(self.location.offset).unwrap();
+        out.write_u64::<LittleEndian>(self.location.length).unwrap();
+        out.write_u16::<LittleEndian>(self.name.len() as u16).unwrap();
+        out.extend_from_slice(&self.name);
+        out
+    }
+
+    /// Decode the records of an index, and return them with the length of the index up to the
+    /// last complete record.
+    fn decode_all(mut index: &[u8]) -> (Vec<Record>, u64) {
+        let mut records = vec![];
+        let mut valid = 0;
+        while index.len() >= RECORD_HEADER_LEN {
+            let deleted = index[0] != 0;
+            let mut header = &index[1..RECORD_HEADER_LEN];
+            let location = Location {
+                pack: header.read_u32::<LittleEndian>().unwrap(),
+                offset: header.read_u64::<LittleEndian>().unwrap(),
+                length: header.read_u64::<LittleEndian>().unwrap(),
+            };
+            let name_len = header.read_u16::<LittleEndian>().unwrap() as usize;
+            if index.len() < RECORD_HEADER_LEN + name_len {
+                break;
+            }
+            records.push(Record {
+                name: index[RECORD_HEADER_LEN..RECORD_HEADER_LEN + name_len].to_vec(),
+                location: location,
+                deleted: deleted,
+            });
+            index = &index[RECORD_HEADER_LEN + name_len..];
+            valid += (RECORD_HEADER_LEN + name_len) as u64;
+        }
+        (records, valid)
+    }
+}
+
+/// Read the entries of pack `pack`. An incomplete or damaged entry, as left by a crash or by bad
+/// media, is skipped up to the next blob whose checksum matches. Tombstones in the skipped part
+/// are lost with it, so the blobs they deleted come back.
+fn scan_pack(dir: &Path, pack: u32) -> Result<Vec<Record>, BackendError> {
+    let path = pack_path(dir, pack);
+    let file = fs::File::open(&path)?;
+    let size = file.metadata()?.len();
+    let mut reader = io::BufReader::new(file);
+
+    let mut records = vec![];
+    let mut offset = 0;
+    // Where the damaged part that is being skipped starts.
+    let mut damaged = None;
+    while offset < size {
+        reader.seek(SeekFrom::Start(offset))?;
+        let mut magic = [0; 4];
+        let entry = match reader.read_exact(&mut magic) {
+            Ok(()) => read_entry(&mut reader, &magic, pack, offset, size)?,
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => None,
+            Err(e) => return Err(e.into()),
+        };
+        match entry {
+            Some(record) => {
+                if let Some(start) = damaged.take() {
+                    warn!("Skipping damaged entries in {} from offset {} to {}",
+                          path.display(),
+                          start,
+                          offset);
+                }
+                offset = record.end();
+                records.push(record);
+            }
+            None => {
+                if damaged.is_none() {
+                    damaged = Some(offset);
+                }
+                match find_blob_magic(&mut reader, offset + 1)? {
+                    Some(next) => offset = next,
+                    None => break,
+                }
+            }
+        }
+    }
+    if let Some(start) = damaged {
+        warn!("Ignoring the damaged end of {} from offset {}", path.display(), start);
+    }
+    Ok(records)
+}
+
+/// The offset of the next blob magic in `reader` from offset `from` on.
+fn find_blob_magic<R: Read + Seek>(reader: &mut R, from: u64) -> Result<Option<u64>, BackendError> {
+    reader.seek(SeekFrom::Start(from))?;
+    let mut window = vec![];
+    let mut window_start = from;
+    let mut chunk = [0; 64 * 1024];
+    loop {
+        let n = reader.read(&mut chunk)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        window.extend_from_slice(&chunk[..n]);
+        if let Some(i) = window.windows(BLOB_MAGIC.len()).position(|w| w == BLOB_MAGIC) {
+            return Ok(Some(window_start + i as u64));
+        }
+        // Keep the bytes a magic split across chunks could start with.
+        let keep = BLOB_MAGIC.len() - 1;
+        if window.len() > keep {
+            let skipped = window.len() - keep;
+            window.drain(..skipped);
+            window_start += skipped as u64;
+        }
+    }
+}
+
+/// Read the rest of the pack entry at `offset`, after its `magic`.
+fn read_entry<R: Read>(reader: &mut R,
+                       magic: &[u8],
+                       pack: u32,
+                       offset: u64,
+                       size: u64)
+                       -> Result<Option<Record>, BackendError> {
+    let eof = |e: io::Error| if e.kind() == io::ErrorKind::UnexpectedEof {
+        Ok(None)
+    } else {
+        Err(BackendError::from(e))
+    };
+
+    let name_len = match reader.read_u16::<LittleEndian>() {
+        Ok(len) => len as u64,
+        Err(e) => return eof(e),
+    };
+    let mut name = vec![0; name_len as usize];
+    if let Err(e) = reader.read_exact(&mut name) {
+        return eof(e);
+    }
+    let header_end = offset + 4 + 2 + name_len;
+
+    if magic == TOMBSTONE_MAGIC {
+        return Ok(Some(Record {
+            name: name,
+            location: Location {
+                pack: pack,
+                offset: header_end,
+                length: 0,
+            },
+            deleted: true,
+        }));
+    } else if magic != BLOB_MAGIC {
+        return Ok(None);
+    }
+
+    let length = match reader.read_u64::<LittleEndian>() {
+        Ok(length) => length,
+        Err(e) => return eof(e),
+    };
+    let data_offset = header_end + 8;
+    // A torn header may claim any length; do not trust it beyond the end of the pack.
+    if data_offset + length + sha256::DIGESTBYTES as u64 > size {
+        return Ok(None);
+    }
+    let mut data = vec![0; length as usize + sha256::DIGESTBYTES];
+    if let Err(e) = reader.read_exact(&mut data) {
+        return eof(e);
+    }
+    if !checksum_matches(&data) {
+        return Ok(None);
+    }
+    Ok(Some(Record {
+        name: name,
+        location: Location {
+            pack: pack,
+            offset: data_offset,
+            length: length,
+        },
+        deleted: false,
+    }))
+}
+
+fn write_entry(file: &mut fs::File,
+               header: &[u8],
+               data: Option<&CipherText>)
+               -> Result<(), BackendError> {
+    file.write_all(header)?;
+    if let Some(data) = data {
+        let bytes = data.to_vec();
+        file.write_all(&bytes)?;
+        file.write_all(&sha256::hash(&bytes).0)?;
+    }
+    Ok(file.sync_data()?)
+}
+
+/// Whether `data` ends with the checksum of the rest of it.
+fn checksum_matches(data: &[u8]) -> bool {
+    let body_len = data.len() - sha256::DIGESTBYTES;
+    &sha256::hash(&data[..body_len]).0[..] == &data[body_len..]
+}
+
+/// Replace the index in `dir` with one holding `records`, and return its length.
+fn write_index(dir: &Path, records: &[Record]) -> Result<u64, BackendError> {
+    let tmp = dir.join(format!("{}.tmp", INDEX_FILE));
+    let mut file = fs::File::create(&tmp)?;
+    let mut len = 0;
+    for record in records {
+        let bytes = record.encode();
+        file.write_all(&bytes)?;
+        len += bytes.len() as u64;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(INDEX_FILE))?;
+    sync_dir(dir)?;
+    Ok(len)
+}
+
+#[derive(Default)]
+struct Packs {
+    blobs: HashMap<Vec<u8>, Location>,
+    /// Where the last complete entry of each pack ends.
+    ends: BTreeMap<u32, u64>,
+    /// Length of the index up to its last complete record.
+    index_len: u64,
+    /// The pack being appended to, and the index, while open for writing.
+    writer: Option<(u32, fs::File)>,
+    index: Option<fs::File>,
+}
+
+impl Packs {
+    fn apply(&mut self, record: Record) {
+        let end = self.ends.entry(record.location.pack).or_insert(0);
+        if record.end() > *end {
+            *end = record.end();
+        }
+        if record.deleted {
+            self.blobs.remove(&record.name);
+        } else {
+            self.blobs.insert(record.name, record.location);
+        }
+    }
+}
+
+pub struct PackBackend {
+    dir: PathBuf,
+    max_pack_size: u64,
+    packs: Mutex<Packs>,
+    /// Held for reading while a blob is read from its pack, and for writing by `compact`, which
+    /// removes the packs that the locations of the blobs pointed into.
+    compaction: RwLock<()>,
+}
+
+impl PackBackend {
+    /// Open the packs in `dir`. Fails if there are packs but no index to go with them.
+    pub fn new(dir: PathBuf, max_pack_size: u64) -> Result<PackBackend, BackendError> {
+        let mut packs = Packs::default();
+        let index = dir.join(INDEX_FILE);
+        match fs::File::open(&index) {
+            Ok(mut file) => {
+                let mut bytes = vec![];
+                file.read_to_end(&mut bytes)?;
+                let (records, valid) = Record::decode_all(&bytes);
+                for record in records {
+                    packs.apply(record);
+                }
+                packs.index_len = valid;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                if !pack_numbers(&dir)?.is_empty() {
+                    return Err(BackendError::Other(format!("The index of the packs in {} is \
+                                                            missing (see `hatbin \
+                                                            rebuild-index`)",
+                                                           dir.display())));
+                }
+            }
+            Err(e) => return Err(e.into()),
+        }
+        Ok(PackBackend {
+            dir: dir,
+            max_pack_size: max_pack_size,
+            packs: Mutex::new(packs),
+            compaction: RwLock::new(()),
+        })
+    }
+
+    /// Recreate the index of the packs in `dir` from the packs themselves, e.g. after losing it,
+    /// and return the number of blobs found.
+    pub fn rebuild_index(dir: &Path) -> Result<usize, BackendError> {
+        let mut records = vec![];
+        for pack in pack_numbers(dir)? {
+            records.extend(scan_pack(dir, pack)?);
+        }
+        write_index(dir, &records)?;
+
+        let mut packs = Packs::default();
+        for record in records {
+            packs.apply(record);
+        }
+        Ok(packs.blobs.len())
+    }
+
+    /// Rewrite the packs without the blobs that were deleted or replaced since, and return the
+    /// number of bytes freed. The old packs are removed only after the new index is in place, and
+    /// once no blob is being read from them.
+    pub fn compact(&self) -> Result<u64, BackendError> {
+        let _compaction = self.compaction.write().unwrap();
+        let mut packs = self.packs.lock().unwrap();
+        let old = pack_numbers(&self.dir)?;
+        let mut old_size = 0;
+        for &pack in &old {
+            old_size += fs::metadata(pack_path(&self.dir, pack))?.len();
+        }
+
+        // Copy the live blobs in pack order, to read each pack front to back.
+        let mut live: Vec<(Vec<u8>, Location)> =
+            packs.blobs.iter().map(|(name, location)| (name.clone(), *location)).collect();
+        live.sort_by_key(|&(_, location)| (location.pack, location.offset));
+        let mut compacted = Packs::default();
+        let mut records = vec![];
+        for (name, location) in live {
+            let data = CipherText::new(self.read(&location)?);
+            let record = self.append(&mut compacted, &name, Some(&data))?;
+            compacted.apply(record.clone());
+            records.push(record);
+        }
+        compacted.writer = None;
+        compacted.index_len = write_index(&self.dir, &records)?;
+        *packs = compacted;
+
+        for &pack in &old {
+            fs::remove_file(pack_path(&self.dir, pack))?;
+        }
+        sync_dir(&self.dir)?;
+
+        let new_size = packs.ends.values().fold(0, |sum, end| sum + end);
+        Ok(old_size.saturating_sub(new_size))
+    }
+
+    /// Read and check the blob at `location`.
+    fn read(&self, location: &Location) -> Result<Vec<u8>, BackendError> {
+        let path = pack_path(&self.dir, location.pack);
+        let mut file = fs::File::open(&path)?;
+        file.seek(SeekFrom::Start(location.offset))?;
+        let mut data = vec![0; location.length as usize + sha256::DIGESTBYTES];
+        file.read_exact(&mut data)
+            .map_err(|e| BackendError::from_io(&format!("Could not read {}", path.display()), &e))?;
+        if !checksum_matches(&data) {
+            return Err(BackendError::Corruption(format!("Damaged blob in {} at offset {}",
+                                                        path.display(),
+                                                        location.offset)));
+        }
+        data.truncate(location.length as usize);
+        Ok(data)
+    }
+
+    /// Append an entry for `name` to the current pack of `packs`: the blob `data`, or a
+    /// tombstone without it. The entry is durable once this returns.
+    fn append(&self,
+              packs: &mut Packs,
+              name: &[u8],
+              data: Option<&CipherText>)
+              -> Result<Record, BackendError> {
+        let mut header = vec![];
+        header.extend_from_slice(if data.is_some() {
+            BLOB_MAGIC
+        } else {
+            TOMBSTONE_MAGIC
+        });
+        header.write_u16::<LittleEndian>(name.len() as u16).unwrap();
+        header.extend_from_slice(name);
+        if let Some(data) = data {
+            header.write_u64::<LittleEndian>(data.len() as u64).unwrap();
+        }
+        let size = header.len() as u64 +
+                   data.map_or(0, |d| d.len() as u64 + sha256::DIGESTBYTES as u64);
+
+        // Start a new pack when the entry does not fit, unless the pack is still empty.
+        let full = match packs.writer {
+            Some((pack, _)) => {
+                let end = packs.ends[&pack];
+                end > 0 && end + size > self.max_pack_size
+            }
+            None => false,
+        };
+        if packs.writer.is_none() || full {
+            let last = packs.ends.iter().next_back().map(|(&pack, &end)| (pack, end));
+            let (pack, end) = match last {
+                Some((pack, end)) if !full && (end == 0 || end + size <= self.max_pack_size) => {
+                    (pack, end)
+                }
+                // Packs on disk that the index does not know are left over from a crash.
+                _ => (pack_numbers(&self.dir)?.last().map_or(0, |&n| n + 1), 0),
+            };
+            packs.writer = Some((pack, open_at(&pack_path(&self.dir, pack), end)?));
+            packs.ends.insert(pack, end);
+        }
+
+        let pack = packs.writer.as_ref().unwrap().0;
+        let offset = packs.ends[&pack];
+        let written = {
+            let file = &mut packs.writer.as_mut().unwrap().1;
+            write_entry(file, &header, data)
+        };
+        if let Err(e) = written {
+            // Reopening cuts off whatever part of the entry was written.
+            packs.writer = None;
+            return Err(e);
+        }
+
+        let header_end = offset + header.len() as u64;
+        Ok(Record {
+            name: name.to_vec(),
+            location: Location {
+                pack: pack,
+                offset: header_end,
+                length: data.map_or(0, |d| d.len() as u64),
+            },
+            deleted: data.is_none(),
+        })
+    }
+
+    /// Append `name` to pack and index, and apply it once both are durable.
+    fn write(&self, name: &[u8], data: Option<&CipherText>) -> Result<(), BackendError> {
+        let mut packs = self.packs.lock().unwrap();
+        if data.is_none() && !packs.blobs.contains_key(name) {
+            return Err(BackendError::NotFound(format!("No blob named {} in {}",
+                                                      name.to_hex(),
+                                                      self.dir.display())));
+        }
+        let record = self.append(&mut packs, name, data)?;
+
+        if packs.index.is_none() {
+            let index = open_at(&self.dir.join(INDEX_FILE), packs.index_len)?;
+            packs.index = Some(index);
+        }
+        let bytes = record.encode();
+        let written = {
+            let index = packs.index.as_mut().unwrap();
+            match index.write_all(&bytes) {
+                Ok(()) => index.sync_data(),
+                Err(e) => Err(e),
+            }
+        };
+        if let Err(e) = written {
+            // The pack entry is cut off again when the pack is next reopened.
+            packs.index = None;
+            packs.writer = None;
+            return Err(e.into());
+        }
+        packs.index_len += bytes.len() as u64;
+        packs.apply(record);
+        Ok(())
+    }
+}
+
+impl StoreBackend for PackBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.write(name, Some(data))
+    }
+
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        // The new contents are appended; the index only points at them once they are durable.
+        self.write(name, Some(data))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        // Keep the pack of the location from being compacted away before it has been read.
+        let _compaction = self.compaction.read().unwrap();
+        let location = self.packs.lock().unwrap().blobs.get(name).cloned();
+        match location {
+            None => Ok(None),
+            Some(location) => self.read(&location).map(Some),
+        }
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.write(name, None)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        let packs = self.packs.lock().unwrap();
+        Ok(packs.blobs.keys().map(|name| name.clone().into_boxed_slice()).collect())
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        // Every entry is durable once it has been written.
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{BackendError, StoreBackend};
+    use crypto::CipherText;
+    use std::fs;
+    use std::io::Write;
+    use util::TempDir;
+
+    fn blob(n: u8, len: usize) -> CipherText {
+        CipherText::new(vec![n; len])
+    }
+
+    fn sorted(mut names: Vec<Box<[u8]>>) -> Vec<Box<[u8]>> {
+        names.sort();
+        names
+    }
+
+    fn names(names: &[&[u8]]) -> Vec<Box<[u8]>> {
+        names.iter().map(|n| n.to_vec().into_boxed_slice()).collect()
+    }
+
+    #[test]
+    fn store_delete_and_reopen() {
+        let dir = TempDir::new("hat-pack-backend");
+        let backend = PackBackend::new(dir.path().to_path_buf(), DEFAULT_MAX_PACK_SIZE).unwrap();
+        backend.store(b"a", &blob(1, 100)).unwrap();
+        backend.store(b"b", &blob(2, 200)).unwrap();
+        backend.store(b"c", &blob(3, 300)).unwrap();
+        backend.delete(b"b").unwrap();
+        match backend.delete(b"b") {
+            Err(BackendError::NotFound(..)) => (),
+            other => panic!("Expected a missing blob: {:?}", other),
+        }
+        // Storing again replaces the blob.
+        backend.store(b"c", &blob(4, 10)).unwrap();
+
+        assert_eq!(names(&[b"a", b"c"]), sorted(backend.list().unwrap()));
+        assert_eq!(Some(vec![1; 100]), backend.retrieve(b"a").unwrap());
+        assert_eq!(None, backend.retrieve(b"b").unwrap());
+        assert_eq!(Some(vec![4; 10]), backend.retrieve(b"c").unwrap());
+        // Everything went into a single pack.
+        assert_eq!(vec![0], pack_numbers(dir.path()).unwrap());
+
+        let reopened = PackBackend::new(dir.path().to_path_buf(), DEFAULT_MAX_PACK_SIZE).unwrap();
+        assert_eq!(names(&[b"a", b"c"]), sorted(reopened.list().unwrap()));
+        assert_eq!(Some(vec![4; 10]), reopened.retrieve(b"c").unwrap());
+
+    }
+
+    #[test]
+    fn full_packs_are_continued_in_new_ones() {
+        let dir = TempDir::new("hat-pack-backend");
+        let backend = PackBackend::new(dir.path().to_path_buf(), 1000).unwrap();
+        // Two blobs fit in a pack.
+        for n in 0..5 {
+            backend.store(&[n], &blob(n, 400)).unwrap();
+        }
+        assert_eq!(vec![0, 1, 2], pack_numbers(dir.path()).unwrap());
+        // Blobs larger than a pack get a pack of their own.
+        backend.store(b"big", &blob(9, 5000)).unwrap();
+        assert_eq!(Some(vec![9; 5000]), backend.retrieve(b"big").unwrap());
+    }
+
+    #[test]
+    fn compact_frees_deleted_blobs() {
+        let dir = TempDir::new("hat-pack-backend");
+        let backend = PackBackend::new(dir.path().to_path_buf(), 10000).unwrap();
+        for n in 0..10 {
+            backend.store(&[n], &blob(n, 1000)).unwrap();
+        }
+        for n in 0..8 {
+            backend.delete(&[n]).unwrap();
+        }
+        let freed = backend.compact().unwrap();
+        assert!(freed >= 8000, "Freed only {} bytes", freed);
+        assert_eq!(names(&[&[8], &[9]]), sorted(backend.list().unwrap()));
+        assert_eq!(Some(vec![9; 1000]), backend.retrieve(&[9]).unwrap());
+
+        // The compacted packs can be appended to and reopened.
+        backend.store(b"new", &blob(7, 10)).unwrap();
+        let reopened = PackBackend::new(dir.path().to_path_buf(), 10000).unwrap();
+        assert_eq!(names(&[&[8], &[9], b"new"]), sorted(reopened.list().unwrap()));
+        assert_eq!(Some(vec![8; 1000]), reopened.retrieve(&[8]).unwrap());
+    }
+
+    #[test]
+    fn rebuild_lost_index() {
+        let dir = TempDir::new("hat-pack-backend");
+        {
+            let backend = PackBackend::new(dir.path().to_path_buf(), 1000).unwrap();
+            for n in 0..4 {
+                backend.store(&[n], &blob(n, 300)).unwrap();
+            }
+            backend.delete(&[1]).unwrap();
+        }
+        fs::remove_file(dir.join(INDEX_FILE)).unwrap();
+        assert!(PackBackend::new(dir.path().to_path_buf(), 1000).is_err());
+
+        // A crash in the middle of a store leaves a partial entry at the end of the last pack.
+        let last = *pack_numbers(dir.path()).unwrap().last().unwrap();
+        fs::OpenOptions::new()
+            .append(true)
+            .open(pack_path(dir.path(), last))
+            .unwrap()
+            .write_all(b"HATB\x01\x00x\xff\xff")
+            .unwrap();
+
+        assert_eq!(3, PackBackend::rebuild_index(dir.path()).unwrap());
+        let backend = PackBackend::new(dir.path().to_path_buf(), 1000).unwrap();
+        assert_eq!(names(&[&[0], &[2], &[3]]), sorted(backend.list().unwrap()));
+        assert_eq!(Some(vec![3; 300]), backend.retrieve(&[3]).unwrap());
+
+        // Appending after the rebuild cuts the partial entry off.
+        backend.store(b"new", &blob(5, 10)).unwrap();
+        assert_eq!(4, PackBackend::rebuild_index(dir.path()).unwrap());
+    }
+
+    #[test]
+    fn rebuild_index_skips_damaged_entries() {
+        let dir = TempDir::new("hat-pack-backend");
+        {
+            let backend = PackBackend::new(dir.path().to_path_buf(), 10000).unwrap();
+            for n in 0..4 {
+                backend.store(&[n], &blob(n, 300)).unwrap();
+            }
+        }
+
+        // Damage the data of the second blob, and the header of the third one.
+        let entry_len = 4 + 2 + 1 + 8 + 300 + 32;
+        let mut pack = fs::OpenOptions::new().write(true).open(pack_path(dir.path(), 0)).unwrap();
+        pack.seek(SeekFrom::Start(entry_len + 100)).unwrap();
+        pack.write_all(&[0xff]).unwrap();
+        pack.seek(SeekFrom::Start(2 * entry_len + 8)).unwrap();
+        pack.write_all(&[0xff; 4]).unwrap();
+        drop(pack);
+
+        assert_eq!(2, PackBackend::rebuild_index(dir.path()).unwrap());
+        let backend = PackBackend::new(dir.path().to_path_buf(), 10000).unwrap();
+        assert_eq!(names(&[&[0], &[3]]), sorted(backend.list().unwrap()));
+        assert_eq!(Some(vec![3; 300]), backend.retrieve(&[3]).unwrap());
+    }
+
+    #[test]
+    fn damaged_blobs_are_reported() {
+        let dir = TempDir::new("hat-pack-backend");
+        let backend = PackBackend::new(dir.path().to_path_buf(), DEFAULT_MAX_PACK_SIZE).unwrap();
+        backend.store(b"a", &blob(1, 100)).unwrap();
+
+        let mut pack = fs::OpenOptions::new().write(true).open(pack_path(dir.path(), 0)).unwrap();
+        pack.seek(SeekFrom::Start(50)).unwrap();
+        pack.write_all(&[0]).unwrap();
+        match backend.retrieve(b"a") {
+            Err(BackendError::Corruption(..)) => (),
+            other => panic!("Expected a damaged blob: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,287 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A backend that retries the operations of another backend when they fail transiently.
+//!
+//! Retries back off exponentially, with random jitter so that clients that failed together do
+//! not retry together. An operation gives up when it runs out of retries, or when the next retry
+//! would start after its deadline.
+
+use backend::{BackendError, StoreBackend};
+use crypto::CipherText;
+use rand::{self, Rng};
+use rustc_serialize::hex::ToHex;
+use std::cmp;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt of an operation.
+    pub max_retries: u32,
+    /// Delay before the first retry; it doubles with every retry after that.
+    pub initial_delay: Duration,
+    pub max_delay: Duration,
+    /// Time after which an operation is not retried anymore, counted from its first attempt.
+    pub deadline: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 5,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+            deadline: Duration::from_secs(120),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// The delay before retry number `retry` (starting at 0): a random duration between half and
+    /// all of the exponential backoff.
+    fn delay(&self, retry: u32) -> Duration {
+        let mut backoff = self.initial_delay;
+        for _ in 0..retry {
+            if backoff >= self.max_delay {
+                break;
+            }
+            backoff = backoff * 2;
+        }
+        let millis = millis(cmp::min(backoff, self.max_delay));
+        Duration::from_millis(millis / 2 + rand::thread_rng().gen_range(0, millis / 2 + 1))
+    }
+}
+
+fn millis(d: Duration) -> u64 {
+    d.as_secs() * 1000 + (d.subsec_nanos() / 1_000_000) as u64
+}
+
+pub struct RetryBackend<B> {
+    backend: B,
+    policy: RetryPolicy,
+}
+
+impl<B: StoreBackend> RetryBackend<B> {
+    pub fn new(backend: B, policy: RetryPolicy) -> RetryBackend<B> {
+        RetryBackend {
+            backend: backend,
+            policy: policy,
+        }
+    }
+
+    /// Run `f` until it succeeds, fails permanently, or the policy says to stop. `f` gets the
+    /// number of the attempt, starting at 0.
+    fn retry<T, F>(&self, what: &str, name: Option<&[u8]>, f: F) -> Result<T, BackendError>
+        where F: Fn(u32) -> Result<T, BackendError>
+    {
+        let start = Instant::now();
+        let mut attempt = 0;
+        loop {
+            let e = match f(attempt) {
+                Err(ref e) if e.is_transient() && attempt < self.policy.max_retries => e.clone(),
+                result => return result,
+            };
+            let delay = self.policy.delay(attempt);
+            if start.elapsed() + delay > self.policy.deadline {
+                warn!("Giving up on {} after {} attempts: {}", what, attempt + 1, e);
+                return Err(e);
+            }
+            warn!("Retrying {}{} in {}ms: {}",
+                  what,
+                  name.map_or(String::new(), |n| format!(" of {}", n.to_hex())),
+                  millis(delay),
+                  e);
+            thread::sleep(delay);
+            attempt += 1;
+        }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for RetryBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.retry("store", Some(name), |_| self.backend.store(name, data))
+    }
+
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.retry("replace", Some(name), |_| self.backend.replace(name, data))
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.retry("retrieve", Some(name), |_| self.backend.retrieve(name))
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.retry("delete", Some(name), |attempt| {
+            match self.backend.delete(name) {
+                // An earlier attempt may have deleted the blob before it failed.
+                Err(BackendError::NotFound(..)) if attempt > 0 => Ok(()),
+                result => result,
+            }
+        })
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        self.retry("list", None, |_| self.backend.list())
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        self.backend.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{BackendError, MemoryBackend, StoreBackend};
+    use crypto::CipherText;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    /// A backend that fails with the queued errors before it does anything.
+    struct FlakyBackend {
+        inner: MemoryBackend,
+        errors: Mutex<Vec<BackendError>>,
+        calls: Mutex<usize>,
+    }
+
+    impl FlakyBackend {
+        fn new(errors: Vec<BackendError>) -> FlakyBackend {
+            FlakyBackend {
+                inner: MemoryBackend::new(),
+                errors: Mutex::new(errors),
+                calls: Mutex::new(0),
+            }
+        }
+
+        fn fail(&self) -> Result<(), BackendError> {
+            *self.calls.lock().unwrap() += 1;
+            let mut errors = self.errors.lock().unwrap();
+            if errors.is_empty() {
+                Ok(())
+            } else {
+                Err(errors.remove(0))
+            }
+        }
+    }
+
+    impl StoreBackend for FlakyBackend {
+        fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+            self.fail()?;
+            self.inner.store(name, data)
+        }
+        fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+            self.fail()?;
+            self.inner.replace(name, data)
+        }
+        fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+            self.fail()?;
+            self.inner.retrieve(name)
+        }
+        fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+            self.fail()?;
+            self.inner.delete(name)
+        }
+        fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+            self.fail()?;
+            self.inner.list()
+        }
+        fn flush(&self) -> Result<(), BackendError> {
+            self.inner.flush()
+        }
+    }
+
+    fn policy(max_retries: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_retries: max_retries,
+            initial_delay: Duration::from_millis(1),
+            max_delay: Duration::from_millis(4),
+            deadline: Duration::from_secs(10),
+        }
+    }
+
+    fn transient(n: usize) -> Vec<BackendError> {
+        (0..n).map(|_| BackendError::Transient("Connection reset".to_owned())).collect()
+    }
+
+    fn calls(backend: &RetryBackend<FlakyBackend>) -> usize {
+        *backend.backend.calls.lock().unwrap()
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let backend = RetryBackend::new(FlakyBackend::new(transient(3)), policy(5));
+        backend.store(&[1], &CipherText::new(vec![1, 2, 3])).unwrap();
+        assert_eq!(4, calls(&backend));
+        assert_eq!(Some(vec![1, 2, 3]), backend.retrieve(&[1]).unwrap());
+        assert_eq!(vec![vec![1].into_boxed_slice()], backend.list().unwrap());
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let backend = RetryBackend::new(FlakyBackend::new(transient(3)), policy(2));
+        assert!(backend.list().unwrap_err().is_transient());
+        assert_eq!(3, calls(&backend));
+        // The next operation starts over.
+        assert!(backend.list().unwrap().is_empty());
+    }
+
+    #[test]
+    fn permanent_errors_are_not_retried() {
+        let errors = vec![BackendError::Permission("Access denied".to_owned())];
+        let backend = RetryBackend::new(FlakyBackend::new(errors), policy(5));
+        match backend.retrieve(&[1]) {
+            Err(BackendError::Permission(..)) => (),
+            other => panic!("Expected a permission error: {:?}", other),
+        }
+        assert_eq!(1, calls(&backend));
+    }
+
+    #[test]
+    fn gives_up_at_deadline() {
+        let mut policy = policy(5);
+        policy.deadline = Duration::from_millis(0);
+        let backend = RetryBackend::new(FlakyBackend::new(transient(1)), policy);
+        assert!(backend.delete(&[1]).unwrap_err().is_transient());
+        assert_eq!(1, calls(&backend));
+    }
+
+    #[test]
+    fn retried_delete_of_deleted_blob_succeeds() {
+        let mut errors = transient(1);
+        errors.push(BackendError::NotFound("No such blob".to_owned()));
+        let backend = RetryBackend::new(FlakyBackend::new(errors), policy(5));
+        backend.delete(&[1]).unwrap();
+        assert_eq!(2, calls(&backend));
+    }
+
+    #[test]
+    fn delays_back_off_exponentially() {
+        let policy = RetryPolicy {
+            max_retries: 10,
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            deadline: Duration::from_secs(60),
+        };
+        for &(retry, backoff) in &[(0, 100), (1, 200), (2, 400), (3, 800), (4, 1000), (9, 1000)] {
+            for _ in 0..20 {
+                let delay = policy.delay(retry);
+                assert!(delay >= Duration::from_millis(backoff / 2), "{:?}", delay);
+                assert!(delay <= Duration::from_millis(backoff), "{:?}", delay);
+            }
+        }
+    }
+}
@@ -0,0 +1,367 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A backend that keeps blobs in a directory on a remote host over SFTP.
+//!
+//! The layout matches `FileBackend`: one file per blob, named by the blob name in hex. Blobs are
+//! written under a temporary name and renamed into place, so readers never see partial blobs.
+//!
+//! Servers speaking SFTP version 3 (such as OpenSSH) refuse to rename over an existing file. To
+//! replace a blob there, the old one is first moved aside to a name ending in `.old`, which is
+//! read instead of the blob until the new one is in place.
+
+use backend::{BackendError, StoreBackend};
+use crypto::CipherText;
+use rand::{self, Rng};
+use rustc_serialize::hex::{FromHex, ToHex};
+use ssh2::{self, CheckResult, KnownHostFileKind, Session, Sftp};
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+
+/// SFTP status codes.
+const FX_NO_SUCH_FILE: i32 = 2;
+const FX_PERMISSION_DENIED: i32 = 3;
+const FX_FAILURE: i32 = 4;
+const FX_NO_SPACE_ON_FILESYSTEM: i32 = 14;
+const FX_QUOTA_EXCEEDED: i32 = 15;
+
+/// Give up on a connection when the server does not answer for this long.
+const TIMEOUT_SECS: u64 = 60;
+
+/// Suffix of a blob that is being replaced.
+const OLD_SUFFIX: &'static str = ".old";
+
+fn sftp_error(context: String, e: ssh2::Error) -> BackendError {
+    let message = format!("{}: {}", context, e);
+    match e.code() {
+        FX_NO_SUCH_FILE => BackendError::NotFound(message),
+        FX_PERMISSION_DENIED => BackendError::Permission(message),
+        FX_NO_SPACE_ON_FILESYSTEM | FX_QUOTA_EXCEEDED => BackendError::Quota(message),
+        FX_FAILURE => BackendError::Other(message),
+        // Errors of the SSH session itself, such as a dropped connection.
+        _ => BackendError::Transient(message),
+    }
+}
+
+pub struct SftpBackend {
+    host: String,
+    port: u16,
+    user: String,
+    /// Private key to log in with; without one, we ask the SSH agent.
+    identity: Option<PathBuf>,
+    known_hosts: PathBuf,
+    dir: PathBuf,
+    connection: Mutex<Option<Connection>>,
+}
+
+struct Connection {
+    session: Session,
+    // The session talks over this socket, so it is dropped after the session.
+    _socket: TcpStream,
+}
+
+impl SftpBackend {
+    pub fn new(host: String,
+               port: u16,
+               user: String,
+               identity: Option<PathBuf>,
+               known_hosts: PathBuf,
+               dir: PathBuf)
+               -> SftpBackend {
+        SftpBackend {
+            host: host,
+            port: port,
+            user: user,
+            identity: identity,
+            known_hosts: known_hosts,
+            dir: dir,
+            connection: Mutex::new(None),
+        }
+    }
+
+    fn connect(&self) -> Result<Connection, BackendError> {
+        let socket = TcpStream::connect((&self.host[..], self.port)).map_err(|e| {
+                BackendError::from_io(&format!("Could not connect to {}:{}", self.host, self.port),
+                                      &e)
+            })?;
+        let timeout = Some(Duration::from_secs(TIMEOUT_SECS));
+        socket.set_read_timeout(timeout)
+            .and_then(|()| socket.set_write_timeout(timeout))
+            .map_err(|e| BackendError::from_io("Could not set socket timeout", &e))?;
+        let mut session = Session::new().ok_or("Could not create SSH session")?;
+        session.set_timeout((TIMEOUT_SECS * 1000) as u32);
+        session.handshake(&socket)
+            .map_err(|e| {
+                BackendError::Transient(format!("SSH connection to {} failed: {}", self.host, e))
+            })?;
+        self.check_host_key(&session)?;
+
+        let login = match self.identity {
+            Some(ref key) => session.userauth_pubkey_file(&self.user, None, key, None),
+            None => session.userauth_agent(&self.user),
+        };
+        if login.is_err() || !session.authenticated() {
+            return Err(BackendError::Permission(format!("Could not log in to {} as {}",
+                                                        self.host,
+                                                        self.user)));
+        }
+
+        Ok(Connection {
+            session: session,
+            _socket: socket,
+        })
+    }
+
+    fn check_host_key(&self, session: &Session) -> Result<(), BackendError> {
+        let mut known_hosts = session.known_hosts().map_err(|e| e.to_string())?;
+        known_hosts.read_file(&self.known_hosts, KnownHostFileKind::OpenSSH)
+            .map_err(|e| format!("Could not read {}: {}", self.known_hosts.display(), e))?;
+
+        let key = match session.host_key() {
+            Some((key, _)) => key,
+            None => return Err(format!("{} sent no host key", self.host).into()),
+        };
+        let problem = match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => return Ok(()),
+            CheckResult::Mismatch => "does not match the one",
+            _ => "is not",
+        };
+        Err(BackendError::Permission(format!("Host key of {} {} in {}",
+                                             self.host,
+                                             problem,
+                                             self.known_hosts.display())))
+    }
+
+    /// Run `f` on the shared connection, connecting first if needed. An SFTP channel borrows its
+    /// session, so each call opens a channel of its own. Transient errors drop the connection, and
+    /// the next call starts over with a fresh one.
+    fn with_sftp<T, F>(&self, f: F) -> Result<T, BackendError>
+        where F: FnOnce(&Sftp) -> Result<T, BackendError>
+    {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            *connection = Some(self.connect()?);
+        }
+
+        let result = match connection.as_ref().unwrap().session.sftp() {
+            Ok(sftp) => f(&sftp),
+            Err(e) => Err(sftp_error(format!("Could not start SFTP on {}", self.host), e)),
+        };
+        if result.as_ref().err().map_or(false, |e| e.is_transient()) {
+            *connection = None;
+        }
+        result
+    }
+
+    fn path(&self, name: &[u8]) -> PathBuf {
+        self.dir.join(name.to_hex())
+    }
+
+    fn old_path(&self, name: &[u8]) -> PathBuf {
+        self.dir.join(format!("{}{}", name.to_hex(), OLD_SUFFIX))
+    }
+
+    /// Rename the fully written `tmp` to the blob `name`, replacing any blob of that name.
+    fn move_into_place(&self, sftp: &Sftp, tmp: &Path, name: &[u8]) -> Result<(), BackendError> {
+        let path = self.path(name);
+        let rename_error = |from: &Path, to: &Path, e| {
+            sftp_error(format!("Could not rename {} to {}", from.display(), to.display()), e)
+        };
+
+        // Without flags, the rename asks to overwrite atomically, which newer servers honour.
+        let e = match sftp.rename(tmp, &path, None) {
+            Ok(()) => return Ok(()),
+            Err(e) => e,
+        };
+        if sftp.stat(&path).is_err() {
+            // The rename failed for another reason than an existing blob.
+            let _ = sftp.unlink(tmp);
+            return Err(rename_error(tmp, &path, e));
+        }
+
+        // Move the existing blob aside; reads fall back to it until the new one is in place. An
+        // older copy left by an interrupted replace is outdated by the blob in `path`.
+        let old = self.old_path(name);
+        let _ = sftp.unlink(&old);
+        if let Err(e) = sftp.rename(&path, &old, None) {
+            let _ = sftp.unlink(tmp);
+            return Err(rename_error(&path, &old, e));
+        }
+        if let Err(e) = sftp.rename(tmp, &path, None) {
+            let _ = sftp.rename(&old, &path, None);
+            let _ = sftp.unlink(tmp);
+            return Err(rename_error(tmp, &path, e));
+        }
+        let _ = sftp.unlink(&old);
+        Ok(())
+    }
+}
+
+/// The blob stored in `path`, unless it is not a blob (e.g. a partially written one). Blobs that
+/// were moved aside while being replaced still count.
+fn blob_name(path: &Path) -> Option<Vec<u8>> {
+    path.file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.trim_right_matches(OLD_SUFFIX))
+        .and_then(|n| n.from_hex().ok())
+}
+
+fn read_file(sftp: &Sftp, path: &Path) -> Result<Option<Vec<u8>>, BackendError> {
+    match sftp.open(path) {
+        Err(ref e) if e.code() == FX_NO_SUCH_FILE => Ok(None),
+        Err(e) => Err(sftp_error(format!("Could not open {}", path.display()), e)),
+        Ok(mut file) => {
+            let mut buf = vec![];
+            file.read_to_end(&mut buf)
+                .map_err(|e| {
+                    BackendError::from_io(&format!("Could not read {}", path.display()), &e)
+                })?;
+            Ok(Some(buf))
+        }
+    }
+}
+
+impl StoreBackend for SftpBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let tmp = self.dir.join(format!("{}.tmp-{}", name.to_hex(), rand::thread_rng().next_u64()));
+
+        self.with_sftp(|sftp| {
+            let mut file = sftp.create(&tmp)
+                .map_err(|e| sftp_error(format!("Could not create {}", tmp.display()), e))?;
+            for slice in data.slices() {
+                if let Err(e) = file.write_all(slice) {
+                    let _ = sftp.unlink(&tmp);
+                    return Err(BackendError::from_io(&format!("Could not write {}", tmp.display()),
+                                                     &e));
+                }
+            }
+            drop(file);
+
+            self.move_into_place(sftp, &tmp, name)
+        })
+    }
+
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.store(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.with_sftp(|sftp| {
+            match read_file(sftp, &self.path(name))? {
+                Some(blob) => Ok(Some(blob)),
+                // The blob may have been moved aside by an interrupted replace.
+                None => read_file(sftp, &self.old_path(name)),
+            }
+        })
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        let path = self.path(name);
+        self.with_sftp(|sftp| {
+            let deleted_old = sftp.unlink(&self.old_path(name)).is_ok();
+            match sftp.unlink(&path) {
+                Err(ref e) if e.code() == FX_NO_SUCH_FILE && deleted_old => Ok(()),
+                res => {
+                    res.map_err(|e| sftp_error(format!("Could not delete {}", path.display()), e))
+                }
+            }
+        })
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        self.with_sftp(|sftp| {
+            let entries = sftp.readdir(&self.dir)
+                .map_err(|e| sftp_error(format!("Could not list {}", self.dir.display()), e))?;
+            let mut names: Vec<_> =
+                entries.iter().filter_map(|&(ref path, _)| blob_name(path)).collect();
+            // A blob being replaced may be there twice.
+            names.sort();
+            names.dedup();
+            Ok(names.into_iter().map(|name| name.into_boxed_slice()).collect())
+        })
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{BackendError, StoreBackend};
+    use crypto::CipherText;
+    use rand::{self, Rng};
+    use std::env;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn blob_names() {
+        assert_eq!(Some(vec![1, 255]), blob_name(Path::new("/srv/blobs/01ff")));
+        assert_eq!(Some(vec![1, 255]), blob_name(Path::new("/srv/blobs/01ff.old")));
+        assert_eq!(None, blob_name(Path::new("/srv/blobs/01ff.tmp-1234")));
+        assert_eq!(None, blob_name(Path::new("/srv/blobs/README")));
+    }
+
+    /// Runs against the SSH server at `HAT_TEST_SFTP_HOST` (e.g. a local sshd), logging in as
+    /// `USER` with the key in `HAT_TEST_SFTP_IDENTITY` (or the SSH agent) and storing blobs in
+    /// a fresh directory under `HAT_TEST_SFTP_DIR`. Skipped when the host is not set.
+    #[test]
+    fn sftp_roundtrip() {
+        let host = match env::var("HAT_TEST_SFTP_HOST") {
+            Ok(host) => host,
+            Err(_) => return,
+        };
+        let var = |name: &str| env::var(name).expect(name);
+        let dir = Path::new(&var("HAT_TEST_SFTP_DIR"))
+            .join(format!("hat-test-{}", rand::thread_rng().next_u64()));
+        ::std::fs::create_dir_all(&dir).unwrap();
+
+        let backend = SftpBackend::new(host,
+                                       22,
+                                       var("USER"),
+                                       env::var("HAT_TEST_SFTP_IDENTITY").ok().map(PathBuf::from),
+                                       Path::new(&var("HOME")).join(".ssh/known_hosts"),
+                                       dir.clone());
+
+        let name = vec![1, 2, 255];
+        assert_eq!(None, backend.retrieve(&name).unwrap());
+        backend.store(&name, &CipherText::new(b"hello".to_vec())).unwrap();
+        assert_eq!(Some(b"hello".to_vec()), backend.retrieve(&name).unwrap());
+
+        // Stores replace existing blobs.
+        backend.store(&name, &CipherText::new(b"world".to_vec())).unwrap();
+        assert_eq!(Some(b"world".to_vec()), backend.retrieve(&name).unwrap());
+        assert_eq!(vec![name.clone().into_boxed_slice()], backend.list().unwrap());
+
+        backend.delete(&name).unwrap();
+        assert_eq!(None, backend.retrieve(&name).unwrap());
+        assert!(backend.list().unwrap().is_empty());
+        match backend.delete(&name) {
+            Err(BackendError::NotFound(..)) => (),
+            other => panic!("Expected a missing blob: {:?}", other),
+        }
+
+        // The connection is still good after the failed delete.
+        backend.store(&name, &CipherText::new(b"again".to_vec())).unwrap();
+        backend.delete(&name).unwrap();
+
+        ::std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,298 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! A backend that limits the bandwidth and request rate used on another backend.
+//!
+//! Each limit is a token bucket that fills up at the allowed rate and holds at most one second
+//! worth of tokens, so short bursts go through at full speed. An operation takes its tokens
+//! up front and, if the bucket runs short, waits until the tokens it borrowed have been earned.
+//! Blobs larger than a bucket therefore still pass, just slower.
+
+use backend::{BackendError, StoreBackend};
+use crypto::CipherText;
+use std::cmp;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ThrottleLimits {
+    /// Bytes per second sent to the backend.
+    pub upload: Option<u64>,
+    /// Bytes per second read from the backend.
+    pub download: Option<u64>,
+    /// Requests per second of any kind.
+    pub requests: Option<f64>,
+}
+
+/// Parse a byte rate such as "5MiB/s", "800KB/s" or "1000000". Units are decimal (KB, MB, GB)
+/// or binary (KiB, MiB, GiB), and "/s" is optional.
+pub fn parse_byte_rate(text: &str) -> Result<u64, String> {
+    let invalid = || format!("Invalid byte rate: {}", text);
+    let amount = text.trim();
+    let amount = if amount.ends_with("/s") {
+        &amount[..amount.len() - 2]
+    } else {
+        amount
+    };
+    let digits = amount.find(|c: char| !c.is_digit(10) && c != '.').unwrap_or(amount.len());
+    let (number, unit) = amount.split_at(digits);
+    let number: f64 = number.parse().map_err(|_| invalid())?;
+    let multiplier = match unit.trim() {
+        "" | "B" => 1,
+        "K" | "KB" => 1000,
+        "KiB" => 1 << 10,
+        "M" | "MB" => 1000 * 1000,
+        "MiB" => 1 << 20,
+        "G" | "GB" => 1000 * 1000 * 1000,
+        "GiB" => 1 << 30,
+        _ => return Err(invalid()),
+    };
+    let rate = (number * multiplier as f64) as u64;
+    if rate == 0 {
+        return Err(invalid());
+    }
+    Ok(rate)
+}
+
+/// Parse a request rate such as "10/s" or "0.5".
+pub fn parse_request_rate(text: &str) -> Result<f64, String> {
+    let rate = text.trim();
+    let rate = if rate.ends_with("/s") {
+        &rate[..rate.len() - 2]
+    } else {
+        rate
+    };
+    match rate.parse::<f64>() {
+        Ok(rate) if rate > 0.0 => Ok(rate),
+        _ => Err(format!("Invalid request rate: {}", text)),
+    }
+}
+
+struct TokenBucket {
+    /// Tokens earned per second, and the most the bucket holds.
+    rate: f64,
+    /// Negative when operations have borrowed tokens that are still to be earned.
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate: f64, now: Instant) -> TokenBucket {
+        TokenBucket {
+            rate: rate,
+            tokens: rate,
+            last: now,
+        }
+    }
+
+    /// Take `amount` tokens, and return how long to wait before using them.
+    fn take(&mut self, amount: f64, now: Instant) -> Duration {
+        let elapsed = now.duration_since(self.last);
+        let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 * 1e-9;
+        self.last = now;
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.rate) - amount;
+        if self.tokens >= 0.0 {
+            return Duration::from_secs(0);
+        }
+        let wait = -self.tokens / self.rate;
+        Duration::new(wait as u64, (wait.fract() * 1e9) as u32)
+    }
+}
+
+/// What an operation sends or reads, which decides the bandwidth limit it waits for.
+#[derive(Clone, Copy, Debug)]
+enum Transfer {
+    /// Sends a blob of this many bytes.
+    Upload(usize),
+    /// Reads a blob of a size that is only known afterwards.
+    Download,
+    /// Moves no blob data.
+    Request,
+}
+
+struct Buckets {
+    upload: Option<TokenBucket>,
+    download: Option<TokenBucket>,
+    requests: Option<TokenBucket>,
+}
+
+pub struct ThrottledBackend<B> {
+    backend: B,
+    buckets: Mutex<Buckets>,
+}
+
+impl<B: StoreBackend> ThrottledBackend<B> {
+    pub fn new(backend: B, limits: &ThrottleLimits) -> ThrottledBackend<B> {
+        let now = Instant::now();
+        let bucket = |rate: Option<f64>| rate.map(|r| TokenBucket::new(r, now));
+        ThrottledBackend {
+            backend: backend,
+            buckets: Mutex::new(Buckets {
+                upload: bucket(limits.upload.map(|r| r as f64)),
+                download: bucket(limits.download.map(|r| r as f64)),
+                requests: bucket(limits.requests),
+            }),
+        }
+    }
+
+    /// Wait for a request slot, and for the bandwidth in the direction of `transfer`.
+    fn throttle(&self, transfer: Transfer) {
+        let wait = {
+            let mut buckets = self.buckets.lock().unwrap();
+            let now = Instant::now();
+            let mut wait = Duration::from_secs(0);
+            if let Some(ref mut bucket) = buckets.requests {
+                wait = cmp::max(wait, bucket.take(1.0, now));
+            }
+            match transfer {
+                Transfer::Upload(bytes) => {
+                    if let Some(ref mut bucket) = buckets.upload {
+                        wait = cmp::max(wait, bucket.take(bytes as f64, now));
+                    }
+                }
+                // Downloads are charged once their size is known; those that went over the
+                // limit hold up the next download.
+                Transfer::Download => {
+                    if let Some(ref mut bucket) = buckets.download {
+                        wait = cmp::max(wait, bucket.take(0.0, now));
+                    }
+                }
+                Transfer::Request => (),
+            }
+            wait
+        };
+        if wait > Duration::from_secs(0) {
+            thread::sleep(wait);
+        }
+    }
+}
+
+impl<B: StoreBackend> StoreBackend for ThrottledBackend<B> {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.throttle(Transfer::Upload(data.len()));
+        self.backend.store(name, data)
+    }
+
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.throttle(Transfer::Upload(data.len()));
+        self.backend.replace(name, data)
+    }
+
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.throttle(Transfer::Download);
+        let blob = self.backend.retrieve(name)?;
+        if let Some(ref blob) = blob {
+            let mut buckets = self.buckets.lock().unwrap();
+            if let Some(ref mut bucket) = buckets.download {
+                bucket.take(blob.len() as f64, Instant::now());
+            }
+        }
+        Ok(blob)
+    }
+
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.throttle(Transfer::Request);
+        self.backend.delete(name)
+    }
+
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        self.throttle(Transfer::Request);
+        self.backend.list()
+    }
+
+    fn flush(&self) -> Result<(), BackendError> {
+        self.backend.flush()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use backend::{FileBackend, StoreBackend};
+    use crypto::CipherText;
+    use util::TempDir;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn byte_rates() {
+        assert_eq!(Ok(5 * 1024 * 1024), parse_byte_rate("5MiB/s"));
+        assert_eq!(Ok(800000), parse_byte_rate("800KB/s"));
+        assert_eq!(Ok(1500), parse_byte_rate("1.5K"));
+        assert_eq!(Ok(1000000), parse_byte_rate("1000000"));
+        assert!(parse_byte_rate("5 parsecs").is_err());
+        assert!(parse_byte_rate("0MB/s").is_err());
+        assert!(parse_byte_rate("fast").is_err());
+
+        assert_eq!(Ok(10.0), parse_request_rate("10/s"));
+        assert_eq!(Ok(0.5), parse_request_rate("0.5"));
+        assert!(parse_request_rate("0").is_err());
+    }
+
+    #[test]
+    fn token_bucket_lends_and_refills() {
+        let start = Instant::now();
+        let mut bucket = TokenBucket::new(100.0, start);
+        // A full bucket lets a burst through.
+        assert_eq!(Duration::from_secs(0), bucket.take(100.0, start));
+        // Going over waits for the missing tokens.
+        assert_eq!(Duration::from_millis(500), bucket.take(50.0, start));
+        // The debt is paid off after half a second, and then the bucket fills up again.
+        assert_eq!(Duration::from_secs(0), bucket.take(0.0, start + Duration::from_millis(500)));
+        assert_eq!(Duration::from_secs(0), bucket.take(100.0, start + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn throttles_file_backend() {
+        let dir = TempDir::new("hat-throttle");
+        let limits = ThrottleLimits {
+            upload: Some(100000),
+            download: None,
+            requests: Some(1000.0),
+        };
+        let backend = ThrottledBackend::new(FileBackend::new(dir.path().to_path_buf()), &limits);
+
+        // The first 100000 bytes use up the bucket; the next 100000 take a second.
+        let start = Instant::now();
+        for i in 0..4 {
+            backend.store(&[i], &CipherText::new(vec![i; 50000])).unwrap();
+        }
+        assert!(start.elapsed() >= Duration::from_millis(900));
+        assert_eq!(Some(vec![3; 50000]), backend.retrieve(&[3]).unwrap());
+    }
+
+    #[test]
+    fn directions_are_throttled_separately() {
+        let dir = TempDir::new("hat-throttle");
+        let limits = ThrottleLimits {
+            upload: None,
+            download: Some(100000),
+            requests: None,
+        };
+        let backend = ThrottledBackend::new(FileBackend::new(dir.path().to_path_buf()), &limits);
+        backend.store(&[1], &CipherText::new(vec![1; 200000])).unwrap();
+
+        // Downloading twice the bucket leaves a second of debt, which only holds up downloads.
+        let start = Instant::now();
+        backend.retrieve(&[1]).unwrap();
+        backend.store(&[2], &CipherText::new(vec![2; 200000])).unwrap();
+        backend.delete(&[2]).unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+        backend.retrieve(&[1]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+}
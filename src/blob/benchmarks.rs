@@ -14,9 +14,11 @@
 
 
 use blob::{Blob, ChunkRef, NodeType, LeafType};
+use crypto::keys::Keeper;
 use hash::Hash;
 use hash::tree::HashRef;
 
+use std::sync::Arc;
 use test::Bencher;
 
 
@@ -44,12 +46,13 @@ fn dummy_hashref() -> HashRef {
 
 #[bench]
 fn insert_128_kb_chunks(bench: &mut Bencher) {
-    let mut b = Blob::new(BLOBSIZE);
+    let keys = Arc::new(Keeper::generate());
+    let mut b = Blob::new(BLOBSIZE, keys.clone());
     let mut href = dummy_hashref();
     let chunk = [0u8; CHUNKSIZE];
     bench.iter(|| {
         if let Err(()) = b.try_append(&chunk[..], &mut href) {
-            b = Blob::new(BLOBSIZE);
+            b = Blob::new(BLOBSIZE, keys.clone());
             b.try_append(&chunk[..], &mut href).unwrap();
         }
     });
@@ -59,7 +62,7 @@ fn insert_128_kb_chunks(bench: &mut Bencher) {
 #[bench]
 fn insert_256_kb_chunks(bench: &mut Bencher) {
     let chunk = vec![0u8; 2 * CHUNKSIZE];
-    let mut b = Blob::new(BLOBSIZE);
+    let mut b = Blob::new(BLOBSIZE, Arc::new(Keeper::generate()));
     let mut href = dummy_hashref();
     bench.iter(|| {
         if let Err(()) = b.try_append(&chunk[..], &mut href) {
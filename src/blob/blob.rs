@@ -17,50 +17,60 @@ use crypto::{CipherText, CipherTextRef, PlainTextRef};
 use hash::Hash;
 use hash::tree::HashRef;
 
+use std::borrow::Cow;
 use std::mem;
+use std::sync::Arc;
 
 use super::BlobError;
 use super::ChunkRef;
+use super::{LeafType, Packing};
 
 
 pub struct Blob {
-    master_key: crypto::FixedKey,
+    keys: Arc<crypto::keys::Keeper>,
     chunks: CipherText,
     footer: Vec<u8>,
     overhead: usize,
     max_len: usize,
+    dictionary: Option<(i64, Arc<Vec<u8>>)>,
 }
 
 impl Blob {
-    pub fn new(max_len: usize) -> Blob {
-        // TODO(jos): Plug an actual crypto key through somehow.
-        let pubkey = crypto::sealed::desc::PublicKey::from_slice(&[215, 136, 80, 128, 158, 109,
-                                                                   227, 141, 219, 63, 118, 91,
-                                                                   123, 97, 1, 97, 65, 237, 62,
-                                                                   171, 83, 159, 200, 11, 68,
-                                                                   138, 40, 82, 24, 47, 187, 29])
-            .unwrap();
-        let seckey = crypto::sealed::desc::SecretKey::from_slice(&[94, 13, 181, 81, 97, 87, 76,
-                                                                   37, 53, 92, 120, 232, 17, 126,
-                                                                   234, 78, 12, 23, 141, 61, 40,
-                                                                   10, 136, 127, 103, 192, 255,
-                                                                   193, 142, 154, 101, 35])
-            .unwrap();
-
-        let master_key = crypto::FixedKey::new(pubkey, Some(seckey));
-
+    pub fn new(max_len: usize, keys: Arc<crypto::keys::Keeper>) -> Blob {
         Blob {
-            master_key: master_key,
+            keys: keys,
             chunks: CipherText::empty(),
             footer: Vec::with_capacity(max_len / 2),
             overhead: crypto::sealed::desc::overhead() + crypto::authed::hash::DIGESTBYTES,
             max_len: max_len,
+            dictionary: None,
         }
     }
 
+    /// Use this compression dictionary for tree listings appended from now on.
+    pub fn set_dictionary(&mut self, id: i64, dictionary: Arc<Vec<u8>>) {
+        self.dictionary = Some((id, dictionary));
+    }
+
     pub fn read_chunk(blob: &[u8], hash: &Hash, cref: &ChunkRef) -> Result<Vec<u8>, BlobError> {
+        Blob::read_chunk_with_dictionary(blob, hash, cref, None)
+    }
+
+    /// Like `read_chunk`, for chunks that may have been packed with a compression dictionary.
+    pub fn read_chunk_with_dictionary(blob: &[u8],
+                                      hash: &Hash,
+                                      cref: &ChunkRef,
+                                      dictionary: Option<&[u8]>)
+                                      -> Result<Vec<u8>, BlobError> {
         let ct = crypto::CipherTextRef::new(blob);
-        Ok(crypto::RefKey::unseal(hash, cref, ct.strip_authentication()?)?.into_vec())
+        let pt = crypto::RefKey::unseal(hash, cref, ct.strip_authentication()?)?.into_vec();
+        match cref.packing {
+            None => Ok(pt),
+            Some(ref packing) => {
+                packing.decompress(&pt[..], dictionary)
+                    .map_err(|e| From::from(format!("Could not unpack chunk: {}", e)))
+            }
+        }
     }
 
     pub fn upperbound_len(&self) -> usize {
@@ -71,8 +81,28 @@ impl Blob {
         }
     }
 
+    /// Compress the chunk if that makes it smaller; already-compressed data is kept raw.
+    fn pack<'a>(&self, chunk: &'a [u8], leaf: LeafType) -> (Option<Packing>, Cow<'a, [u8]>) {
+        let (packing, dictionary) = match self.dictionary {
+            Some((id, ref dict)) => (Packing::for_leaf(leaf, Some(id)), Some(&dict[..])),
+            None => (Packing::for_leaf(leaf, None), None),
+        };
+        match packing.compress(chunk, dictionary) {
+            Ok(packed) => {
+                if packed.len() < chunk.len() {
+                    (Some(packing), Cow::Owned(packed))
+                } else {
+                    (None, Cow::Borrowed(chunk))
+                }
+            }
+            Err(_) => (None, Cow::Borrowed(chunk)),
+        }
+    }
+
     pub fn try_append(&mut self, chunk: &[u8], mut href: &mut HashRef) -> Result<(), ()> {
-        let ct = crypto::RefKey::seal(&mut href, PlainTextRef::new(chunk));
+        let (packing, packed) = self.pack(chunk, href.leaf);
+        href.persistent_ref.packing = packing;
+        let ct = crypto::RefKey::seal(&mut href, PlainTextRef::new(&packed[..]));
 
         href.persistent_ref.offset = self.chunks.len();
         let mut href_bytes = href.as_bytes();
@@ -103,7 +133,7 @@ impl Blob {
         }
 
         let footer_overhead = self.footer.len() + self.overhead;
-        let footer = self.master_key.seal(PlainTextRef::new(&self.footer[..]));
+        let footer = self.keys.seal(PlainTextRef::new(&self.footer[..]));
         self.footer.truncate(0);
 
         assert!(self.chunks.len() + footer_overhead <= self.max_len);
@@ -122,9 +152,23 @@ impl Blob {
         Some(out)
     }
 
+    /// Seal the footer of an existing blob with the current master key.
+    /// The resealed blob has the same length, and the chunks it contains are left untouched.
+    pub fn reseal(&self, bytes: &[u8]) -> Result<CipherText, BlobError> {
+        let ct = CipherTextRef::new(bytes);
+        let (rest, footer) = self.keys.unseal(ct.strip_authentication()?)?;
+
+        let mut out = CipherText::new(rest.as_bytes().to_vec());
+        out.append(self.keys.seal(footer.as_ref()));
+        out.append_authentication();
+
+        assert_eq!(out.len(), bytes.len());
+        Ok(out)
+    }
+
     pub fn refs_from_bytes(&self, bytes: &[u8]) -> Result<Vec<HashRef>, BlobError> {
         let ct = CipherTextRef::new(bytes);
-        let (_rest, footer_vec) = self.master_key.unseal(ct.strip_authentication()?)?;
+        let (_rest, footer_vec) = self.keys.unseal(ct.strip_authentication()?)?;
         let mut footer_pos = footer_vec.as_bytes();
 
         let mut hrefs = Vec::new();
@@ -13,14 +13,104 @@
 // limitations under the License.
 
 use capnp;
+use flate2;
 use root_capnp;
+use snap;
 use sodiumoxide::crypto::secretbox::xsalsa20poly1305;
+use zstd;
 
+use std::io::{self, Read, Write};
+
+
+/// Compression level used for zstd; tree listings are small, so we can afford a high level.
+const ZSTD_LEVEL: i32 = 9;
 
 #[derive(Debug, Clone, Eq, PartialEq)]
 pub enum Packing {
     GZip,
     Snappy,
+    /// Zstandard, optionally with the id of the compression dictionary it was packed with.
+    Zstd(Option<i64>),
+}
+
+impl Packing {
+    /// Tree listings are small and highly repetitive, so they are worth the slower gzip, or zstd
+    /// when the repository has a dictionary trained for them.
+    /// File contents are packed with snappy to keep backup and restore fast.
+    pub fn for_leaf(leaf: LeafType, dictionary: Option<i64>) -> Packing {
+        match (leaf, dictionary) {
+            (LeafType::TreeList, Some(id)) => Packing::Zstd(Some(id)),
+            (LeafType::TreeList, None) => Packing::GZip,
+            (LeafType::FileChunk, _) => Packing::Snappy,
+        }
+    }
+
+    /// The compression dictionary needed to compress or decompress with this packing.
+    pub fn dictionary(&self) -> Option<i64> {
+        match *self {
+            Packing::Zstd(id) => id,
+            _ => None,
+        }
+    }
+
+    pub fn compress(&self, data: &[u8], dictionary: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        match *self {
+            Packing::GZip => {
+                let mut enc = flate2::write::GzEncoder::new(Vec::with_capacity(data.len()),
+                                                            flate2::Compression::Default);
+                enc.write_all(data)?;
+                enc.finish()
+            }
+            Packing::Snappy => {
+                snap::Encoder::new()
+                    .compress_vec(data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+            }
+            Packing::Zstd(id) => {
+                let out = Vec::with_capacity(data.len());
+                let mut enc = match (id, dictionary) {
+                    (None, _) => zstd::stream::Encoder::new(out, ZSTD_LEVEL)?,
+                    (Some(_), Some(dict)) => {
+                        zstd::stream::Encoder::with_dictionary(out, ZSTD_LEVEL, dict)?
+                    }
+                    (Some(id), None) => return Err(missing_dictionary(id)),
+                };
+                enc.write_all(data)?;
+                enc.finish()
+            }
+        }
+    }
+
+    pub fn decompress(&self, data: &[u8], dictionary: Option<&[u8]>) -> io::Result<Vec<u8>> {
+        match *self {
+            Packing::GZip => {
+                let mut out = Vec::with_capacity(data.len() * 2);
+                flate2::read::GzDecoder::new(data)?.read_to_end(&mut out)?;
+                Ok(out)
+            }
+            Packing::Snappy => {
+                snap::Decoder::new()
+                    .decompress_vec(data)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }
+            Packing::Zstd(id) => {
+                let mut out = Vec::with_capacity(data.len() * 4);
+                match (id, dictionary) {
+                    (None, _) => zstd::stream::Decoder::new(data)?.read_to_end(&mut out)?,
+                    (Some(_), Some(dict)) => {
+                        zstd::stream::Decoder::with_dictionary(data, dict)?.read_to_end(&mut out)?
+                    }
+                    (Some(id), None) => return Err(missing_dictionary(id)),
+                };
+                Ok(out)
+            }
+        }
+    }
+}
+
+fn missing_dictionary(id: i64) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound,
+                   format!("compression dictionary {} is not available", id))
 }
 
 #[derive(Debug, Clone, Eq, PartialEq)]
@@ -142,6 +232,7 @@ impl ChunkRef {
             None => msg.borrow().init_packing().set_none(()),
             Some(Packing::GZip) => msg.borrow().init_packing().set_gzip(()),
             Some(Packing::Snappy) => msg.borrow().init_packing().set_snappy(()),
+            Some(Packing::Zstd(id)) => msg.borrow().init_packing().set_zstd(id.unwrap_or(0)),
         }
     }
 
@@ -155,6 +246,8 @@ impl ChunkRef {
                 root_capnp::chunk_ref::packing::None(()) => None,
                 root_capnp::chunk_ref::packing::Gzip(()) => Some(Packing::GZip),
                 root_capnp::chunk_ref::packing::Snappy(()) => Some(Packing::Snappy),
+                root_capnp::chunk_ref::packing::Zstd(0) => Some(Packing::Zstd(None)),
+                root_capnp::chunk_ref::packing::Zstd(id) => Some(Packing::Zstd(Some(id))),
             },
             key: match msg.get_key().which()? {
                 root_capnp::chunk_ref::key::None(()) => None,
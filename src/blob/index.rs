@@ -18,13 +18,30 @@
 use crypto;
 use db;
 
-use errors::DieselError;
+use errors::{CryptoError, DieselError};
 
+use std::str;
 use std::sync::{Arc, Mutex};
 
 use tags;
 
 
+/// Compression dictionaries are stored next to the blobs, under these predictable names.
+const DICTIONARY_PREFIX: &'static [u8] = b"dictionary-";
+
+fn dictionary_name(id: i64) -> Vec<u8> {
+    let mut name = DICTIONARY_PREFIX.to_vec();
+    name.extend_from_slice(id.to_string().as_bytes());
+    name
+}
+
+fn dictionary_id_of_name(name: &[u8]) -> Option<i64> {
+    if !name.starts_with(DICTIONARY_PREFIX) {
+        return None;
+    }
+    str::from_utf8(&name[DICTIONARY_PREFIX.len()..]).ok().and_then(|id| id.parse().ok())
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct BlobDesc {
     pub name: Vec<u8>,
@@ -34,52 +51,34 @@ pub struct BlobDesc {
 pub struct InternalBlobIndex {
     index: Arc<db::Index>,
     next_id: Arc<Mutex<i64>>,
-    name_key: Arc<crypto::FixedKey>,
+    keys: Arc<crypto::keys::Keeper>,
 }
 
 pub struct BlobIndex(InternalBlobIndex);
 
 
 impl InternalBlobIndex {
-    pub fn new(index: Arc<db::Index>) -> Result<InternalBlobIndex, DieselError> {
-        // TODO(jos): Plug an actual crypto key through somehow.
-        let pubkey = crypto::sealed::desc::PublicKey::from_slice(&[215, 136, 80, 128, 158, 109,
-                                                                   227, 141, 219, 63, 118, 91,
-                                                                   123, 97, 1, 97, 65, 237, 62,
-                                                                   171, 83, 159, 200, 11, 68,
-                                                                   138, 40, 82, 24, 47, 187, 29])
-            .unwrap();
-        let seckey = crypto::sealed::desc::SecretKey::from_slice(&[94, 13, 181, 81, 97, 87, 76,
-                                                                   37, 53, 92, 120, 232, 17, 126,
-                                                                   234, 78, 12, 23, 141, 61, 40,
-                                                                   10, 136, 127, 103, 192, 255,
-                                                                   193, 142, 154, 101, 35])
-            .unwrap();
-        let name_key = crypto::FixedKey::new(pubkey, Some(seckey));
-
-
+    pub fn new(index: Arc<db::Index>,
+               keys: Arc<crypto::keys::Keeper>)
+               -> Result<InternalBlobIndex, DieselError> {
         let bi = InternalBlobIndex {
             index: index,
             next_id: Arc::new(Mutex::new(0)),
-            name_key: Arc::new(name_key),
+            keys: keys,
         };
         bi.refresh_next_id();
         Ok(bi)
     }
 
     fn name_of_id(&self, id: i64) -> Vec<u8> {
-        return self.name_key
+        return self.keys
             .light_seal(crypto::PlainText::from_i64(id).as_ref())
             .to_vec();
     }
 
-    fn id_of_name(&self, name: &[u8]) -> Result<i64, String> {
-        return Ok(self.name_key
-            .light_unseal(crypto::CipherTextRef::new(name))
-            .unwrap()
-            .as_ref()
-            .read_i64()
-            .unwrap());
+    fn id_of_name(&self, name: &[u8]) -> Result<i64, CryptoError> {
+        let pt = self.keys.light_unseal(crypto::CipherTextRef::new(name))?;
+        Ok(pt.as_ref().read_i64()?)
     }
 
     fn new_blob_desc(&self) -> BlobDesc {
@@ -104,23 +103,44 @@ impl InternalBlobIndex {
         *id
     }
 
-    fn recover(&self, name: Vec<u8>) -> BlobDesc {
-        let wanted_id = self.id_of_name(&name).unwrap();
+    fn recover(&self, name: Vec<u8>) -> Result<BlobDesc, CryptoError> {
+        let wanted_id = self.id_of_name(&name)?;
         if let Some(id) = {
             self.index.lock().blob_id_from_name(&name[..])
         } {
             assert_eq!(id, wanted_id);
 
             // Blob exists.
+            return Ok(BlobDesc {
+                name: name,
+                id: id,
+            });
+        }
+
+        let blob = BlobDesc {
+            name: name,
+            id: wanted_id,
+        };
+        self.index.lock().blob_in_air(&blob);
+        self.index.lock().blob_commit(&blob);
+
+        Ok(blob)
+    }
+
+    fn recover_with_new_id(&self, name: Vec<u8>) -> BlobDesc {
+        if let Some(id) = {
+            self.index.lock().blob_id_from_name(&name[..])
+        } {
             return BlobDesc {
                 name: name,
                 id: id,
             };
         }
 
+        self.refresh_next_id();
         let blob = BlobDesc {
             name: name,
-            id: wanted_id,
+            id: self.next_id(),
         };
         self.index.lock().blob_in_air(&blob);
         self.index.lock().blob_commit(&blob);
@@ -128,14 +148,23 @@ impl InternalBlobIndex {
         blob
     }
 
+    fn name_matches_id(&self, blob: &BlobDesc) -> bool {
+        match self.id_of_name(&blob.name) {
+            Ok(id) => id == blob.id,
+            Err(_) => false,
+        }
+    }
+
     fn reserve(&self) -> BlobDesc {
         self.new_blob_desc()
     }
 }
 
 impl BlobIndex {
-    pub fn new(index: Arc<db::Index>) -> Result<BlobIndex, DieselError> {
-        InternalBlobIndex::new(index).map(|bi| BlobIndex(bi))
+    pub fn new(index: Arc<db::Index>,
+               keys: Arc<crypto::keys::Keeper>)
+               -> Result<BlobIndex, DieselError> {
+        InternalBlobIndex::new(index, keys).map(|bi| BlobIndex(bi))
     }
 
     /// Reserve an internal `BlobDesc` for a new blob.
@@ -158,10 +187,61 @@ impl BlobIndex {
 
     /// Reinstall blob recovered by from external storage.
     /// Creates a new blob by a known external name.
-    pub fn recover(&self, name: Vec<u8>) -> BlobDesc {
+    pub fn recover(&self, name: Vec<u8>) -> Result<BlobDesc, CryptoError> {
         self.0.recover(name)
     }
 
+    /// Reinstall a blob whose name cannot be read with the current keys, as happens to blobs
+    /// named before a key rotation. The blob is given a new internal id.
+    pub fn recover_with_new_id(&self, name: Vec<u8>) -> BlobDesc {
+        self.0.recover_with_new_id(name)
+    }
+
+    /// Check whether the blob name decodes to the blob's id with the current keys.
+    pub fn name_matches_id(&self, blob: &BlobDesc) -> bool {
+        self.0.name_matches_id(blob)
+    }
+
+    /// The most recently committed blob, if any.
+    pub fn latest(&self) -> Option<BlobDesc> {
+        self.0.index.lock().blob_latest()
+    }
+
+    /// Reserve an id and external name for a new compression dictionary.
+    pub fn reserve_dictionary(&self) -> BlobDesc {
+        let id = self.0.index.lock().dictionary_next_id();
+        BlobDesc {
+            name: dictionary_name(id),
+            id: id,
+        }
+    }
+
+    /// Report that this dictionary has been committed to persistent storage.
+    pub fn commit_dictionary(&self, dict: &BlobDesc) {
+        self.0.index.lock().dictionary_insert(dict)
+    }
+
+    /// Reinstall a compression dictionary found in external storage. Returns `None` if the name
+    /// is not that of a dictionary.
+    pub fn recover_dictionary(&self, name: &[u8]) -> Option<BlobDesc> {
+        dictionary_id_of_name(name).map(|id| {
+            let dict = BlobDesc {
+                name: name.to_vec(),
+                id: id,
+            };
+            let mut index = self.0.index.lock();
+            if !index.dictionary_exists(id) {
+                index.dictionary_insert(&dict);
+            }
+            dict
+        })
+    }
+
+    /// All compression dictionaries, newest first.
+    pub fn list_dictionaries(&self) -> Vec<BlobDesc> {
+        self.0.index.lock().dictionary_list()
+    }
+
     pub fn find(&self, name: &[u8]) -> Option<BlobDesc> {
         if let Some(id) = self.0.index.lock().blob_id_from_name(&name) {
             Some(BlobDesc {
@@ -181,6 +261,10 @@ impl BlobIndex {
         self.0.index.lock().blob_set_tag(tag, None)
     }
 
+    pub fn retag(&self, from: tags::Tag, to: tags::Tag) {
+        self.0.index.lock().blob_retag(from, to)
+    }
+
     pub fn list_by_tag(&self, tag: tags::Tag) -> Vec<BlobDesc> {
         self.0.index.lock().blob_list_by_tag(tag)
     }
@@ -15,23 +15,28 @@
 //! Combines data chunks into larger blobs to be stored externally.
 
 
-use backend::StoreBackend;
+use backend::{BackendError, StoreBackend};
 use capnp;
+use crypto;
 use errors;
 use hash::Hash;
 use hash::tree::HashRef;
+use rustc_serialize::hex::ToHex;
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::mem;
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
 use tags;
 use util::FnBox;
 use key;
+use zstd;
 
 
 mod chunk;
 mod blob;
 mod index;
+mod upload;
 #[cfg(test)]
 pub mod tests;
 
@@ -42,6 +47,11 @@ mod benchmarks;
 pub use self::blob::Blob;
 pub use self::chunk::{ChunkRef, Key, NodeType, LeafType, Packing};
 pub use self::index::{BlobDesc, BlobIndex};
+pub use self::upload::UploadConfig;
+
+
+/// Upper bound on the size of a trained compression dictionary.
+const MAX_DICTIONARY_SIZE: usize = 112 * 1024;
 
 
 error_type! {
@@ -52,6 +62,9 @@ error_type! {
             from (s: &'static str) s.into();
             from (s: String) s.into();
         },
+        Backend(BackendError) {
+            cause;
+        },
         CryptoError(errors::CryptoError) {
             cause;
         },
@@ -69,6 +82,9 @@ pub struct StoreInner<B> {
     blob_desc: BlobDesc,
     blob_refs: Vec<(Box<FnBox<(), ()>>)>,
     blob: Blob,
+    uploads: upload::Uploader,
+    keys: Arc<crypto::keys::Keeper>,
+    dictionaries: HashMap<i64, Arc<Vec<u8>>>,
 }
 
 impl<B> Drop for StoreInner<B> {
@@ -79,43 +95,129 @@ impl<B> Drop for StoreInner<B> {
 }
 
 impl<B: StoreBackend> StoreInner<B> {
-    fn new(index: Arc<BlobIndex>, backend: Arc<B>, max_blob_size: usize) -> StoreInner<B> {
+    fn new(index: Arc<BlobIndex>,
+           backend: Arc<B>,
+           max_blob_size: usize,
+           keys: Arc<crypto::keys::Keeper>,
+           uploads: &UploadConfig)
+           -> StoreInner<B> {
         let mut bs = StoreInner {
+            uploads: upload::Uploader::new(backend.clone(), index.clone(), uploads),
             backend: backend,
             blob_index: index,
             blob_desc: Default::default(),
             blob_refs: Vec::new(),
-            blob: Blob::new(max_blob_size),
+            blob: Blob::new(max_blob_size, keys.clone()),
+            keys: keys,
+            dictionaries: HashMap::new(),
         };
         bs.reserve_new_blob();
+        bs.use_latest_dictionary();
         bs
     }
 
+    fn use_latest_dictionary(&mut self) {
+        let latest = match self.blob_index.list_dictionaries().into_iter().next() {
+            None => return,
+            Some(dict) => dict,
+        };
+        // Write-only clients cannot read the dictionary and fall back to plain packing.
+        match self.dictionary(latest.id) {
+            Ok(dict) => self.blob.set_dictionary(latest.id, dict),
+            Err(e) => debug!("Not using compression dictionary {}: {}", latest.id, e),
+        }
+    }
+
+    fn dictionary(&mut self, id: i64) -> Result<Arc<Vec<u8>>, BlobError> {
+        if let Some(dict) = self.dictionaries.get(&id) {
+            return Ok(dict.clone());
+        }
+        let desc = self.blob_index
+            .list_dictionaries()
+            .into_iter()
+            .find(|d| d.id == id)
+            .ok_or_else(|| format!("Unknown compression dictionary: {}", id))?;
+        let ct = self.backend
+            .retrieve(&desc.name[..])?
+            .ok_or_else(|| format!("Compression dictionary is missing: {}", id))?;
+        let (_, pt) = self.keys.unseal(crypto::CipherTextRef::new(&ct[..]))?;
+
+        let dict = Arc::new(pt.into_vec());
+        self.dictionaries.insert(id, dict.clone());
+        Ok(dict)
+    }
+
+    fn add_dictionary(&mut self, dictionary: Vec<u8>) -> Result<i64, BlobError> {
+        let desc = self.blob_index.reserve_dictionary();
+        let ct = self.keys.seal(crypto::PlainTextRef::new(&dictionary[..]));
+        self.backend.store(&desc.name[..], &ct)?;
+        self.blob_index.commit_dictionary(&desc);
+
+        let dict = Arc::new(dictionary);
+        self.dictionaries.insert(desc.id, dict.clone());
+        self.blob.set_dictionary(desc.id, dict);
+        Ok(desc.id)
+    }
+
+    fn train_dictionary(&mut self, samples: &[Vec<u8>]) -> Result<i64, BlobError> {
+        let dictionary = zstd::dict::from_samples(samples, MAX_DICTIONARY_SIZE)
+            .map_err(|e| format!("Could not train compression dictionary: {}", e))?;
+        self.add_dictionary(dictionary)
+    }
+
+    fn reseal_dictionaries(&mut self) -> Result<(), BlobError> {
+        for desc in self.blob_index.list_dictionaries() {
+            let ct = self.backend
+                .retrieve(&desc.name[..])?
+                .ok_or_else(|| format!("Compression dictionary is missing: {}", desc.id))?;
+            let (_, pt) = self.keys.unseal(crypto::CipherTextRef::new(&ct[..]))?;
+            self.backend.replace(&desc.name[..], &self.keys.seal(pt.as_ref()))?;
+        }
+        Ok(())
+    }
+
     fn reserve_new_blob(&mut self) -> BlobDesc {
         mem::replace(&mut self.blob_desc, self.blob_index.reserve())
     }
 
-    fn flush(&mut self) {
+    /// Seal the current blob and queue it for upload, together with the callbacks of its chunks.
+    fn queue_blob(&mut self) -> Result<(), BlobError> {
         let ct = match self.blob.to_ciphertext() {
-            None => return,
+            None => return Ok(()),
             Some(ct) => ct,
         };
 
         // Replace blob id
-        let old_blob_desc = self.reserve_new_blob();
-
-        self.blob_index.in_air(&old_blob_desc);
-        self.backend
-            .store(&old_blob_desc.name[..], &ct)
-            .expect("Store operation failed");
-        self.blob_index.commit_done(&old_blob_desc);
+        let desc = self.reserve_new_blob();
+        self.blob_index.in_air(&desc);
+        let upload = upload::Upload {
+            desc: desc,
+            ct: ct,
+            callbacks: mem::replace(&mut self.blob_refs, vec![]),
+        };
 
-        // Go through callbacks
-        while let Some(callback) = self.blob_refs.pop() {
-            callback.call(());
+        // Stop uploading new blobs while an upload is failing; the sealed blob waits with the
+        // failed ones until a flush retries them.
+        match self.uploads.error() {
+            None => {
+                self.uploads.queue(upload);
+                Ok(())
+            }
+            Some(e) => {
+                self.uploads.park(upload, e.clone());
+                Err(e.into())
+            }
         }
     }
 
+    fn flush(&mut self) -> Result<(), BlobError> {
+        // Failed uploads kept their sealed blobs, so a later flush can retry them.
+        self.uploads.retry_failed();
+        let queued = self.queue_blob();
+        self.uploads.wait()?;
+        queued
+    }
+
     fn store(&mut self,
              chunk: &[u8],
              hash: Hash,
@@ -123,7 +225,7 @@ impl<B: StoreBackend> StoreInner<B> {
              leaf: LeafType,
              info: Option<&key::Info>,
              callback: Box<FnBox<(), ()>>)
-             -> HashRef {
+             -> Result<HashRef, BlobError> {
         let mut href = HashRef {
             hash: hash,
             node: node,
@@ -132,8 +234,8 @@ impl<B: StoreBackend> StoreInner<B> {
             persistent_ref: ChunkRef {
                 blob_id: Some(0),
                 blob_name: vec![0],
-                packing: None,
                 // Updated by try_append.
+                packing: None,
                 offset: 0,
                 length: 0,
                 key: None,
@@ -147,34 +249,66 @@ impl<B: StoreBackend> StoreInner<B> {
             href.persistent_ref.blob_id = Some(self.blob_desc.id);
             href.persistent_ref.blob_name = self.blob_desc.name.clone();
             if let Err(()) = self.blob.try_append(chunk, &mut href) {
-                self.flush();
+                self.queue_blob()?;
                 href.persistent_ref.blob_id = Some(self.blob_desc.id);
                 href.persistent_ref.blob_name = self.blob_desc.name.clone();
 
                 self.blob.try_append(chunk, &mut href).unwrap();
             }
 
-            // Queue the callback; we will trigger it when the blob has been pushed.
+            // Queue the callback; the uploader triggers it when the blob has been pushed.
             self.blob_refs.push(callback);
         }
 
         // Info is internal to the blob only.
         href.info = None;
         // To avoid unnecessary blocking, we reply with the ID *before* possibly flushing.
-        href
+        Ok(href)
     }
 
     fn retrieve(&mut self, hash: &Hash, cref: &ChunkRef) -> Result<Option<Vec<u8>>, BlobError> {
         if cref.offset == 0 && cref.length == 0 {
             return Ok(Some(Vec::new()));
         }
+        let dictionary = match cref.packing.as_ref().and_then(|p| p.dictionary()) {
+            None => None,
+            Some(id) => Some(self.dictionary(id)?),
+        };
         match self.backend.retrieve(&cref.blob_name[..]) {
-            Ok(Some(blob)) => Ok(Some(Blob::read_chunk(&blob, hash, cref)?)),
+            Ok(Some(blob)) => {
+                Ok(Some(Blob::read_chunk_with_dictionary(&blob,
+                                                         hash,
+                                                         cref,
+                                                         dictionary.as_ref().map(|d| &d[..]))?))
+            }
             Ok(None) => Ok(None),
             Err(e) => Err(e.into()),
         }
     }
 
+    fn retrieve_from_blob(&mut self,
+                          blob_name: &[u8],
+                          chunks: &[(Hash, ChunkRef)])
+                          -> Result<Option<Vec<Vec<u8>>>, BlobError> {
+        let blob = match self.backend.retrieve(blob_name)? {
+            None => return Ok(None),
+            Some(blob) => blob,
+        };
+        let mut result = Vec::with_capacity(chunks.len());
+        for &(ref hash, ref cref) in chunks {
+            assert_eq!(blob_name, &cref.blob_name[..]);
+            let dictionary = match cref.packing.as_ref().and_then(|p| p.dictionary()) {
+                None => None,
+                Some(id) => Some(self.dictionary(id)?),
+            };
+            result.push(Blob::read_chunk_with_dictionary(&blob,
+                                                         hash,
+                                                         cref,
+                                                         dictionary.as_ref().map(|d| &d[..]))?);
+        }
+        Ok(Some(result))
+    }
+
     fn retrieve_refs(&mut self, blob: BlobDesc) -> Result<Option<Vec<HashRef>>, BlobError> {
         match self.backend.retrieve(&blob.name[..])? {
             None => Ok(None),
@@ -190,13 +324,57 @@ impl<B: StoreBackend> StoreInner<B> {
         }
     }
 
-    fn recover(&mut self) -> Result<(), String> {
-        self.backend.list()?.into_iter()
-            .filter(|b| b.len() > 4)  // FIXME(jos): Remove when "root" is gone.
-            .map(|b| self.blob_index.recover(b.into_vec())).last();
+    fn recover(&mut self) -> Result<(), BlobError> {
+        let mut unnamed = vec![];
+        for b in self.backend.list()? {
+            if self.blob_index.recover_dictionary(&b[..]).is_some() {
+                continue;
+            }
+            // FIXME(jos): Remove when "root" is gone.
+            if b.len() > 4 {
+                if let Err(e) = self.blob_index.recover(b.to_vec()) {
+                    debug!("Cannot read blob name {}: {}", b.to_hex(), e);
+                    unnamed.push(b.into_vec());
+                }
+            }
+        }
+        // Blobs named under a rotated key get new ids, after all ids we know have been taken.
+        for name in unnamed {
+            self.blob_index.recover_with_new_id(name);
+        }
+        self.use_latest_dictionary();
         Ok(())
     }
 
+    fn reseal(&mut self, blob: &BlobDesc) -> Result<(), BlobError> {
+        match self.backend.retrieve(&blob.name[..])? {
+            None => Err(format!("Blob to reseal is missing: {}", blob.name.to_hex()).into()),
+            Some(ct) => {
+                let resealed = self.blob.reseal(&ct[..])?;
+                self.backend.replace(&blob.name[..], &resealed)?;
+                Ok(())
+            }
+        }
+    }
+
+    fn verify_key(&mut self) -> Result<(), BlobError> {
+        let blob = match self.blob_index.latest() {
+            None => return Ok(()),  // Nothing to check against yet.
+            Some(blob) => blob,
+        };
+        if self.blob_index.name_matches_id(&blob) {
+            return Ok(());
+        }
+        // Key rotation reseals blob footers, but keeps blob names as they are.
+        match self.retrieve_refs(blob) {
+            Ok(_) => Ok(()),
+            Err(_) => {
+                Err(From::from("Repository key does not match the blobs in this repository \
+                                (wrong key file?)"))
+            }
+        }
+    }
+
     fn tag(&mut self, chunk: ChunkRef, tag: tags::Tag) {
         self.blob_index.tag(&BlobDesc {
                                 id: chunk.blob_id.unwrap_or(0),
@@ -209,10 +387,15 @@ impl<B: StoreBackend> StoreInner<B> {
         self.blob_index.tag_all(tag);
     }
 
-    fn delete_by_tag(&mut self, tag: tags::Tag) -> Result<(), String> {
+    fn delete_by_tag(&mut self, tag: tags::Tag) -> Result<(), BlobError> {
         let blobs = self.blob_index.list_by_tag(tag);
         for b in &blobs {
-            self.backend.delete(&b.name)?;
+            match self.backend.delete(&b.name) {
+                // Blobs that crashed in the air never made it, and an interrupted delete may
+                // already have removed others.
+                Ok(()) | Err(BackendError::NotFound(..)) => (),
+                Err(e) => return Err(e.into()),
+            }
         }
         self.blob_index.delete_by_tag(tag);
         Ok(())
@@ -220,8 +403,26 @@ impl<B: StoreBackend> StoreInner<B> {
 }
 
 impl<B: StoreBackend> BlobStore<B> {
-    pub fn new(index: Arc<BlobIndex>, backend: Arc<B>, max_blob_size: usize) -> BlobStore<B> {
-        BlobStore(Arc::new(Mutex::new(StoreInner::new(index, backend, max_blob_size))))
+    pub fn new(index: Arc<BlobIndex>,
+               backend: Arc<B>,
+               max_blob_size: usize,
+               keys: Arc<crypto::keys::Keeper>)
+               -> BlobStore<B> {
+        BlobStore::with_uploads(index, backend, max_blob_size, keys, &UploadConfig::default())
+    }
+
+    /// A blob store that uploads its blobs as `uploads` says.
+    pub fn with_uploads(index: Arc<BlobIndex>,
+                        backend: Arc<B>,
+                        max_blob_size: usize,
+                        keys: Arc<crypto::keys::Keeper>,
+                        uploads: &UploadConfig)
+                        -> BlobStore<B> {
+        BlobStore(Arc::new(Mutex::new(StoreInner::new(index,
+                                                      backend,
+                                                      max_blob_size,
+                                                      keys,
+                                                      uploads))))
     }
 
     fn lock(&self) -> MutexGuard<StoreInner<B>> {
@@ -230,7 +431,8 @@ impl<B: StoreBackend> BlobStore<B> {
 
     /// Store a new data chunk into the current blob. The callback is triggered after the blob
     /// containing the chunk has been committed to persistent storage (it is then safe to use the
-    /// `ChunkRef` as persistent reference).
+    /// `ChunkRef` as persistent reference). Full blobs are uploaded in the background; this only
+    /// waits for the backend when the upload queue is full.
     pub fn store(&self,
                  chunk: &[u8],
                  hash: Hash,
@@ -238,7 +440,7 @@ impl<B: StoreBackend> BlobStore<B> {
                  leaf: LeafType,
                  info: Option<&key::Info>,
                  callback: Box<FnBox<(), ()>>)
-                 -> HashRef {
+                 -> Result<HashRef, BlobError> {
         let mut guard = self.lock();
         guard.store(chunk, hash, node, leaf, info, callback)
     }
@@ -248,16 +450,46 @@ impl<B: StoreBackend> BlobStore<B> {
         self.lock().retrieve(hash, cref)
     }
 
+    /// Fetch a blob once and read several of its chunks from it.
+    pub fn retrieve_from_blob(&self,
+                              blob_name: &[u8],
+                              chunks: &[(Hash, ChunkRef)])
+                              -> Result<Option<Vec<Vec<u8>>>, BlobError> {
+        self.lock().retrieve_from_blob(blob_name, chunks)
+    }
+
     /// Fetch a blob and recover the HashRefs for its contents.
     pub fn retrieve_refs(&self, blob: BlobDesc) -> Result<Option<Vec<HashRef>>, BlobError> {
         self.lock().retrieve_refs(blob)
     }
 
     /// Reinstall a blob recovered from external storage.
-    pub fn recover(&self) -> Result<(), String> {
+    pub fn recover(&self) -> Result<(), BlobError> {
         self.lock().recover()
     }
 
+    /// Seal the footer of a stored blob with the current master key.
+    pub fn reseal(&self, blob: &BlobDesc) -> Result<(), BlobError> {
+        self.lock().reseal(blob)
+    }
+
+    /// Train a compression dictionary from sample tree listings and use it for new tree listings.
+    /// Chunks packed with older dictionaries remain readable.
+    pub fn train_dictionary(&self, samples: &[Vec<u8>]) -> Result<i64, BlobError> {
+        self.lock().train_dictionary(samples)
+    }
+
+    /// Seal the compression dictionaries with the current master key.
+    pub fn reseal_dictionaries(&self) -> Result<(), BlobError> {
+        self.lock().reseal_dictionaries()
+    }
+
+    /// Check that the repository key can read the blobs we already know about.
+    /// This catches opening a repository with somebody else's key file.
+    pub fn verify_key(&self) -> Result<(), BlobError> {
+        self.lock().verify_key()
+    }
+
     pub fn tag(&self, chunk: ChunkRef, tag: tags::Tag) {
         self.lock().tag(chunk, tag)
     }
@@ -266,7 +498,7 @@ impl<B: StoreBackend> BlobStore<B> {
         self.lock().tag_all(tag)
     }
 
-    pub fn delete_by_tag(&self, tag: tags::Tag) -> Result<(), String> {
+    pub fn delete_by_tag(&self, tag: tags::Tag) -> Result<(), BlobError> {
         self.lock().delete_by_tag(tag)
     }
 
@@ -285,10 +517,12 @@ impl<B: StoreBackend> BlobStore<B> {
         }
     }
 
-    /// Flush the current blob, independent of its size.
-    pub fn flush(&self) {
+    /// Flush the current blob, independent of its size, and wait for all uploads to finish.
+    /// A failed flush can be retried.
+    pub fn flush(&self) -> Result<(), BlobError> {
         let mut guard = self.lock();
-        guard.flush();
+        guard.flush()?;
         guard.blob_index.flush();
+        Ok(())
     }
 }
@@ -12,23 +12,31 @@
 // See the License for the specific language governing permissions and
 // limitations under the License
 
-use backend::{MemoryBackend, StoreBackend};
-use blob::{Blob, BlobError, BlobIndex, BlobStore, ChunkRef, Key, NodeType, LeafType};
+use backend::{BackendError, MemoryBackend, StoreBackend};
+use blob::{Blob, BlobError, BlobIndex, BlobStore, ChunkRef, Key, NodeType, LeafType, Packing};
+use crypto::CipherText;
+use crypto::keys::Keeper;
 use db;
 use hash;
 use quickcheck;
+use sodiumoxide::randombytes;
 
 use std::collections::HashSet;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
 
 #[test]
 fn identity() {
     fn prop(chunks: Vec<Vec<u8>>) -> bool {
         let backend = Arc::new(MemoryBackend::new());
 
+        let keys = Arc::new(Keeper::generate());
         let db = Arc::new(db::Index::new_for_testing());
-        let blob_index = Arc::new(BlobIndex::new(db).unwrap());
-        let bs_p = BlobStore::new(blob_index, backend.clone(), 1024);
+        let blob_index = Arc::new(BlobIndex::new(db, keys.clone()).unwrap());
+        let bs_p = BlobStore::new(blob_index, backend.clone(), 1024, keys);
 
         let mut ids = Vec::new();
         for chunk in chunks.iter() {
@@ -37,11 +45,12 @@ fn identity() {
                                  NodeType::Leaf,
                                  LeafType::FileChunk,
                                  None,
-                                 Box::new(move |_| {})),
+                                 Box::new(move |_| {}))
+                          .unwrap(),
                       chunk));
         }
 
-        bs_p.flush();
+        bs_p.flush().unwrap();
 
         // Non-empty chunks must be in the backend now:
         for &(ref id, chunk) in ids.iter() {
@@ -66,14 +75,39 @@ fn identity() {
     quickcheck::quickcheck(prop as fn(Vec<Vec<u8>>) -> bool);
 }
 
+#[test]
+fn write_only_key_cannot_retrieve_refs() {
+    let backend = Arc::new(MemoryBackend::new());
+    let keys = Keeper::generate();
+
+    let db = Arc::new(db::Index::new_for_testing());
+    let write_only = Arc::new(keys.public_only());
+    let blob_index = Arc::new(BlobIndex::new(db, write_only.clone()).unwrap());
+    let bs_p = BlobStore::new(blob_index, backend.clone(), 1024, write_only);
+
+    let chunk = vec![1, 2, 3];
+    let id = bs_p.store(&chunk[..],
+                        hash::Hash::new(&chunk[..]),
+                        NodeType::Leaf,
+                        LeafType::FileChunk,
+                        None,
+                        Box::new(move |_| {}))
+        .unwrap();
+    bs_p.flush().unwrap();
+
+    let blob = bs_p.find(&id.persistent_ref.blob_name[..]).unwrap();
+    assert!(bs_p.retrieve_refs(blob).is_err());
+}
+
 #[test]
 fn identity_with_excessive_flushing() {
     fn prop(chunks: Vec<Vec<u8>>) -> bool {
         let backend = Arc::new(MemoryBackend::new());
 
+        let keys = Arc::new(Keeper::generate());
         let db = Arc::new(db::Index::new_for_testing());
-        let blob_index = Arc::new(BlobIndex::new(db).unwrap());
-        let bs_p = BlobStore::new(blob_index, backend.clone(), 1024);
+        let blob_index = Arc::new(BlobIndex::new(db, keys.clone()).unwrap());
+        let bs_p = BlobStore::new(blob_index, backend.clone(), 1024, keys);
 
         let mut ids = Vec::new();
         for chunk in chunks.iter() {
@@ -82,9 +116,10 @@ fn identity_with_excessive_flushing() {
                                  NodeType::Leaf,
                                  LeafType::FileChunk,
                                  None,
-                                 Box::new(move |_| {})),
+                                 Box::new(move |_| {}))
+                          .unwrap(),
                       chunk));
-            bs_p.flush();
+            bs_p.flush().unwrap();
             let &(ref id, chunk) = ids.last().unwrap();
             assert_eq!(bs_p.retrieve(&id.hash, &id.persistent_ref)
                            .unwrap()
@@ -150,7 +185,7 @@ fn blob_reuse() {
     };
     let mut c2 = c1.clone();
 
-    let mut b = Blob::new(1000);
+    let mut b = Blob::new(1000, Arc::new(Keeper::generate()));
     b.try_append(&[1, 2, 3], &mut c1).unwrap();
     b.try_append(&[4, 5, 6], &mut c2).unwrap();
 
@@ -180,7 +215,8 @@ fn blob_reuse() {
 fn blob_identity() {
     fn prop(chunks: Vec<Vec<u8>>) -> bool {
         let max_size = 10000;
-        let mut b = Blob::new(max_size);
+        let keys = Arc::new(Keeper::generate());
+        let mut b = Blob::new(max_size, keys.clone());
         let mut n = 0;
         for chunk in chunks.iter() {
             let mut cref = hash::tree::HashRef {
@@ -214,12 +250,13 @@ fn blob_identity() {
 
         assert_eq!(max_size, out.len());
 
-        let hrefs = Blob::new(max_size).refs_from_bytes(&out).unwrap();
+        let hrefs = Blob::new(max_size, keys).refs_from_bytes(&out).unwrap();
         assert_eq!(n, hrefs.len());
 
         // Check recovered ChunkRefs.
         for (i, href) in hrefs.into_iter().enumerate() {
-            assert!(chunks[i].len() < href.persistent_ref.length);
+            assert!(href.persistent_ref.packing.is_some() ||
+                    chunks[i].len() < href.persistent_ref.length);
             let chunk = Blob::read_chunk(&out, &href.hash, &href.persistent_ref).unwrap();
             assert_eq!(chunks[i].len(), chunk.len());
             assert_eq!(&chunks[i], &chunk);
@@ -230,13 +267,130 @@ fn blob_identity() {
     quickcheck::quickcheck(prop as fn(Vec<Vec<u8>>) -> bool);
 }
 
+fn append_and_read(chunk: &[u8], leaf: LeafType) -> (Option<Packing>, Vec<u8>) {
+    let mut blob = Blob::new(128000, Arc::new(Keeper::generate()));
+    let mut href = hash::tree::HashRef {
+        hash: hash::Hash::new(chunk),
+        node: NodeType::Leaf,
+        leaf: leaf,
+        info: None,
+        persistent_ref: ChunkRef {
+            blob_id: None,
+            blob_name: Vec::new(),
+            offset: 0,
+            length: 0,
+            packing: None,
+            key: None,
+        },
+    };
+    blob.try_append(chunk, &mut href).unwrap();
+    let out = blob.to_ciphertext().unwrap().to_vec();
+
+    let hrefs = blob.refs_from_bytes(&out).unwrap();
+    assert_eq!(1, hrefs.len());
+    assert_eq!(href.persistent_ref.packing, hrefs[0].persistent_ref.packing);
+    (hrefs[0].persistent_ref.packing.clone(),
+     Blob::read_chunk(&out, &hrefs[0].hash, &hrefs[0].persistent_ref).unwrap())
+}
+
+#[test]
+fn blob_packs_compressible_chunks() {
+    let mut chunk = vec![];
+    for _ in 0..64 {
+        chunk.extend_from_slice(b"hat hat hat hat hat hat hat hat hat hat hat hat hat hat");
+    }
+
+    let (packing, out) = append_and_read(&chunk[..], LeafType::FileChunk);
+    assert_eq!(Some(Packing::Snappy), packing);
+    assert_eq!(chunk, out);
+
+    let (packing, out) = append_and_read(&chunk[..], LeafType::TreeList);
+    assert_eq!(Some(Packing::GZip), packing);
+    assert_eq!(chunk, out);
+}
+
+#[test]
+fn blob_keeps_incompressible_chunks_raw() {
+    let chunk = randombytes::randombytes(4096);
+
+    for leaf in vec![LeafType::FileChunk, LeafType::TreeList] {
+        let (packing, out) = append_and_read(&chunk[..], leaf);
+        assert_eq!(None, packing);
+        assert_eq!(chunk, out);
+    }
+}
+fn tree_listing(n: usize) -> Vec<u8> {
+    let mut listing = vec![];
+    for i in 0..20 {
+        listing.extend_from_slice(format!("name: \"file-{}-{}.rs\" size: {} created: {} \
+                                           permissions: 420 user: 1000 group: 100\n",
+                                          n,
+                                          i,
+                                          n * i,
+                                          1480000000 + n * 7 + i)
+            .as_bytes());
+    }
+    listing
+}
+
+#[test]
+fn blob_store_zstd_dictionary() {
+    let backend = Arc::new(MemoryBackend::new());
+    let keys = Arc::new(Keeper::generate());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(db, keys.clone()).unwrap());
+    let bs = BlobStore::new(blob_index, backend.clone(), 1024 * 1024, keys.clone());
+
+    let store = |chunk: &[u8]| {
+        bs.store(chunk,
+                 hash::Hash::new(chunk),
+                 NodeType::Leaf,
+                 LeafType::TreeList,
+                 None,
+                 Box::new(move |_| {}))
+            .unwrap()
+    };
+
+    let samples: Vec<_> = (0..200).map(tree_listing).collect();
+    let first = bs.train_dictionary(&samples).unwrap();
+    let listing = tree_listing(1000);
+    let href1 = store(&listing[..]);
+    assert_eq!(Some(Packing::Zstd(Some(first))), href1.persistent_ref.packing);
+
+    // Chunks packed with an older dictionary stay readable after retraining.
+    let second = bs.train_dictionary(&samples[100..]).unwrap();
+    assert!(second > first);
+    let href2 = store(&tree_listing(1001)[..]);
+    assert_eq!(Some(Packing::Zstd(Some(second))), href2.persistent_ref.packing);
+    bs.flush().unwrap();
+
+    assert_eq!(listing, bs.retrieve(&href1.hash, &href1.persistent_ref).unwrap().unwrap());
+
+    // Both listings share a blob and can be read from a single fetch of it.
+    let name = href1.persistent_ref.blob_name.clone();
+    assert_eq!(name, href2.persistent_ref.blob_name);
+    let chunks = [(href1.hash.clone(), href1.persistent_ref.clone()),
+                  (href2.hash.clone(), href2.persistent_ref.clone())];
+    assert_eq!(vec![listing.clone(), tree_listing(1001)],
+               bs.retrieve_from_blob(&name[..], &chunks[..]).unwrap().unwrap());
+
+    // A fresh index finds the dictionaries in the backend.
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(db, keys.clone()).unwrap());
+    let bs = BlobStore::new(blob_index.clone(), backend, 1024 * 1024, keys);
+    bs.recover().unwrap();
+    assert_eq!(2, blob_index.list_dictionaries().len());
+    assert_eq!(listing, bs.retrieve(&href1.hash, &href1.persistent_ref).unwrap().unwrap());
+    assert_eq!(tree_listing(1001),
+               bs.retrieve(&href2.hash, &href2.persistent_ref).unwrap().unwrap());
+}
 
 #[test]
 fn random_input_fails() {
     use sodiumoxide::crypto::secretbox::xsalsa20poly1305;
 
     fn prop(data: Vec<u8>, hash: Vec<u8>) -> bool {
-        let blob = Blob::new(128000);
+        let blob = Blob::new(128000, Arc::new(Keeper::generate()));
         if let Ok(_) = blob.refs_from_bytes(&data[..]) {
             return false;
         }
@@ -292,7 +446,7 @@ fn empty_blocks_blob_ciphertext(blob: &mut Blob, blocksize: usize) -> Vec<u8> {
 fn blob_ciphertext_uniqueblocks() {
     // If every inserted block gets a unique (nonce, key) combination, they should produce unique
     // blocks in the out-coming ciphertext (by high enough probability to assert it).
-    let mut blob = Blob::new(1024 * 1024);
+    let mut blob = Blob::new(1024 * 1024, Arc::new(Keeper::generate()));
     let mut blocks = HashSet::new();
 
     for _ in 1..10 {
@@ -304,9 +458,35 @@ fn blob_ciphertext_uniqueblocks() {
     }
 }
 
+#[test]
+fn blob_reseal_with_rotated_key() {
+    let keys = Arc::new(Keeper::generate());
+    let old_keys = Arc::new(Keeper::new(keys.master(), keys.hash_key().map(|k| k.to_vec())));
+
+    let mut blob = Blob::new(1024, keys.clone());
+    let bytes = empty_blocks_blob_ciphertext(&mut blob, 16);
+    let refs = blob.refs_from_bytes(&bytes).unwrap();
+
+    keys.rotate();
+    let resealed = blob.reseal(&bytes).unwrap().to_vec();
+    assert_eq!(bytes.len(), resealed.len());
+    keys.finish_rotation();
+
+    // Only the new key can read the resealed footer.
+    assert_eq!(refs.len(), blob.refs_from_bytes(&resealed).unwrap().len());
+    assert!(blob.refs_from_bytes(&bytes).is_err());
+    assert!(Blob::new(1024, old_keys).refs_from_bytes(&resealed).is_err());
+
+    // The chunks themselves are untouched.
+    for r in refs {
+        assert_eq!(Blob::read_chunk(&bytes, &r.hash, &r.persistent_ref).unwrap(),
+                   Blob::read_chunk(&resealed, &r.hash, &r.persistent_ref).unwrap());
+    }
+}
+
 #[test]
 fn blob_ciphertext_authed_allbytes() {
-    let mut blob = Blob::new(1024);
+    let mut blob = Blob::new(1024, Arc::new(Keeper::generate()));
     let mut bytes = empty_blocks_blob_ciphertext(&mut blob, 1);
 
     fn verify(blob: &Blob, bs: &[u8]) -> Result<Vec<Vec<u8>>, BlobError> {
@@ -342,3 +522,318 @@ fn blob_ciphertext_authed_allbytes() {
     // We did not corrupt the blob.
     assert_eq!(vs, verify(&blob, &bytes[..]).unwrap());
 }
+
+/// A backend whose first few stores fail with a transient error.
+struct FlakyBackend {
+    inner: MemoryBackend,
+    failures: Mutex<usize>,
+}
+
+impl StoreBackend for FlakyBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let mut failures = self.failures.lock().unwrap();
+        if *failures > 0 {
+            *failures -= 1;
+            return Err(BackendError::Transient("Connection reset".to_owned()));
+        }
+        self.inner.store(name, data)
+    }
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.inner.replace(name, data)
+    }
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.inner.retrieve(name)
+    }
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.inner.delete(name)
+    }
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        self.inner.list()
+    }
+    fn flush(&self) -> Result<(), BackendError> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn failed_flush_can_be_retried() {
+    let backend = Arc::new(FlakyBackend {
+        inner: MemoryBackend::new(),
+        failures: Mutex::new(2),
+    });
+    let keys = Arc::new(Keeper::generate());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(db, keys.clone()).unwrap());
+    let bs = BlobStore::new(blob_index, backend.clone(), 1024, keys);
+
+    let (sender, receiver) = mpsc::channel();
+    let chunk = vec![1, 2, 3];
+    let href = bs.store(&chunk[..],
+                        hash::Hash::new(&chunk[..]),
+                        NodeType::Leaf,
+                        LeafType::FileChunk,
+                        None,
+                        Box::new(move |()| sender.send(()).unwrap()))
+        .unwrap();
+
+    for _ in 0..2 {
+        match bs.flush() {
+            Err(BlobError::Backend(ref e)) if e.is_transient() => (),
+            other => panic!("Expected a transient error: {:?}", other),
+        }
+        // The chunk is not committed until its blob is stored.
+        assert!(receiver.try_recv().is_err());
+    }
+
+    bs.flush().unwrap();
+    receiver.try_recv().unwrap();
+    assert_eq!(chunk, bs.retrieve(&href.hash, &href.persistent_ref).unwrap().unwrap());
+}
+
+#[test]
+fn full_blob_is_kept_while_uploads_fail() {
+    let backend = Arc::new(FlakyBackend {
+        inner: MemoryBackend::new(),
+        failures: Mutex::new(1),
+    });
+    let keys = Arc::new(Keeper::generate());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(db, keys.clone()).unwrap());
+    let bs = BlobStore::new(blob_index, backend.clone(), 1024, keys);
+
+    {
+        let store = |chunk: &[u8]| {
+            bs.store(chunk,
+                     hash::Hash::new(chunk),
+                     NodeType::Leaf,
+                     LeafType::FileChunk,
+                     None,
+                     Box::new(|()| ()))
+        };
+        store(&randombytes::randombytes(500)[..]).unwrap();
+        assert!(bs.flush().is_err());
+
+        // Each chunk fills most of a blob. The full blob is sealed, but not uploaded while the
+        // earlier upload is still failing.
+        store(&randombytes::randombytes(500)[..]).unwrap();
+        assert!(store(&randombytes::randombytes(500)[..]).is_err());
+        assert!(backend.inner.list().unwrap().is_empty());
+    }
+
+    // Nothing is left unsealed, so the store can be dropped without a flush.
+    drop(bs);
+}
+
+/// A backend whose first store panics.
+struct PanickingBackend {
+    inner: MemoryBackend,
+    panicked: AtomicBool,
+}
+
+impl StoreBackend for PanickingBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        if !self.panicked.swap(true, Ordering::SeqCst) {
+            panic!("Backend bug");
+        }
+        self.inner.store(name, data)
+    }
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.inner.replace(name, data)
+    }
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.inner.retrieve(name)
+    }
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.inner.delete(name)
+    }
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        self.inner.list()
+    }
+    fn flush(&self) -> Result<(), BackendError> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn panicking_upload_fails_flush() {
+    let backend = Arc::new(PanickingBackend {
+        inner: MemoryBackend::new(),
+        panicked: AtomicBool::new(false),
+    });
+    let keys = Arc::new(Keeper::generate());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(db, keys.clone()).unwrap());
+    let bs = BlobStore::new(blob_index, backend.clone(), 1024, keys);
+
+    let (sender, receiver) = mpsc::channel();
+    let chunk = vec![1, 2, 3];
+    let href = bs.store(&chunk[..],
+                        hash::Hash::new(&chunk[..]),
+                        NodeType::Leaf,
+                        LeafType::FileChunk,
+                        None,
+                        Box::new(move |()| sender.send(()).unwrap()))
+        .unwrap();
+
+    // The flush reports the panic instead of waiting for the upload forever.
+    assert!(bs.flush().is_err());
+    assert!(receiver.try_recv().is_err());
+
+    bs.flush().unwrap();
+    receiver.try_recv().unwrap();
+    assert_eq!(chunk, bs.retrieve(&href.hash, &href.persistent_ref).unwrap().unwrap());
+}
+
+/// A backend whose stores wait until the gate is unlocked.
+struct GatedBackend {
+    inner: MemoryBackend,
+    gate: Mutex<()>,
+}
+
+impl StoreBackend for GatedBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let _open = self.gate.lock().unwrap();
+        self.inner.store(name, data)
+    }
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.inner.replace(name, data)
+    }
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.inner.retrieve(name)
+    }
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.inner.delete(name)
+    }
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        self.inner.list()
+    }
+    fn flush(&self) -> Result<(), BackendError> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn full_blobs_upload_in_the_background() {
+    let backend = Arc::new(GatedBackend {
+        inner: MemoryBackend::new(),
+        gate: Mutex::new(()),
+    });
+    let keys = Arc::new(Keeper::generate());
+    let db = Arc::new(db::Index::new_for_testing());
+    let blob_index = Arc::new(BlobIndex::new(db, keys.clone()).unwrap());
+    let bs = BlobStore::new(blob_index, backend.clone(), 1024, keys);
+
+    let gate = backend.gate.lock().unwrap();
+    let (sender, receiver) = mpsc::channel();
+    let mut hrefs = vec![];
+    for i in 0..3u8 {
+        // Each chunk fills most of a blob, so every store after the first seals a full blob.
+        let chunk = randombytes::randombytes(500);
+        let sender = sender.clone();
+        hrefs.push((chunk.clone(),
+                    bs.store(&chunk[..],
+                             hash::Hash::new(&chunk[..]),
+                             NodeType::Leaf,
+                             LeafType::FileChunk,
+                             None,
+                             Box::new(move |()| sender.send(i).unwrap()))
+                        .unwrap()));
+    }
+    // The stores returned while the backend was stuck, and nothing is committed yet.
+    assert!(receiver.try_recv().is_err());
+
+    drop(gate);
+    bs.flush().unwrap();
+    let committed: Vec<u8> = receiver.try_iter().collect();
+    assert_eq!(vec![0, 1, 2], committed);
+    for (chunk, href) in hrefs {
+        assert_eq!(chunk, bs.retrieve(&href.hash, &href.persistent_ref).unwrap().unwrap());
+    }
+}
+
+/// A backend whose first store reports that it started, and then waits for the test to decide
+/// whether it succeeds.
+struct HeldBackend {
+    inner: MemoryBackend,
+    first: Mutex<Option<(mpsc::Sender<()>, mpsc::Receiver<bool>)>>,
+}
+
+impl StoreBackend for HeldBackend {
+    fn store(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        let first = self.first.lock().unwrap().take();
+        if let Some((started, succeed)) = first {
+            started.send(()).unwrap();
+            if !succeed.recv().unwrap() {
+                return Err(BackendError::Transient("Connection reset".to_owned()));
+            }
+        }
+        self.inner.store(name, data)
+    }
+    fn replace(&self, name: &[u8], data: &CipherText) -> Result<(), BackendError> {
+        self.inner.replace(name, data)
+    }
+    fn retrieve(&self, name: &[u8]) -> Result<Option<Vec<u8>>, BackendError> {
+        self.inner.retrieve(name)
+    }
+    fn delete(&self, name: &[u8]) -> Result<(), BackendError> {
+        self.inner.delete(name)
+    }
+    fn list(&self) -> Result<Vec<Box<[u8]>>, BackendError> {
+        self.inner.list()
+    }
+    fn flush(&self) -> Result<(), BackendError> {
+        self.inner.flush()
+    }
+}
+
+#[test]
+fn blobs_commit_in_queue_order() {
+    for &succeed in &[true, false] {
+        let (started, started_receiver) = mpsc::channel();
+        let (decide, decision) = mpsc::channel();
+        let backend = Arc::new(HeldBackend {
+            inner: MemoryBackend::new(),
+            first: Mutex::new(Some((started, decision))),
+        });
+        let keys = Arc::new(Keeper::generate());
+        let db = Arc::new(db::Index::new_for_testing());
+        let blob_index = Arc::new(BlobIndex::new(db, keys.clone()).unwrap());
+        let bs = BlobStore::new(blob_index, backend.clone(), 1024, keys);
+
+        let (sender, receiver) = mpsc::channel();
+        {
+            let store = |i: u8| {
+                // Each chunk fills most of a blob, so every store after the first seals a blob.
+                let chunk = randombytes::randombytes(500);
+                let sender = sender.clone();
+                bs.store(&chunk[..],
+                         hash::Hash::new(&chunk[..]),
+                         NodeType::Leaf,
+                         LeafType::FileChunk,
+                         None,
+                         Box::new(move |()| sender.send(i).unwrap()))
+                    .unwrap();
+            };
+            store(0);
+            store(1);
+            started_receiver.recv().unwrap();
+            // The upload of the first blob is stuck; the second blob is stored meanwhile, but not
+            // committed before the first one.
+            store(2);
+            while backend.inner.list().unwrap().is_empty() {
+                thread::sleep(Duration::from_millis(1));
+            }
+            assert!(receiver.try_recv().is_err());
+        }
+
+        decide.send(succeed).unwrap();
+        if bs.flush().is_err() {
+            // A failed upload holds back the blobs after it until it is retried.
+            assert!(!succeed);
+            assert!(receiver.try_recv().is_err());
+            bs.flush().unwrap();
+        }
+        let committed: Vec<u8> = receiver.try_iter().collect();
+        assert_eq!(vec![0, 1, 2], committed);
+    }
+}
@@ -0,0 +1,284 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Uploads sealed blobs to the backend from background threads.
+//!
+//! The blob store hands every full blob to a bounded queue and goes back to packing chunks, so it
+//! only waits for the backend when the queue is full. A blob is committed in the index, and the
+//! callbacks of its chunks are run, only once its upload is done; a crash before that leaves the
+//! blob "in air", with nothing referring to it.
+//!
+//! Uploads finish in any order, but blobs are committed strictly in the order they were queued:
+//! a blob waits for every earlier one to be stored, also for those that failed and wait for a
+//! retry. A tree node is stored after its children, so its hash is never committed before the
+//! blobs holding them are durable.
+
+use backend::{BackendError, StoreBackend};
+use blob::{BlobDesc, BlobIndex};
+use crypto::CipherText;
+use std::collections::BTreeMap;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::thread::{self, JoinHandle};
+use util::FnBox;
+
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct UploadConfig {
+    /// Number of blobs uploaded at the same time.
+    pub threads: usize,
+    /// Number of sealed blobs that can wait for an upload thread. Each of them holds a blob in
+    /// memory.
+    pub queue_depth: usize,
+}
+
+impl Default for UploadConfig {
+    fn default() -> UploadConfig {
+        UploadConfig {
+            threads: 2,
+            queue_depth: 2,
+        }
+    }
+}
+
+pub struct Upload {
+    pub desc: BlobDesc,
+    pub ct: CipherText,
+    /// Run once the blob is stored.
+    pub callbacks: Vec<Box<FnBox<(), ()>>>,
+}
+
+/// An upload with its place in the queue order.
+type Queued = (u64, Upload);
+
+struct State {
+    /// Uploads that are queued or running.
+    in_flight: usize,
+    /// Uploads that failed, kept to be queued again.
+    failed: Vec<(Queued, BackendError)>,
+    /// Stored uploads waiting for earlier ones before they are committed.
+    stored: BTreeMap<u64, Upload>,
+    /// The place of the next upload to be queued, and of the next one to be committed.
+    next_seq: u64,
+    next_commit: u64,
+    /// Number of upload threads that died while finishing an upload.
+    panics: usize,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    cvar: Condvar,
+    /// Held while committing, so that commits happen one at a time and in order.
+    committing: Mutex<()>,
+}
+
+pub struct Uploader {
+    sender: Option<SyncSender<Queued>>,
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl Uploader {
+    pub fn new<B: StoreBackend>(backend: Arc<B>,
+                                index: Arc<BlobIndex>,
+                                config: &UploadConfig)
+                                -> Uploader {
+        assert!(config.threads > 0);
+        let (sender, receiver) = mpsc::sync_channel(config.queue_depth);
+        let receiver = Arc::new(Mutex::new(receiver));
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                in_flight: 0,
+                failed: vec![],
+                stored: BTreeMap::new(),
+                next_seq: 0,
+                next_commit: 0,
+                panics: 0,
+            }),
+            cvar: Condvar::new(),
+            committing: Mutex::new(()),
+        });
+
+        let workers = (0..config.threads)
+            .map(|_| {
+                let backend = backend.clone();
+                let index = index.clone();
+                let receiver = receiver.clone();
+                let shared = shared.clone();
+                thread::spawn(move || upload_loop(&*backend, &index, &receiver, &shared))
+            })
+            .collect();
+
+        Uploader {
+            sender: Some(sender),
+            shared: shared,
+            workers: workers,
+        }
+    }
+
+    /// Give `upload` the next place in the queue order.
+    fn next(&self, upload: Upload) -> Queued {
+        let mut state = self.shared.state.lock().unwrap();
+        let seq = state.next_seq;
+        state.next_seq += 1;
+        (seq, upload)
+    }
+
+    /// Hand a blob to the upload threads. Blocks while the queue is full.
+    pub fn queue(&self, upload: Upload) {
+        let queued = self.next(upload);
+        self.send(queued);
+    }
+
+    fn send(&self, queued: Queued) {
+        self.shared.state.lock().unwrap().in_flight += 1;
+        self.sender
+            .as_ref()
+            .unwrap()
+            .send(queued)
+            .expect("Upload threads have stopped");
+    }
+
+    /// Wait until no uploads are queued or running, and return the error of a failed one.
+    pub fn wait(&self) -> Result<(), BackendError> {
+        let mut state = self.shared.state.lock().unwrap();
+        while state.in_flight > 0 {
+            state = self.shared.cvar.wait(state).unwrap();
+        }
+        match self.error_locked(&state) {
+            None => Ok(()),
+            Some(e) => Err(e),
+        }
+    }
+
+    /// The error of a failed upload, if any upload has failed since the last retry.
+    pub fn error(&self) -> Option<BackendError> {
+        self.error_locked(&self.shared.state.lock().unwrap())
+    }
+
+    fn error_locked(&self, state: &State) -> Option<BackendError> {
+        match state.failed.first() {
+            Some(&(_, ref e)) => Some(e.clone()),
+            None if state.panics > 0 => {
+                Some(BackendError::Other(format!("{} upload threads panicked", state.panics)))
+            }
+            None => None,
+        }
+    }
+
+    /// Keep a blob with the failed uploads without trying to upload it, to be queued with them
+    /// by the next retry.
+    pub fn park(&self, upload: Upload, e: BackendError) {
+        let queued = self.next(upload);
+        self.shared.state.lock().unwrap().failed.push((queued, e));
+    }
+
+    /// Queue the failed uploads again, in their original order.
+    pub fn retry_failed(&self) {
+        let mut failed = mem::replace(&mut self.shared.state.lock().unwrap().failed, vec![]);
+        failed.sort_by_key(|&((seq, _), _)| seq);
+        for (queued, _) in failed {
+            self.send(queued);
+        }
+    }
+}
+
+impl Drop for Uploader {
+    fn drop(&mut self) {
+        // Closing the queue stops the upload threads once they have emptied it.
+        self.sender = None;
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+        let state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+        for &((_, ref upload), ref e) in &state.failed {
+            warn!("Dropping blob {} that failed to upload: {}", upload.desc.id, e);
+        }
+        for upload in state.stored.values() {
+            warn!("Dropping blob {} that was stored after a failed one", upload.desc.id);
+        }
+    }
+}
+
+/// Counts an upload as done when dropped, also when the upload thread panics, so that `wait()`
+/// never waits for an upload that will not finish.
+struct InFlight<'a> {
+    shared: &'a Shared,
+}
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap_or_else(|e| e.into_inner());
+        if thread::panicking() {
+            state.panics += 1;
+        }
+        state.in_flight -= 1;
+        self.shared.cvar.notify_all();
+    }
+}
+
+/// Commit the stored uploads whose earlier uploads are all committed, in queue order.
+fn commit_in_order(index: &BlobIndex, shared: &Shared) {
+    let _committing = shared.committing.lock().unwrap_or_else(|e| e.into_inner());
+    loop {
+        let upload = {
+            let mut state = shared.state.lock().unwrap();
+            let seq = state.next_commit;
+            match state.stored.remove(&seq) {
+                Some(upload) => {
+                    state.next_commit += 1;
+                    upload
+                }
+                None => return,
+            }
+        };
+        index.commit_done(&upload.desc);
+        for callback in upload.callbacks {
+            callback.call(());
+        }
+    }
+}
+
+fn upload_loop<B: StoreBackend>(backend: &B,
+                                index: &BlobIndex,
+                                receiver: &Mutex<Receiver<Queued>>,
+                                shared: &Shared) {
+    loop {
+        // Only hold on to the queue while waiting for the next upload.
+        let next = receiver.lock().unwrap().recv();
+        let (seq, upload) = match next {
+            Ok(queued) => queued,
+            Err(_) => return,
+        };
+        let _in_flight = InFlight { shared: shared };
+
+        // A panicking backend fails the upload like any other error, so it can be retried.
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                backend.store(&upload.desc.name[..], &upload.ct)
+            }))
+            .unwrap_or_else(|_| {
+                Err(BackendError::Other(format!("Upload of blob {} panicked", upload.desc.id)))
+            });
+        match result {
+            Ok(()) => {
+                shared.state.lock().unwrap().stored.insert(seq, upload);
+                commit_in_order(index, shared);
+            }
+            Err(e) => shared.state.lock().unwrap().failed.push(((seq, upload), e)),
+        }
+    }
+}
@@ -0,0 +1,384 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Repository keys and the key file they are stored in.
+//!
+//! Every repository has its own master key pair. It is generated when the repository is
+//! initialized and is used to seal blob footers and blob names. Next to it, we keep a secret
+//! hashing key, so that chunk hashes do not reveal which plaintext a repository contains.
+//!
+//! On disk, the secret keys are wrapped with `secretbox` under a key derived from a passphrase.
+//! The passphrase can be changed by rewriting the key file; the keys themselves never change.
+//!
+//! A key file may also hold just the public key (and the hashing key). Such a write-only key is
+//! enough to commit new snapshots, but cannot read back anything stored in the repository.
+//!
+//! The master key pair can be rotated. While the blob footers are being resealed, the previous
+//! key pair is kept as the *retired* key, and everything sealed can be opened with either key.
+
+
+use capnp;
+use crypto::{CipherText, CipherTextRef, FixedKey, PlainText, PlainTextRef, authed, pwhash,
+             sealed};
+use errors::CryptoError;
+use root_capnp;
+use rustc_serialize::hex::ToHex;
+use sodiumoxide::crypto::scalarmult::curve25519;
+use sodiumoxide::randombytes::randombytes;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::RwLock;
+
+// Version 1 stored the secret key unprotected; it can still be read.
+const KEY_FILE_VERSION: u64 = 2;
+
+/// Work limits of the key derivation that protects key files. Tests create many key files, and
+/// use the cheapest limits allowed.
+#[cfg(not(test))]
+const WRAP_LIMITS: (u64, u64) = (pwhash::OPSLIMIT_MODERATE, pwhash::MEMLIMIT_MODERATE);
+#[cfg(test)]
+const WRAP_LIMITS: (u64, u64) = (pwhash::OPSLIMIT_MIN, pwhash::MEMLIMIT_MIN);
+
+/// Number of hash bytes in a public key fingerprint.
+const FINGERPRINT_BYTES: usize = 16;
+
+/// The key pair that every repository shared before repositories got keys of their own.
+const LEGACY_PUBLIC_KEY: [u8; 32] = [215, 136, 80, 128, 158, 109, 227, 141, 219, 63, 118, 91,
+                                     123, 97, 1, 97, 65, 237, 62, 171, 83, 159, 200, 11, 68, 138,
+                                     40, 82, 24, 47, 187, 29];
+const LEGACY_SECRET_KEY: [u8; 32] = [94, 13, 181, 81, 97, 87, 76, 37, 53, 92, 120, 232, 17, 126,
+                                     234, 78, 12, 23, 141, 61, 40, 10, 136, 127, 103, 192, 255,
+                                     193, 142, 154, 101, 35];
+
+
+pub struct Keeper {
+    master: RwLock<FixedKey>,
+    retired: RwLock<Option<FixedKey>>,
+    // Repositories created before keyed hashing was introduced have no hashing key.
+    hash_key: Option<Vec<u8>>,
+}
+
+impl Keeper {
+    pub fn new(master: FixedKey, hash_key: Option<Vec<u8>>) -> Keeper {
+        Keeper {
+            master: RwLock::new(master),
+            retired: RwLock::new(None),
+            hash_key: hash_key,
+        }
+    }
+
+    /// Generate a fresh master key pair and hashing key.
+    pub fn generate() -> Keeper {
+        let (pubkey, seckey) = sealed::imp::gen_keypair();
+        Keeper::new(FixedKey::new(pubkey, Some(seckey)),
+                    Some(randombytes(authed::hash::KEYBYTES)))
+    }
+
+    /// The keys of repositories created before they had a key file: the key pair that was built
+    /// into hat back then, and no hashing key.
+    pub fn legacy() -> Keeper {
+        let pubkey = sealed::desc::PublicKey::from_slice(&LEGACY_PUBLIC_KEY[..]).unwrap();
+        let seckey = sealed::desc::SecretKey::from_slice(&LEGACY_SECRET_KEY[..]).unwrap();
+        Keeper::new(FixedKey::new(pubkey, Some(seckey)), None)
+    }
+
+    pub fn master(&self) -> FixedKey {
+        self.master.read().unwrap().clone()
+    }
+
+    fn retired(&self) -> Option<FixedKey> {
+        self.retired.read().unwrap().clone()
+    }
+
+    pub fn hash_key(&self) -> Option<&[u8]> {
+        self.hash_key.as_ref().map(|k| &k[..])
+    }
+
+    /// A write-only copy of these keys.
+    pub fn public_only(&self) -> Keeper {
+        Keeper::new(FixedKey::new(self.master().public_key().clone(), None),
+                    self.hash_key.clone())
+    }
+
+    /// Whether we only hold the public key, i.e. can write but not read blobs.
+    pub fn is_write_only(&self) -> bool {
+        self.master().secret_key().is_none()
+    }
+
+    /// A fingerprint of the current public key, in hex.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(self.master().public_key())
+    }
+
+    /// A fingerprint of the retired public key, while a key rotation is in progress.
+    pub fn retired_fingerprint(&self) -> Option<String> {
+        self.retired().map(|retired| fingerprint(retired.public_key()))
+    }
+
+    /// Whether a key rotation has started, but not yet finished.
+    pub fn is_rotating(&self) -> bool {
+        self.retired.read().unwrap().is_some()
+    }
+
+    /// Replace the master key pair with a freshly generated one.
+    /// The old key pair is retired, but can still unseal until `finish_rotation()`.
+    pub fn rotate(&self) {
+        let (pubkey, seckey) = sealed::imp::gen_keypair();
+        let mut master = self.master.write().unwrap();
+        let mut retired = self.retired.write().unwrap();
+        assert!(retired.is_none(), "Key rotation already in progress");
+        *retired = Some(master.clone());
+        *master = FixedKey::new(pubkey, Some(seckey));
+    }
+
+    /// Forget the retired key pair.
+    pub fn finish_rotation(&self) {
+        *self.retired.write().unwrap() = None;
+    }
+
+    pub fn seal(&self, pt: PlainTextRef) -> CipherText {
+        self.master().seal(pt)
+    }
+
+    pub fn light_seal(&self, pt: PlainTextRef) -> CipherText {
+        self.master().light_seal(pt)
+    }
+
+    pub fn unseal<'a>(&self,
+                      ct: CipherTextRef<'a>)
+                      -> Result<(CipherTextRef<'a>, PlainText), CryptoError> {
+        match (self.master().unseal(ct), self.retired()) {
+            (Err(_), Some(retired)) => retired.unseal(ct),
+            (res, _) => res,
+        }
+    }
+
+    pub fn light_unseal(&self, ct: CipherTextRef) -> Result<PlainText, CryptoError> {
+        match (self.master().light_unseal(ct), self.retired()) {
+            (Err(_), Some(retired)) => retired.light_unseal(ct),
+            (res, _) => res,
+        }
+    }
+
+    /// Read keys from a key file previously written by `store()` or `store_public()`.
+    /// `passphrase` is only asked for if the key file needs one.
+    pub fn load(path: &Path, passphrase: &Fn() -> Option<String>) -> Result<Keeper, CryptoError> {
+        let mut buf = vec![];
+        fs::File::open(path)?.read_to_end(&mut buf)?;
+        Keeper::from_bytes(&buf[..], passphrase)
+    }
+
+    /// Write keys to a new key file readable only by its owner.
+    pub fn store(&self, path: &Path, passphrase: &str) -> Result<(), CryptoError> {
+        let bytes = self.as_bytes(Some(passphrase))?;
+        write_key_file(path, &bytes[..])
+    }
+
+    /// Write a write-only key file without the secret key.
+    pub fn store_public(&self, path: &Path) -> Result<(), CryptoError> {
+        let bytes = self.as_bytes(None)?;
+        write_key_file(path, &bytes[..])
+    }
+
+    fn as_bytes(&self, passphrase: Option<&str>) -> Result<Vec<u8>, CryptoError> {
+        let master = self.master();
+
+        let mut message = capnp::message::Builder::new_default();
+        {
+            let mut root = message.init_root::<root_capnp::key_file::Builder>();
+            root.set_version(KEY_FILE_VERSION);
+            root.set_public_key(&master.public_key().0[..]);
+
+            match passphrase {
+                None => {
+                    if let Some(ref hash_key) = self.hash_key {
+                        root.set_hash_key(&hash_key[..]);
+                    }
+                }
+                Some(passphrase) => {
+                    let seckey = master.secret_key()
+                        .ok_or("Cannot store a key file without secret key")?;
+
+                    // The hashing key is wrapped together with the secret key.
+                    let mut plain = seckey.0.to_vec();
+                    if let Some(ref hash_key) = self.hash_key {
+                        plain.extend_from_slice(&hash_key[..]);
+                    }
+                    wrap(&plain[..], passphrase, root.borrow().init_wrapped_secret_key())?;
+
+                    if let Some(retired) = self.retired() {
+                        let seckey = retired.secret_key()
+                            .ok_or("Cannot store a key file without secret key")?;
+                        root.set_retired_public_key(&retired.public_key().0[..]);
+                        wrap(&seckey.0[..],
+                             passphrase,
+                             root.borrow().init_wrapped_retired_secret_key())?;
+                    }
+                }
+            }
+        }
+        let mut out = Vec::new();
+        capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+        Ok(out)
+    }
+
+    fn from_bytes(bytes: &[u8],
+                  passphrase: &Fn() -> Option<String>)
+                  -> Result<Keeper, CryptoError> {
+        let reader = capnp::serialize_packed::read_message(&mut &bytes[..],
+                                                           capnp::message::ReaderOptions::new())?;
+        let root = reader.get_root::<root_capnp::key_file::Reader>()?;
+
+        let pubkey = sealed::desc::PublicKey::from_slice(root.get_public_key()?)
+            .ok_or("Key file has an invalid public key")?;
+
+        let (mut secret_bytes, retired) = match root.get_version() {
+            1 => (root.get_secret_key()?.to_vec(), None),
+            KEY_FILE_VERSION if root.has_wrapped_secret_key() => {
+                let passphrase =
+                    passphrase().ok_or("A passphrase is required to unlock the key file")?;
+                let secret_bytes = unwrap(root.get_wrapped_secret_key()?, &passphrase)?;
+
+                let retired = if root.has_wrapped_retired_secret_key() {
+                    let pubkey = sealed::desc::PublicKey::from_slice(root.get_retired_public_key()?)
+                        .ok_or("Key file has an invalid retired public key")?;
+                    let seckey_bytes = unwrap(root.get_wrapped_retired_secret_key()?,
+                                              &passphrase)?;
+                    Some(key_pair(pubkey, &seckey_bytes[..])?)
+                } else {
+                    None
+                };
+                (secret_bytes, retired)
+            }
+            KEY_FILE_VERSION => {
+                // Write-only key file.
+                let hash_key = if root.has_hash_key() {
+                    Some(root.get_hash_key()?.to_vec())
+                } else {
+                    None
+                };
+                return Ok(Keeper::new(FixedKey::new(pubkey, None), hash_key));
+            }
+            v => return Err(From::from(format!("Unsupported key file version: {}", v))),
+        };
+
+        // Anything following the secret key is the hashing key.
+        if secret_bytes.len() < sealed::desc::SECRETKEYBYTES {
+            return Err(From::from("Key file has an invalid secret key"));
+        }
+        let hash_key = secret_bytes.split_off(sealed::desc::SECRETKEYBYTES);
+        let hash_key = if hash_key.is_empty() { None } else { Some(hash_key) };
+
+        let keeper = Keeper::new(key_pair(pubkey, &secret_bytes[..])?, hash_key);
+        *keeper.retired.write().unwrap() = retired;
+        Ok(keeper)
+    }
+}
+
+/// Rewrite a key file without its retired key pair, once a key rotation has completed.
+/// The remaining keys are copied as-is, so this does not need the passphrase.
+pub fn remove_retired_key(path: &Path) -> Result<(), CryptoError> {
+    let mut buf = vec![];
+    fs::File::open(path)?.read_to_end(&mut buf)?;
+
+    let reader = capnp::serialize_packed::read_message(&mut &buf[..],
+                                                       capnp::message::ReaderOptions::new())?;
+    let old = reader.get_root::<root_capnp::key_file::Reader>()?;
+
+    let mut message = capnp::message::Builder::new_default();
+    {
+        let mut root = message.init_root::<root_capnp::key_file::Builder>();
+        root.set_version(old.get_version());
+        root.set_public_key(old.get_public_key()?);
+        if old.has_wrapped_secret_key() {
+            root.set_wrapped_secret_key(old.get_wrapped_secret_key()?)?;
+        }
+        if old.has_hash_key() {
+            root.set_hash_key(old.get_hash_key()?);
+        }
+    }
+    let mut out = Vec::new();
+    capnp::serialize_packed::write_message(&mut out, &message).unwrap();
+    write_key_file(path, &out[..])
+}
+
+fn fingerprint(pubkey: &sealed::desc::PublicKey) -> String {
+    authed::hash::new(&pubkey.0[..])[..FINGERPRINT_BYTES].to_hex()
+}
+
+fn key_pair(pubkey: sealed::desc::PublicKey, seckey: &[u8]) -> Result<FixedKey, CryptoError> {
+    let seckey = sealed::desc::SecretKey::from_slice(seckey)
+        .ok_or("Key file has an invalid secret key")?;
+
+    // Refuse key files where the two halves of the key pair do not belong together.
+    let derived = curve25519::scalarmult_base(&curve25519::Scalar(seckey.0));
+    if derived.0 != pubkey.0 {
+        return Err(From::from("Key file is corrupt: secret key does not match public key"));
+    }
+
+    Ok(FixedKey::new(pubkey, Some(seckey)))
+}
+
+fn wrap(plain: &[u8],
+        passphrase: &str,
+        mut wrapper: root_capnp::wrapped_key::Builder)
+        -> Result<(), CryptoError> {
+    let salt = pwhash::gen_salt();
+    let (opslimit, memlimit) = WRAP_LIMITS;
+    let alg = pwhash::ALG_ARGON2I13;
+    let wrap_key = pwhash::derive_key(passphrase.as_bytes(), &salt[..], opslimit, memlimit, alg)?;
+    let nonce = authed::imp::gen_nonce();
+
+    wrapper.set_salt(&salt[..]);
+    wrapper.set_ops_limit(opslimit);
+    wrapper.set_mem_limit(memlimit);
+    wrapper.set_algorithm(alg);
+    wrapper.set_nonce(&nonce.0[..]);
+    wrapper.set_cipher_text(&authed::imp::seal(plain, &nonce, &wrap_key)[..]);
+    Ok(())
+}
+
+fn unwrap(wrapper: root_capnp::wrapped_key::Reader,
+          passphrase: &str)
+          -> Result<Vec<u8>, CryptoError> {
+    let wrap_key = pwhash::derive_key(passphrase.as_bytes(),
+                                      wrapper.get_salt()?,
+                                      wrapper.get_ops_limit(),
+                                      wrapper.get_mem_limit(),
+                                      wrapper.get_algorithm())?;
+    let nonce = authed::desc::Nonce::from_slice(wrapper.get_nonce()?)
+        .ok_or("Key file has an invalid nonce")?;
+    let plain = authed::imp::open(wrapper.get_cipher_text()?, &nonce, &wrap_key)
+        .map_err(|()| "Wrong passphrase for key file")?;
+    Ok(plain)
+}
+
+/// Write a key file readable only by its owner.
+/// The file is written to a temporary name first, so a crash never leaves a partial key file.
+fn write_key_file(path: &Path, bytes: &[u8]) -> Result<(), CryptoError> {
+    let tmp_path = path.with_extension("tmp");
+    {
+        let mut fd = fs::OpenOptions::new().write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&tmp_path)?;
+        fd.write_all(bytes)?;
+        fd.sync_all()?;
+    }
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
@@ -20,6 +20,8 @@ use hash::tree::HashRef;
 use sodiumoxide::crypto::stream;
 use std::io;
 
+pub mod keys;
+
 pub struct PlainText(Vec<u8>);
 pub struct PlainTextRef<'a>(&'a [u8]);
 
@@ -27,6 +29,7 @@ pub struct CipherText {
     chunks: Vec<Vec<u8>>,
     len: usize,
 }
+#[derive(Clone, Copy)]
 pub struct CipherTextRef<'a>(&'a [u8]);
 
 
@@ -44,9 +47,15 @@ pub mod authed {
         use libsodium_sys::crypto_generichash_blake2b;
         pub use libsodium_sys::crypto_generichash_blake2b_BYTES_MAX as DIGESTBYTES;
 
+        // Recommended key size for keyed hashing (crypto_generichash_blake2b_KEYBYTES).
+        pub const KEYBYTES: usize = 32;
+
         pub fn new(text: &[u8]) -> Vec<u8> {
+            new_keyed(text, &[])
+        }
+
+        pub fn new_keyed(text: &[u8], key: &[u8]) -> Vec<u8> {
             let mut digest = vec![0; DIGESTBYTES];
-            let key = vec![];
             unsafe {
                 crypto_generichash_blake2b(digest.as_mut_ptr(),
                                            digest.len(),
@@ -60,6 +69,77 @@ pub mod authed {
     }
 }
 
+pub mod pwhash {
+    use errors::CryptoError;
+    use sodiumoxide::randombytes::randombytes;
+    use std::os::raw::{c_char, c_int, c_ulonglong};
+
+    pub const SALTBYTES: usize = 16;
+
+    // Argon2i, the default algorithm of libsodium's crypto_pwhash.
+    pub const ALG_ARGON2I13: i32 = 1;
+    pub const OPSLIMIT_MODERATE: u64 = 6;
+    pub const MEMLIMIT_MODERATE: u64 = 134217728;
+
+    // The limits are read back from key files, so they are kept between libsodium's minimum and
+    // its "sensitive" setting; a damaged key file must not make us allocate gigabytes.
+    pub const OPSLIMIT_MIN: u64 = 3;
+    pub const OPSLIMIT_MAX: u64 = 8;
+    pub const MEMLIMIT_MIN: u64 = 8192;
+    pub const MEMLIMIT_MAX: u64 = 536870912;
+
+    // The generic crypto_pwhash interface is not exposed by libsodium-sys.
+    extern "C" {
+        fn crypto_pwhash(out: *mut u8,
+                         outlen: c_ulonglong,
+                         passwd: *const c_char,
+                         passwdlen: c_ulonglong,
+                         salt: *const u8,
+                         opslimit: c_ulonglong,
+                         memlimit: usize,
+                         alg: c_int)
+                         -> c_int;
+    }
+
+    pub fn gen_salt() -> Vec<u8> {
+        randombytes(SALTBYTES)
+    }
+
+    pub fn derive_key(passphrase: &[u8],
+                      salt: &[u8],
+                      opslimit: u64,
+                      memlimit: u64,
+                      alg: i32)
+                      -> Result<::crypto::authed::desc::Key, CryptoError> {
+        if salt.len() != SALTBYTES {
+            return Err("crypto_pwhash: invalid salt length".into());
+        }
+        if opslimit < OPSLIMIT_MIN || opslimit > OPSLIMIT_MAX || memlimit < MEMLIMIT_MIN ||
+           memlimit > MEMLIMIT_MAX {
+            return Err(format!("crypto_pwhash: limits out of range (ops: {}, mem: {})",
+                               opslimit,
+                               memlimit)
+                .into());
+        }
+        let mut key = vec![0; ::crypto::authed::desc::KEYBYTES];
+        let ret = unsafe {
+            crypto_pwhash(key.as_mut_ptr(),
+                          key.len() as c_ulonglong,
+                          passphrase.as_ptr() as *const c_char,
+                          passphrase.len() as c_ulonglong,
+                          salt.as_ptr(),
+                          opslimit as c_ulonglong,
+                          memlimit as usize,
+                          alg as c_int)
+        };
+        if ret != 0 {
+            // Most likely we ran out of memory.
+            return Err("crypto_pwhash: key derivation failed".into());
+        }
+        Ok(::crypto::authed::desc::Key::from_slice(&key[..]).unwrap())
+    }
+}
+
 pub mod sealed {
     pub mod desc {
         pub use sodiumoxide::crypto::box_::curve25519xsalsa20poly1305::{MACBYTES, PUBLICKEYBYTES,
@@ -212,6 +292,9 @@ impl<'a> CipherTextRef<'a> {
     pub fn len(&self) -> usize {
         self.0.len()
     }
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
     pub fn split_from_right(&self,
                             len: usize)
                             -> Result<(CipherTextRef<'a>, CipherTextRef<'a>), CryptoError> {
@@ -283,6 +366,7 @@ impl RefKey {
     }
 }
 
+#[derive(Clone)]
 pub struct FixedKey {
     pubkey: sealed::desc::PublicKey,
     seckey: Option<sealed::desc::SecretKey>,
@@ -298,6 +382,14 @@ impl FixedKey {
         }
     }
 
+    pub fn public_key(&self) -> &sealed::desc::PublicKey {
+        &self.pubkey
+    }
+
+    pub fn secret_key(&self) -> Option<&sealed::desc::SecretKey> {
+        self.seckey.as_ref()
+    }
+
     pub fn light_seal(&self, pt: PlainTextRef) -> CipherText {
         pt.to_sealed_ciphertext(&self.pubkey)
     }
@@ -327,7 +419,9 @@ impl FixedKey {
     }
 
     pub fn light_unseal<'a>(&self, ct: CipherTextRef<'a>) -> Result<PlainText, CryptoError> {
-        let seckey = self.seckey.as_ref().expect("unseal requires access to read-key");
+        let seckey = self.seckey
+            .as_ref()
+            .ok_or("crypto read failed: write-only key cannot unseal")?;
         Ok(ct.to_sealed_plaintext(&self.pubkey, &seckey)?)
     }
 
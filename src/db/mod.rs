@@ -35,6 +35,38 @@ use util::{Counter, InfoWriter, PeriodicTimer};
 mod schema;
 
 
+/// Crash tests (see `hat::crash_tests`) can end the process right before or after any index
+/// commit.
+#[cfg(test)]
+pub mod crash {
+    use backend::CRASH_STATUS;
+    use std::process;
+    use std::sync::atomic::{ATOMIC_USIZE_INIT, AtomicUsize, Ordering};
+
+    static POINTS: AtomicUsize = ATOMIC_USIZE_INIT;
+    static CRASH_AT: AtomicUsize = ATOMIC_USIZE_INIT;
+
+    /// End the process at the `n`th crash point, counting from 1.
+    pub fn crash_at(n: usize) {
+        CRASH_AT.store(n, Ordering::SeqCst);
+    }
+
+    pub fn point() {
+        let n = POINTS.fetch_add(1, Ordering::SeqCst) + 1;
+        if n == CRASH_AT.load(Ordering::SeqCst) {
+            process::exit(CRASH_STATUS);
+        }
+    }
+}
+
+#[cfg(test)]
+fn crash_point() {
+    crash::point();
+}
+
+#[cfg(not(test))]
+fn crash_point() {}
+
 pub struct Index(Mutex<InternalIndex>);
 pub type IndexGuard<'a> = MutexGuard<'a, InternalIndex>;
 
@@ -95,6 +127,17 @@ fn decode_chunk_ref(cref: Option<&Vec<u8>>,
     })
 }
 
+fn list_entry(hash_: self::schema::Hash, blob_: Option<self::schema::Blob>) -> Entry {
+    Entry {
+        hash: self::hash::Hash { bytes: hash_.hash },
+        node: From::from(hash_.height),
+        leaf: From::from(hash_.leaf_type),
+        childs: hash_.childs.as_ref().map(|p| decode_childs(p).unwrap()),
+        persistent_ref: decode_chunk_ref(hash_.blob_ref.as_ref(), blob_),
+        ready: hash_.ready,
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct GcData {
     pub num: i64,
@@ -479,16 +522,24 @@ impl InternalIndex {
             .load::<(self::schema::Hash, Option<self::schema::Blob>)>(&self.conn)
             .expect("Error listing hashes")
             .into_iter()
-            .map(|(hash_, blob_)| {
-                Entry {
-                    hash: self::hash::Hash { bytes: hash_.hash },
-                    node: From::from(hash_.height),
-                    leaf: From::from(hash_.leaf_type),
-                    childs: hash_.childs.as_ref().map(|p| decode_childs(p).unwrap()),
-                    persistent_ref: decode_chunk_ref(hash_.blob_ref.as_ref(), blob_),
-                    ready: hash_.ready,
-                }
-            })
+            .map(|(hash_, blob_)| list_entry(hash_, blob_))
+            .collect()
+    }
+
+    /// The `limit` most recently added leaves of type `leaf`, newest first.
+    pub fn hash_list_recent_leaves(&mut self, leaf: blob::LeafType, limit: i64) -> Vec<Entry> {
+        use self::schema::hashes::dsl::*;
+        use self::schema::blobs::dsl::blobs;
+
+        hashes.left_outer_join(blobs)
+            .filter(height.eq(i64::from(blob::NodeType::Leaf)))
+            .filter(leaf_type.eq(i64::from(leaf)))
+            .order(id.desc())
+            .limit(limit)
+            .load::<(self::schema::Hash, Option<self::schema::Blob>)>(&self.conn)
+            .expect("Error listing hashes")
+            .into_iter()
+            .map(|(hash_, blob_)| list_entry(hash_, blob_))
             .collect()
     }
 
@@ -520,8 +571,10 @@ impl InternalIndex {
     }
 
     pub fn flush(&mut self) {
+        crash_point();
         let tm = self.conn.transaction_manager();
         tm.commit_transaction(&self.conn).unwrap();
+        crash_point();
         tm.begin_transaction(&self.conn).unwrap();
     }
 
@@ -573,6 +626,22 @@ impl InternalIndex {
             .expect("Error reading blob")
     }
 
+    /// The committed blob with the highest id, or `None` if there are no committed blobs.
+    pub fn blob_latest(&self) -> Option<blob::BlobDesc> {
+        use self::schema::blobs::dsl::*;
+        blobs.filter(tag.eq(tags::Tag::Done as i32))
+            .order(id.desc())
+            .first::<schema::Blob>(&self.conn)
+            .optional()
+            .expect("Error reading blob")
+            .map(|blob_| {
+                blob::BlobDesc {
+                    id: blob_.id,
+                    name: blob_.name,
+                }
+            })
+    }
+
     pub fn blob_set_tag(&self, tag_: tags::Tag, target: Option<&blob::BlobDesc>) {
         use self::schema::blobs::dsl::*;
         match target {
@@ -602,6 +671,14 @@ impl InternalIndex {
         };
     }
 
+    pub fn blob_retag(&self, from: tags::Tag, to: tags::Tag) {
+        use self::schema::blobs::dsl::*;
+        diesel::update(blobs.filter(tag.eq(from as i32)))
+            .set(tag.eq(to as i32))
+            .execute(&self.conn)
+            .expect("Error updating blob tags");
+    }
+
     pub fn blob_delete_by_tag(&self, tag_: tags::Tag) {
         use self::schema::blobs::dsl::*;
         diesel::delete(blobs.filter(tag.eq(tag_ as i32)))
@@ -625,6 +702,59 @@ impl InternalIndex {
             .collect()
     }
 
+    pub fn dictionary_next_id(&self) -> i64 {
+        use diesel::expression::max;
+        use self::schema::dictionaries::dsl::*;
+
+        1 +
+        dictionaries.select(max(id).nullable())
+            .first::<Option<i64>>(&self.conn)
+            .optional()
+            .expect("Error querying dictionaries")
+            .and_then(|x| x)
+            .unwrap_or(0)
+    }
+
+    pub fn dictionary_insert(&mut self, dict: &blob::BlobDesc) {
+        use self::schema::dictionaries::dsl::*;
+
+        let new = schema::NewDictionary {
+            id: dict.id,
+            name: &dict.name,
+        };
+        diesel::insert(&new)
+            .into(dictionaries)
+            .execute(&self.conn)
+            .expect("Error inserting dictionary");
+
+        self.flush();
+    }
+
+    pub fn dictionary_exists(&self, id_: i64) -> bool {
+        use self::schema::dictionaries::dsl::*;
+        dictionaries.find(id_)
+            .select(id)
+            .first::<i64>(&self.conn)
+            .optional()
+            .expect("Error reading dictionary")
+            .is_some()
+    }
+
+    pub fn dictionary_list(&self) -> Vec<blob::BlobDesc> {
+        use self::schema::dictionaries::dsl::*;
+        dictionaries.order(id.desc())
+            .load::<schema::Dictionary>(&self.conn)
+            .expect("Error listing dictionaries")
+            .into_iter()
+            .map(|dict| {
+                blob::BlobDesc {
+                    id: dict.id,
+                    name: dict.name,
+                }
+            })
+            .collect()
+    }
+
     pub fn last_insert_rowid(&self) -> i64 {
         diesel::select(diesel::expression::sql("last_insert_rowid()"))
             .first::<i64>(&self.conn)
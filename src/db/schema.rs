@@ -48,6 +48,13 @@ table! {
     }
 }
 
+table! {
+    dictionaries {
+        id -> BigInt,
+        name -> Binary,
+    }
+}
+
 table! {
     family {
         id -> BigInt,
@@ -140,6 +147,19 @@ pub struct NewBlob<'a> {
     pub tag: i32,
 }
 
+#[derive(Queryable)]
+pub struct Dictionary {
+    pub id: i64,
+    pub name: Vec<u8>,
+}
+
+#[derive(Insertable)]
+#[table_name="dictionaries"]
+pub struct NewDictionary<'a> {
+    pub id: i64,
+    pub name: &'a [u8],
+}
+
 #[derive(Queryable)]
 pub struct Family {
     pub id: i64,
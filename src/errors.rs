@@ -35,6 +35,7 @@ impl error::Error for RetryError {
 
 mod hat_error {
 
+    use backend;
     use blob;
     use capnp;
     use key;
@@ -72,6 +73,9 @@ mod hat_error {
             Blob(blob::BlobError) {
                 cause;
             },
+            Backend(backend::BackendError) {
+                cause;
+            },
         }
     }
 
@@ -105,7 +109,8 @@ mod diesel_error {
 }
 
 mod crypto_error {
-    use std::str;
+    use capnp;
+    use std::{io, str};
     use std::borrow::Cow;
 
     error_type! {
@@ -116,6 +121,12 @@ mod crypto_error {
                 from (s: &'static str) s.into();
                 from (s: String) s.into();
             },
+            IO(io::Error) {
+                cause;
+            },
+            DataSerialization(capnp::Error) {
+                cause;
+            },
         }
     }
 }
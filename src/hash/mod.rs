@@ -48,6 +48,12 @@ impl Hash {
     pub fn new(text: &[u8]) -> Hash {
         Hash { bytes: crypto::authed::hash::new(text) }
     }
+
+    /// Computes the keyed `hash(key, text)`.
+    /// Without the key, it is not possible to tell which plaintext a keyed hash belongs to.
+    pub fn new_keyed(key: &[u8], text: &[u8]) -> Hash {
+        Hash { bytes: crypto::authed::hash::new_keyed(text, key) }
+    }
 }
 
 
@@ -296,6 +302,11 @@ impl HashIndex {
         self.0.index.lock().hash_list()
     }
 
+    /// List the `limit` most recently added leaves of type `leaf`, newest first.
+    pub fn list_recent_leaves(&self, leaf: blob::LeafType, limit: usize) -> Vec<db::Entry> {
+        self.0.index.lock().hash_list_recent_leaves(leaf, limit as i64)
+    }
+
     /// Permanently delete hash by its ID.
     pub fn delete(&self, id: i64) {
         self.0.index.lock().hash_delete(id)
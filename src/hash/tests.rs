@@ -270,3 +270,16 @@ fn identity_1_short_of_flush() {
         assert_eq!(bytes, chunk);
     }
 }
+
+#[test]
+fn keyed_hash_depends_on_key() {
+    fn prop(text: Vec<u8>) -> bool {
+        let key1 = vec![1u8; 32];
+        let key2 = vec![2u8; 32];
+        let h1 = Hash::new_keyed(&key1[..], &text[..]);
+
+        h1 == Hash::new_keyed(&key1[..], &text[..]) &&
+        h1 != Hash::new_keyed(&key2[..], &text[..]) && h1 != Hash::new(&text[..])
+    }
+    quickcheck::quickcheck(prop as fn(Vec<u8>) -> bool);
+}
@@ -0,0 +1,678 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Repository configuration.
+//!
+//! The configuration is stored as JSON next to the key file. It names the backend holding the
+//! blobs, and holds the settings that every client of a repository must agree on, so it should
+//! be copied along with the key file. It also tunes how blobs are uploaded, which is up to each
+//! client.
+
+use backend::{self, CachingBackend, CommandBackend, ErasureBackend, FileBackend, MirrorBackend,
+              PackBackend, RetryBackend, RetryPolicy, S3Backend, SftpBackend, StoreBackend,
+              ThrottleLimits, ThrottledBackend};
+use blob;
+use errors::HatError;
+use key;
+use rustc_serialize::json;
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+
+/// Hash-tree fan-out and blob size used before they became configurable.
+pub const LEGACY_TREE_ORDER: usize = 8;
+pub const LEGACY_MAX_BLOB_SIZE: usize = 4 * 1024 * 1024;
+
+/// Directory levels of the file backend layout in new repositories.
+pub const DEFAULT_SHARD_LEVELS: usize = 2;
+/// Deepest file backend layout; each level spreads the blobs over 256 directories.
+pub const MAX_SHARD_LEVELS: usize = 4;
+
+/// Room left in a blob next to a chunk of maximal size, for its reference and the blob footer.
+const BLOB_OVERHEAD: usize = 64 * 1024;
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct ChunkingConfig {
+    /// Either "fixed" or "content-defined". Fixed chunking cuts chunks of `max_size` bytes.
+    pub method: String,
+    pub min_size: usize,
+    pub avg_size: usize,
+    pub max_size: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct BackendConfig {
+    /// The type of backend, e.g. "file".
+    pub kind: String,
+    /// Parameters for the backend, e.g. the "path" of a file backend.
+    pub params: BTreeMap<String, String>,
+    /// The backends a "mirror" backend copies blobs to, in the order to read from them, or that
+    /// an "erasure" backend spreads the shards of blobs over.
+    pub replicas: Option<Vec<BackendConfig>>,
+}
+
+/// Parse a number of directory levels for the file backend layout.
+pub fn parse_shard_levels(text: &str) -> Result<usize, String> {
+    match text.parse() {
+        Ok(levels) if levels <= MAX_SHARD_LEVELS => Ok(levels),
+        Ok(_) => Err(format!("{} is more than the maximum of {} levels", text, MAX_SHARD_LEVELS)),
+        Err(_) => Err(format!("{} is not a number", text)),
+    }
+}
+
+impl BackendConfig {
+    /// Blobs stored in the directory `path`; relative paths are relative to the repository.
+    pub fn file(path: &str) -> BackendConfig {
+        let mut params = BTreeMap::new();
+        params.insert("path".to_owned(), path.to_owned());
+        BackendConfig {
+            kind: "file".to_owned(),
+            params: params,
+            replicas: None,
+        }
+    }
+
+    fn param(&self, name: &str) -> Result<&str, HatError> {
+        match self.params.get(name) {
+            Some(value) => Ok(&value[..]),
+            None => {
+                Err(From::from(format!("The {} backend needs a '{}' parameter", self.kind, name)))
+            }
+        }
+    }
+
+    /// A parameter that can also be given in the environment, to keep it out of the config file.
+    fn secret_param(&self, name: &str, env_name: &str) -> Result<String, HatError> {
+        match self.params.get(name) {
+            Some(value) => Ok(value.clone()),
+            None => {
+                env::var(env_name).map_err(|_| {
+                    From::from(format!("The {} backend needs a '{}' parameter or {} in the \
+                                        environment",
+                                       self.kind,
+                                       name,
+                                       env_name))
+                })
+            }
+        }
+    }
+
+    /// Prepare a new backend for use, e.g. by creating its directory.
+    pub fn init(&self, repository_root: &Path) -> Result<(), HatError> {
+        match &self.kind[..] {
+            "file" | "pack" => Ok(fs::create_dir_all(repository_root.join(self.param("path")?))?),
+            "mirror" | "erasure" => {
+                for replica in self.replicas.iter().flat_map(|r| r) {
+                    replica.init(repository_root)?;
+                }
+                self.open(repository_root).map(|_| ())
+            }
+            // Check that the backend can be set up from the parameters we got.
+            _ => self.open(repository_root).map(|_| ()),
+        }
+    }
+
+    /// The backend of a "file" configuration. Without a "shard_levels" parameter, blobs are
+    /// stored in the flat layout of repositories from before sharding. A "previous_shard_levels"
+    /// parameter records the layout of an unfinished migration, whose blobs are found in both.
+    pub fn file_backend(&self, repository_root: &Path) -> Result<FileBackend, HatError> {
+        if self.kind != "file" {
+            return Err(From::from(format!("Not a file backend: {}", self.kind)));
+        }
+        let levels = self.shard_levels()?;
+        let path = repository_root.join(self.param("path")?);
+        match self.params.get("previous_shard_levels") {
+            Some(n) => {
+                let previous = parse_shard_levels(n)
+                    .map_err(|e| format!("Invalid previous_shard_levels: {}", e))?;
+                Ok(FileBackend::migrating(path, levels, previous))
+            }
+            None => Ok(FileBackend::with_shard_levels(path, levels)),
+        }
+    }
+
+    /// The directory levels of a "file" configuration's layout.
+    pub fn shard_levels(&self) -> Result<usize, HatError> {
+        match self.params.get("shard_levels") {
+            Some(n) => {
+                Ok(parse_shard_levels(n).map_err(|e| format!("Invalid shard_levels: {}", e))?)
+            }
+            None => Ok(0),
+        }
+    }
+
+    /// The backend of a "pack" configuration, which appends blobs to pack files of at most
+    /// "max_pack_size" bytes.
+    pub fn pack_backend(&self, repository_root: &Path) -> Result<PackBackend, HatError> {
+        let max_pack_size = match self.params.get("max_pack_size") {
+            Some(n) => n.parse().map_err(|_| format!("Invalid max_pack_size: {}", n))?,
+            None => backend::DEFAULT_MAX_PACK_SIZE,
+        };
+        Ok(PackBackend::new(self.pack_dir(repository_root)?, max_pack_size)?)
+    }
+
+    /// Rebuild the index of a "pack" configuration from its pack files, returning the number of
+    /// blobs found.
+    pub fn rebuild_pack_index(&self, repository_root: &Path) -> Result<usize, HatError> {
+        Ok(PackBackend::rebuild_index(&self.pack_dir(repository_root)?)?)
+    }
+
+    fn pack_dir(&self, repository_root: &Path) -> Result<PathBuf, HatError> {
+        if self.kind != "pack" {
+            return Err(From::from(format!("Not a pack backend: {}", self.kind)));
+        }
+        Ok(repository_root.join(self.param("path")?))
+    }
+
+    /// The backend of a "mirror" configuration. Stores succeed once "quorum" replicas have the
+    /// blob; by default, all of them.
+    pub fn mirror_backend(&self, repository_root: &Path) -> Result<MirrorBackend, HatError> {
+        if self.kind != "mirror" {
+            return Err(From::from(format!("Not a mirror backend: {}", self.kind)));
+        }
+        let replicas = self.open_replicas(repository_root)?;
+        let quorum = match self.params.get("quorum") {
+            Some(n) => n.parse().map_err(|_| format!("Invalid quorum: {}", n))?,
+            None => replicas.len(),
+        };
+        Ok(MirrorBackend::new(replicas, quorum)?)
+    }
+
+    /// The backend of an "erasure" configuration, which cuts blobs into "data_shards" shards
+    /// and adds a parity shard for every further replica.
+    pub fn erasure_backend(&self, repository_root: &Path) -> Result<ErasureBackend, HatError> {
+        if self.kind != "erasure" {
+            return Err(From::from(format!("Not an erasure backend: {}", self.kind)));
+        }
+        let data_shards = self.param("data_shards")?;
+        let data_shards = data_shards.parse()
+            .map_err(|_| format!("Invalid data_shards: {}", data_shards))?;
+        Ok(ErasureBackend::new(self.open_replicas(repository_root)?, data_shards)?)
+    }
+
+    /// Use `params` in place of the parameters of the same names of every replica, e.g. to limit
+    /// the bandwidth of repairs, which copy blobs between replicas, for this run only.
+    pub fn override_replica_params(&mut self, params: &BTreeMap<String, String>) {
+        for replica in self.replicas.iter_mut().flat_map(|r| r) {
+            replica.params.extend(params.iter().map(|(k, v)| (k.clone(), v.clone())));
+        }
+    }
+
+    fn open_replicas(&self, repository_root: &Path) -> Result<Vec<Box<StoreBackend>>, HatError> {
+        let mut replicas = vec![];
+        for replica in self.replicas.iter().flat_map(|r| r) {
+            replicas.push(replica.open(repository_root)?);
+        }
+        Ok(replicas)
+    }
+
+    /// The backend of this configuration. The "upload_limit" and "download_limit" parameters
+    /// limit its bandwidth (e.g. "5MiB/s"), and "request_limit" its requests per second. With a
+    /// "retries" parameter, operations that fail transiently are retried up to that many times,
+    /// for at most "retry_deadline" seconds. With a "cache_bytes" parameter, that many bytes of
+    /// recently read blobs are cached in memory, and with "cache_dir", up to "cache_dir_bytes"
+    /// more in that directory.
+    pub fn open(&self, repository_root: &Path) -> Result<Box<StoreBackend>, HatError> {
+        let mut backend = self.open_backend(repository_root)?;
+        let limits = ThrottleLimits {
+            upload: match self.params.get("upload_limit") {
+                Some(rate) => Some(backend::parse_byte_rate(rate)?),
+                None => None,
+            },
+            download: match self.params.get("download_limit") {
+                Some(rate) => Some(backend::parse_byte_rate(rate)?),
+                None => None,
+            },
+            requests: match self.params.get("request_limit") {
+                Some(rate) => Some(backend::parse_request_rate(rate)?),
+                None => None,
+            },
+        };
+        if limits != ThrottleLimits::default() {
+            // Retries count against the limits too, so throttle below them.
+            backend = Box::new(ThrottledBackend::new(backend, &limits));
+        }
+        if let Some(n) = self.params.get("retries") {
+            let mut policy = RetryPolicy::default();
+            policy.max_retries = n.parse().map_err(|_| format!("Invalid retries: {}", n))?;
+            if let Some(secs) = self.params.get("retry_deadline") {
+                let secs = secs.parse()
+                    .map_err(|_| format!("Invalid retry_deadline: {}", secs))?;
+                policy.deadline = Duration::from_secs(secs);
+            }
+            backend = Box::new(RetryBackend::new(backend, policy));
+        }
+        if let Some(n) = self.params.get("cache_bytes") {
+            let memory_bytes = n.parse().map_err(|_| format!("Invalid cache_bytes: {}", n))?;
+            backend = match self.params.get("cache_dir") {
+                None => Box::new(CachingBackend::new(backend, memory_bytes)),
+                Some(dir) => {
+                    let disk_bytes = self.param("cache_dir_bytes")?;
+                    let disk_bytes = disk_bytes.parse()
+                        .map_err(|_| format!("Invalid cache_dir_bytes: {}", disk_bytes))?;
+                    Box::new(CachingBackend::with_spill_dir(backend,
+                                                            memory_bytes,
+                                                            repository_root.join(dir),
+                                                            disk_bytes)?)
+                }
+            };
+        }
+        Ok(backend)
+    }
+
+    fn open_backend(&self, repository_root: &Path) -> Result<Box<StoreBackend>, HatError> {
+        match &self.kind[..] {
+            "file" => Ok(Box::new(self.file_backend(repository_root)?)),
+            "pack" => Ok(Box::new(self.pack_backend(repository_root)?)),
+            "mirror" => Ok(Box::new(self.mirror_backend(repository_root)?)),
+            "erasure" => Ok(Box::new(self.erasure_backend(repository_root)?)),
+            "command" => {
+                // The commands run in the repository directory unless told otherwise.
+                let dir = match self.params.get("dir") {
+                    Some(dir) => repository_root.join(dir),
+                    None => repository_root.to_path_buf(),
+                };
+                Ok(Box::new(CommandBackend::new(dir,
+                                                self.param("store")?.to_owned(),
+                                                self.param("retrieve")?.to_owned(),
+                                                self.param("delete")?.to_owned(),
+                                                self.param("list")?.to_owned())))
+            }
+            "s3" => {
+                let threshold = match self.params.get("multipart_threshold") {
+                    Some(n) => {
+                        n.parse().map_err(|_| format!("Invalid multipart_threshold: {}", n))?
+                    }
+                    None => backend::DEFAULT_MULTIPART_THRESHOLD,
+                };
+                let region = self.params.get("region").map_or("us-east-1", |r| &r[..]);
+                let prefix = self.params.get("prefix").map_or("", |p| &p[..]);
+                Ok(Box::new(S3Backend::new(self.param("endpoint")?,
+                                           region,
+                                           self.param("bucket")?,
+                                           prefix,
+                                           &self.secret_param("access_key", "AWS_ACCESS_KEY_ID")?,
+                                           &self.secret_param("secret_key",
+                                                              "AWS_SECRET_ACCESS_KEY")?,
+                                           threshold)?))
+            }
+            "sftp" => {
+                let port = match self.params.get("port") {
+                    Some(n) => n.parse().map_err(|_| format!("Invalid port: {}", n))?,
+                    None => 22,
+                };
+                let user = match self.params.get("user") {
+                    Some(user) => user.clone(),
+                    None => {
+                        env::var("USER").map_err(|_| "The sftp backend needs a 'user' parameter")?
+                    }
+                };
+                let known_hosts = match self.params.get("known_hosts") {
+                    Some(path) => PathBuf::from(path),
+                    None => {
+                        env::home_dir()
+                            .ok_or("The sftp backend needs a 'known_hosts' parameter")?
+                            .join(".ssh/known_hosts")
+                    }
+                };
+                Ok(Box::new(SftpBackend::new(self.param("host")?.to_owned(),
+                                             port,
+                                             user,
+                                             self.params.get("identity").map(PathBuf::from),
+                                             known_hosts,
+                                             PathBuf::from(self.param("path")?))))
+            }
+            other => Err(From::from(format!("Unknown backend type: {}", other))),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct Config {
+    pub backend: BackendConfig,
+    /// Number of children per node in the hash trees.
+    pub tree_order: usize,
+    pub chunking: ChunkingConfig,
+    pub max_blob_size: usize,
+    /// Number of threads uploading blobs to the backend.
+    pub upload_threads: usize,
+    /// Number of full blobs that can wait for an upload thread, held in memory.
+    pub upload_queue_depth: usize,
+    /// Fingerprint of the current public key of the repository. Clients holding another key
+    /// (e.g. a write-only key exported before the key was rotated) refuse to use it.
+    pub public_key_fingerprint: Option<String>,
+}
+
+impl Default for Config {
+    /// The configuration of new repositories.
+    fn default() -> Config {
+        Config::with_chunking(BackendConfig::file("blobs"), key::Chunking::default())
+    }
+}
+
+impl Config {
+    /// The configuration of repositories created before the configuration file existed, which
+    /// kept their blobs in a "blobs" directory next to the repository.
+    pub fn legacy() -> Config {
+        Config::with_chunking(BackendConfig::file("../blobs"),
+                              key::Chunking::Fixed(key::LEGACY_CHUNK_SIZE))
+    }
+
+    fn with_chunking(backend: BackendConfig, chunking: key::Chunking) -> Config {
+        Config {
+            backend: backend,
+            tree_order: LEGACY_TREE_ORDER,
+            max_blob_size: LEGACY_MAX_BLOB_SIZE,
+            upload_threads: blob::UploadConfig::default().threads,
+            upload_queue_depth: blob::UploadConfig::default().queue_depth,
+            public_key_fingerprint: None,
+            chunking: match chunking {
+                key::Chunking::Fixed(size) => {
+                    ChunkingConfig {
+                        method: "fixed".to_owned(),
+                        min_size: size,
+                        avg_size: size,
+                        max_size: size,
+                    }
+                }
+                key::Chunking::ContentDefined { min, avg, max } => {
+                    ChunkingConfig {
+                        method: "content-defined".to_owned(),
+                        min_size: min,
+                        avg_size: avg,
+                        max_size: max,
+                    }
+                }
+            },
+        }
+    }
+
+    pub fn chunking(&self) -> Result<key::Chunking, HatError> {
+        let c = &self.chunking;
+        match &c.method[..] {
+            "fixed" => Ok(key::Chunking::fixed(c.max_size)?),
+            "content-defined" => {
+                Ok(key::Chunking::content_defined(c.min_size, c.avg_size, c.max_size)?)
+            }
+            other => Err(From::from(format!("Unknown chunking method: {}", other))),
+        }
+    }
+
+    pub fn uploads(&self) -> blob::UploadConfig {
+        blob::UploadConfig {
+            threads: self.upload_threads,
+            queue_depth: self.upload_queue_depth,
+        }
+    }
+
+    /// Check that the settings can be used together.
+    pub fn validate(&self) -> Result<(), HatError> {
+        let chunking = self.chunking()?;
+        if self.tree_order < 2 {
+            return Err(From::from(format!("Invalid tree order: {}", self.tree_order)));
+        }
+        if self.max_blob_size < chunking.max_chunk_len() + BLOB_OVERHEAD {
+            return Err(From::from(format!("Blob size {} is too small for chunks of up to {} \
+                                           bytes",
+                                          self.max_blob_size,
+                                          chunking.max_chunk_len())));
+        }
+        if self.upload_threads < 1 {
+            return Err(From::from(format!("Invalid number of upload threads: {}",
+                                          self.upload_threads)));
+        }
+        if self.upload_queue_depth < 1 {
+            return Err(From::from(format!("Invalid upload queue depth: {}",
+                                          self.upload_queue_depth)));
+        }
+        Ok(())
+    }
+
+    /// Parse a configuration. A missing setting is an error rather than getting a default, as a
+    /// default could silently change how the repository is stored.
+    fn parse(text: &str) -> Result<Config, String> {
+        json::decode(text).map_err(|e| match e {
+            json::DecoderError::MissingFieldError(name) => format!("Missing setting '{}'", name),
+            e => e.to_string(),
+        })
+    }
+
+    /// Read and validate the configuration at `path`. The file is never written.
+    pub fn load(path: &Path) -> Result<Config, HatError> {
+        let mut text = String::new();
+        fs::File::open(path)?.read_to_string(&mut text)?;
+        let config = Config::parse(&text)
+            .map_err(|e| format!("Could not parse {}: {}", path.display(), e))?;
+
+        // Fail early on settings we cannot use.
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Write the configuration to `path`. It is written to a temporary file first and renamed
+    /// into place, so a crash never leaves a truncated configuration behind.
+    pub fn store(&self, path: &Path) -> Result<(), HatError> {
+        let tmp_path = path.with_extension("tmp");
+        {
+            let mut f = fs::File::create(&tmp_path)?;
+            writeln!(f, "{}", json::as_pretty_json(self))?;
+            f.sync_all()?;
+        }
+        fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use key;
+    use rustc_serialize::json;
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::{Read, Write};
+    use std::path::Path;
+    use util::TempDir;
+
+    #[test]
+    fn roundtrip() {
+        for config in vec![Config::default(), Config::legacy()] {
+            let text = json::as_pretty_json(&config).to_string();
+            assert_eq!(config, json::decode(&text).unwrap());
+        }
+    }
+
+    #[test]
+    fn missing_settings_are_rejected() {
+        let dir = TempDir::new("hat-config-test");
+        let path = dir.join("hat.config");
+        let config = Config::default();
+        let text = format!("{{\"chunking\": {}}}", json::as_json(&config.chunking));
+        fs::File::create(&path).unwrap().write_all(text.as_bytes()).unwrap();
+
+        match Config::load(&path) {
+            Err(e) => assert!(e.to_string().contains("Missing setting"), "{}", e),
+            Ok(config) => panic!("Loaded an incomplete configuration: {:?}", config),
+        }
+        let mut stored = String::new();
+        fs::File::open(&path).unwrap().read_to_string(&mut stored).unwrap();
+        assert_eq!(text, stored);
+    }
+
+    #[test]
+    fn override_replica_params() {
+        let mut mirror = BackendConfig {
+            kind: "mirror".to_owned(),
+            params: BTreeMap::new(),
+            replicas: Some(vec![BackendConfig::file("a"), BackendConfig::file("b")]),
+        };
+        let mut params = BTreeMap::new();
+        params.insert("upload_limit".to_owned(), "1MiB/s".to_owned());
+        mirror.override_replica_params(&params);
+
+        assert!(mirror.params.is_empty());
+        for replica in mirror.replicas.unwrap() {
+            assert_eq!(Some(&"1MiB/s".to_owned()), replica.params.get("upload_limit"));
+            assert!(replica.params.contains_key("path"));
+        }
+    }
+
+    #[test]
+    fn store_replaces_existing() {
+        let dir = TempDir::new("hat-config-test");
+        let path = dir.join("hat.config");
+        Config::legacy().store(&path).unwrap();
+        Config::default().store(&path).unwrap();
+        assert_eq!(Config::default(), Config::load(&path).unwrap());
+        assert!(!path.with_extension("tmp").exists());
+    }
+
+    #[test]
+    fn backend() {
+        let mut backend = BackendConfig::file("blobs");
+        assert!(backend.open(Path::new("repo")).is_ok());
+
+        backend.params.insert("shard_levels".to_owned(), "two".to_owned());
+        assert!(backend.open(Path::new("repo")).is_err());
+        backend.params.insert("shard_levels".to_owned(), "5".to_owned());
+        assert!(backend.open(Path::new("repo")).is_err());
+        backend.params.insert("shard_levels".to_owned(), "2".to_owned());
+        assert!(backend.open(Path::new("repo")).is_ok());
+        backend.params.insert("previous_shard_levels".to_owned(), "x".to_owned());
+        assert!(backend.open(Path::new("repo")).is_err());
+        backend.params.insert("previous_shard_levels".to_owned(), "0".to_owned());
+        assert!(backend.open(Path::new("repo")).is_ok());
+
+        backend.params.insert("retries".to_owned(), "3".to_owned());
+        backend.params.insert("retry_deadline".to_owned(), "soon".to_owned());
+        assert!(backend.open(Path::new("repo")).is_err());
+        backend.params.insert("retry_deadline".to_owned(), "60".to_owned());
+        assert!(backend.open(Path::new("repo")).is_ok());
+        backend.params.insert("cache_bytes".to_owned(), "64M".to_owned());
+        assert!(backend.open(Path::new("repo")).is_err());
+        backend.params.insert("cache_bytes".to_owned(), "67108864".to_owned());
+        assert!(backend.open(Path::new("repo")).is_ok());
+        backend.params.insert("upload_limit".to_owned(), "5 lightyears".to_owned());
+        assert!(backend.open(Path::new("repo")).is_err());
+        backend.params.insert("upload_limit".to_owned(), "5MiB/s".to_owned());
+        backend.params.insert("request_limit".to_owned(), "20/s".to_owned());
+        assert!(backend.open(Path::new("repo")).is_ok());
+
+        backend.params.clear();
+        assert!(backend.open(Path::new("repo")).is_err());
+
+        backend.kind = "command".to_owned();
+        assert!(backend.open(Path::new("repo")).is_err());
+        for cmd in vec!["store", "retrieve", "delete", "list"] {
+            backend.params.insert(cmd.to_owned(), "true".to_owned());
+        }
+        assert!(backend.open(Path::new("repo")).is_ok());
+
+        backend.kind = "s3".to_owned();
+        backend.params.clear();
+        assert!(backend.open(Path::new("repo")).is_err());
+        for &(name, value) in &[("endpoint", "http://localhost:9000"),
+                                ("bucket", "hat"),
+                                ("access_key", "key"),
+                                ("secret_key", "secret")] {
+            backend.params.insert(name.to_owned(), value.to_owned());
+        }
+        assert!(backend.open(Path::new("repo")).is_ok());
+
+        backend.kind = "sftp".to_owned();
+        backend.params.clear();
+        assert!(backend.open(Path::new("repo")).is_err());
+        for &(name, value) in &[("host", "localhost"),
+                                ("user", "hat"),
+                                ("known_hosts", "/dev/null"),
+                                ("path", "blobs")] {
+            backend.params.insert(name.to_owned(), value.to_owned());
+        }
+        assert!(backend.open(Path::new("repo")).is_ok());
+
+        backend.kind = "pack".to_owned();
+        backend.params.clear();
+        assert!(backend.open(Path::new("repo")).is_err());
+        let root = TempDir::new("hat-pack-config");
+        backend.params.insert("path".to_owned(), "packs".to_owned());
+        backend.init(root.path()).unwrap();
+        assert!(backend.open(root.path()).is_ok());
+        backend.params.insert("max_pack_size".to_owned(), "1G".to_owned());
+        assert!(backend.open(root.path()).is_err());
+        backend.params.insert("max_pack_size".to_owned(), "1073741824".to_owned());
+        assert!(backend.open(root.path()).is_ok());
+
+        backend.kind = "mirror".to_owned();
+        backend.params.clear();
+        assert!(backend.open(Path::new("repo")).is_err());
+        backend.replicas = Some(vec![BackendConfig::file("blobs"), BackendConfig::file("copy")]);
+        assert!(backend.open(Path::new("repo")).is_ok());
+        backend.params.insert("quorum".to_owned(), "3".to_owned());
+        assert!(backend.open(Path::new("repo")).is_err());
+        backend.params.insert("quorum".to_owned(), "1".to_owned());
+        assert!(backend.open(Path::new("repo")).is_ok());
+
+        backend.kind = "erasure".to_owned();
+        backend.params.clear();
+        assert!(backend.open(Path::new("repo")).is_err());
+        backend.params.insert("data_shards".to_owned(), "3".to_owned());
+        assert!(backend.open(Path::new("repo")).is_err());
+        backend.params.insert("data_shards".to_owned(), "1".to_owned());
+        assert!(backend.open(Path::new("repo")).is_ok());
+
+        backend.kind = "carrier-pigeon".to_owned();
+        assert!(backend.open(Path::new("repo")).is_err());
+    }
+
+    #[test]
+    fn validate() {
+        assert!(Config::default().validate().is_ok());
+        assert!(Config::legacy().validate().is_ok());
+
+        let mut config = Config::default();
+        config.tree_order = 1;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.max_blob_size = config.chunking.max_size;
+        assert!(config.validate().is_err());
+
+        let mut config = Config::default();
+        config.upload_threads = 0;
+        assert!(config.validate().is_err());
+        config.upload_threads = 4;
+        config.upload_queue_depth = 0;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn chunking() {
+        assert_eq!(key::Chunking::default(), Config::default().chunking().unwrap());
+        assert_eq!(key::Chunking::Fixed(key::LEGACY_CHUNK_SIZE),
+                   Config::legacy().chunking().unwrap());
+
+        let mut config = Config::default();
+        config.chunking.method = "magic".to_owned();
+        assert!(config.chunking().is_err());
+        config.chunking.method = "content-defined".to_owned();
+        config.chunking.min_size = 0;
+        assert!(config.chunking().is_err());
+    }
+}
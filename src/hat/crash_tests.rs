@@ -0,0 +1,235 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Crash tests: run a workload on a repository, crash it at every backend operation and around
+//! every index commit in turn, and check that the repository reopens in a consistent state.
+//!
+//! A crashed process must not run any more code, not even destructors, so every run happens in a
+//! child process. The test binary runs itself with `HAT_CRASH_DIR` set, and either
+//! `HAT_CRASH_AFTER` or `HAT_CRASH_AT_COMMIT`, which makes `crash_child` run the workload until
+//! its backend or its index ends the process. With `HAT_ERROR_AT`, one backend operation fails
+//! first, and the process ends as soon as the workload gives up on the error.
+
+use backend::{BackendError, CRASH_STATUS, Fault, FaultyBackend, StoreBackend};
+use db;
+use errors::HatError;
+use hat::{HatRc, init_repository, load_config};
+use hat::config::BackendConfig;
+use hat::tests::snapshot_files;
+use rustc_serialize::hex::ToHex;
+use std::collections::HashSet;
+use std::env;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::process::{self, Command};
+use std::sync::Arc;
+use tags;
+use util::TempDir;
+
+
+const FAMILY: &'static str = "crashing";
+
+fn passphrase() -> Option<String> {
+    Some("crash test".to_owned())
+}
+
+/// The files of the snapshot with id `snapshot_id`. Snapshots share some of their data, so
+/// deleting the first one leaves blobs that the second still uses.
+fn files(snapshot_id: i64) -> Vec<(&'static str, Vec<u8>)> {
+    let shared: Vec<u8> = (0..300000).map(|i| (i % 251) as u8).collect();
+    let mut files = vec![("shared", shared), ("dir/first", b"first".to_vec()), ("empty", vec![])];
+    match snapshot_id {
+        1 => files.push(("changing", vec![1; 100000])),
+        2 => {
+            files.push(("changing", vec![2; 200000]));
+            files.push(("dir/second", b"second".to_vec()));
+        }
+        _ => panic!("Unexpected snapshot: {}", snapshot_id),
+    }
+    files
+}
+
+/// Commit two snapshots, then delete the first one and collect the garbage it leaves.
+fn workload<B: StoreBackend>(hat: &mut HatRc<B>) -> Result<(), HatError> {
+    for id in 1..3 {
+        let mut family = hat.open_family(FAMILY.to_owned())?;
+        snapshot_files(&family, files(id))?;
+        family.flush()?;
+        hat.commit(&mut family, None)?;
+        hat.meta_commit()?;
+    }
+    hat.deregister_by_name(FAMILY.to_owned(), 1)?;
+    hat.gc()?;
+    hat.meta_commit()?;
+    hat.data_flush()
+}
+
+fn new_repository() -> TempDir {
+    let root = TempDir::new("hat-crash-test");
+    init_repository(root.path().to_path_buf(),
+                    BackendConfig::file("blobs"),
+                    &passphrase,
+                    &passphrase)
+        .unwrap();
+    root
+}
+
+fn open_backend(root: &PathBuf) -> Box<StoreBackend> {
+    load_config(root).unwrap().backend.open(root).unwrap()
+}
+
+fn read_file(path: &Path) -> Vec<u8> {
+    let mut contents = vec![];
+    fs::File::open(path)
+        .and_then(|mut f| f.read_to_end(&mut contents))
+        .expect(&format!("Could not read {}", path.display()));
+    contents
+}
+
+/// Reopen a crashed repository, which finishes whatever the crash interrupted, and check that
+/// the repository is consistent.
+fn check_recovery(root: &PathBuf) {
+    let backend = Arc::new(open_backend(root));
+    let mut hat = HatRc::open_repository(root.clone(), backend.clone(), &passphrase).unwrap();
+    assert!(hat.snapshot_index.list_not_done().is_empty());
+
+    let stored = || -> HashSet<Vec<u8>> {
+        backend.list().unwrap().into_iter().map(|name| name.into_vec()).collect()
+    };
+    // Blobs are only committed once they are stored.
+    let blobs = stored();
+    for blob in hat.blob_index.list_by_tag(tags::Tag::Done) {
+        assert!(blobs.contains(&blob.name), "Committed blob {} is missing", blob.id);
+    }
+
+    // Collecting garbage keeps every blob that a hash refers to.
+    hat.gc().unwrap();
+    let blobs = stored();
+    for entry in hat.hash_index.list() {
+        if let Some(cref) = entry.persistent_ref {
+            if cref.blob_id != Some(0) {
+                assert!(blobs.contains(&cref.blob_name),
+                        "Blob of hash {} is missing",
+                        entry.hash.bytes.to_hex());
+            }
+        }
+    }
+
+    // Every snapshot that survived reads back as it was taken.
+    let family = hat.open_family(FAMILY.to_owned()).unwrap();
+    for snapshot in hat.snapshot_index.list_all() {
+        if snapshot.family_name != FAMILY {
+            continue;
+        }
+        let id = snapshot.info.snapshot_id;
+        let dir_ref = match hat.snapshot_index.lookup(FAMILY, id) {
+            Some((_, _, Some(dir_ref))) => dir_ref,
+            _ => panic!("Snapshot {} has no tree", id),
+        };
+        let mut output = root.join(format!("checkout-{}", id));
+        hat.checkout_dir_ref(&family, &mut output, dir_ref).unwrap();
+        for (name, contents) in files(id) {
+            assert!(contents == read_file(&output.join(name)),
+                    "{} differs in snapshot {}",
+                    name,
+                    id);
+        }
+        fs::remove_dir_all(output).unwrap();
+    }
+}
+
+#[test]
+fn crash_recovery() {
+    // Count the operations of an uninterrupted run, to know where it can crash.
+    let operations = {
+        let dir = new_repository();
+        let root = dir.path().to_path_buf();
+        let backend = Arc::new(FaultyBackend::new(open_backend(&root)));
+        let mut hat = HatRc::open_repository(root, backend.clone(), &passphrase).unwrap();
+        workload(&mut hat).unwrap();
+        backend.operations()
+    };
+
+    for n in 0..operations {
+        let (dir, _) = run_child(&[("HAT_CRASH_AFTER", n)]);
+        check_recovery(&dir.path().to_path_buf());
+    }
+
+    // A failed upload must hold back the commits of the blobs uploaded after it, up to a crash.
+    for n in 0..operations {
+        let (dir, _) = run_child(&[("HAT_ERROR_AT", n), ("HAT_CRASH_AFTER", n + 3)]);
+        check_recovery(&dir.path().to_path_buf());
+    }
+
+    // Index commits are counted as they happen, until a run gets past all of them.
+    for n in 1.. {
+        let (dir, finished) = run_child(&[("HAT_CRASH_AT_COMMIT", n)]);
+        check_recovery(&dir.path().to_path_buf());
+        if finished {
+            break;
+        }
+    }
+}
+
+/// Run the workload on a new repository in a child process, with the crash points in `vars`.
+/// Returns the repository, and whether the run finished before it crashed.
+fn run_child(vars: &[(&str, usize)]) -> (TempDir, bool) {
+    let dir = new_repository();
+    let mut command = Command::new(env::current_exe().unwrap());
+    command.args(&["hat::crash_tests::crash_child", "--exact", "--nocapture"])
+        .env("HAT_CRASH_DIR", dir.path());
+    for &(var, n) in vars {
+        command.env(var, n.to_string());
+    }
+    let output = command.output().unwrap();
+    // The upload threads store blobs in any order, so a run may do fewer operations before a
+    // crash point than the uninterrupted run did, and finish before it.
+    if !output.status.success() && output.status.code() != Some(CRASH_STATUS) {
+        panic!("Run with {:?} failed: {}",
+               vars,
+               String::from_utf8_lossy(&output.stderr));
+    }
+    (dir, output.status.success())
+}
+
+/// One run of `crash_recovery`; does nothing when run by itself.
+#[test]
+fn crash_child() {
+    let root = match env::var("HAT_CRASH_DIR") {
+        Ok(dir) => PathBuf::from(dir),
+        Err(_) => return,
+    };
+    let mut backend = match env::var("HAT_CRASH_AFTER") {
+        Ok(n) => FaultyBackend::fail_after(open_backend(&root), n.parse().unwrap(), Fault::Crash),
+        Err(_) => FaultyBackend::new(open_backend(&root)),
+    };
+    let failing = env::var("HAT_ERROR_AT").is_ok();
+    if let Ok(n) = env::var("HAT_ERROR_AT") {
+        let error = BackendError::Transient("Connection reset".to_owned());
+        backend = backend.with_error_at(n.parse().unwrap(), error);
+    }
+    if let Ok(n) = env::var("HAT_CRASH_AT_COMMIT") {
+        db::crash::crash_at(n.parse().unwrap());
+    }
+    let run = HatRc::open_repository(root, Arc::new(backend), &passphrase)
+        .and_then(|mut hat| workload(&mut hat));
+    match run {
+        Ok(()) => (),
+        // Whatever the failed operation left behind must survive a crash.
+        Err(_) if failing => process::exit(CRASH_STATUS),
+        Err(e) => panic!("Workload failed: {}", e),
+    }
+}
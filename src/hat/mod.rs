@@ -16,6 +16,7 @@
 use backend::StoreBackend;
 use blob;
 use capnp;
+use crypto;
 use db;
 use errors::HatError;
 use filetime;
@@ -24,25 +25,37 @@ use hash;
 use key;
 use root_capnp;
 use snapshot;
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str;
 use std::sync::{Arc, mpsc};
 use tags;
 use util::{Process, FileIterator};
 use void::Void;
+use rustc_serialize::{Encodable, Encoder};
 use rustc_serialize::hex::ToHex;
 
+mod config;
 mod family;
 mod insert_path_handler;
 mod walker;
+pub use self::config::{BackendConfig, ChunkingConfig, Config, DEFAULT_SHARD_LEVELS,
+                       LEGACY_TREE_ORDER, parse_shard_levels};
 use self::family::Family;
 
 #[cfg(test)]
 mod tests;
+#[cfg(test)]
+mod crash_tests;
 #[cfg(all(test, feature = "benchmarks"))]
 mod benchmarks;
 
+/// Bounds on the number of tree listings used to train a compression dictionary.
+const DICTIONARY_MIN_SAMPLES: usize = 16;
+const DICTIONARY_MAX_SAMPLES: usize = 10000;
+/// Upper bound on the total size of the training samples; zstd needs only a few MB.
+const DICTIONARY_MAX_SAMPLE_BYTES: usize = 4 * 1024 * 1024;
 
 
 pub struct GcBackend {
@@ -110,6 +123,70 @@ impl gc::GcBackend for GcBackend {
 }
 
 
+/// Which snapshot of a family to check out.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnapshotChoice {
+    /// The most recent snapshot.
+    Latest,
+    /// The snapshot with this ID.
+    Id(i64),
+    /// The newest snapshot taken at or before this time, in seconds since the epoch.
+    Before(u64),
+}
+
+/// How far a snapshot has come.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SnapshotState {
+    InProgress,
+    Complete,
+    Deleting,
+    Deleted,
+    Recovering,
+}
+
+impl SnapshotState {
+    fn from_work_status(status: &db::SnapshotWorkStatus) -> SnapshotState {
+        match *status {
+            db::SnapshotWorkStatus::CommitInProgress => SnapshotState::InProgress,
+            db::SnapshotWorkStatus::CommitComplete => SnapshotState::Complete,
+            db::SnapshotWorkStatus::DeleteInProgress => SnapshotState::Deleting,
+            db::SnapshotWorkStatus::DeleteComplete => SnapshotState::Deleted,
+            db::SnapshotWorkStatus::RecoverInProgress => SnapshotState::Recovering,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            SnapshotState::InProgress => "in-progress",
+            SnapshotState::Complete => "complete",
+            SnapshotState::Deleting => "deleting",
+            SnapshotState::Deleted => "deleted",
+            SnapshotState::Recovering => "recovering",
+        }
+    }
+}
+
+impl Encodable for SnapshotState {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_str(self.name())
+    }
+}
+
+/// A snapshot as listed by `Hat::list_snapshots`.
+#[derive(Clone, Debug, PartialEq, RustcEncodable)]
+pub struct SnapshotListing {
+    pub family_name: String,
+    pub snapshot_id: i64,
+    pub state: SnapshotState,
+    /// When the snapshot was taken, in seconds since the epoch. Unknown until the snapshot tree
+    /// is written, and for snapshots of versions that did not record it.
+    pub timestamp: Option<u64>,
+    pub msg: Option<String>,
+    /// The top hash of the snapshot tree, in hex.
+    pub hash: Option<String>,
+}
+
+
 pub struct Hat<B: StoreBackend, G: gc::Gc<GcBackend>> {
     repository_root: Option<PathBuf>,
     families: Vec<Family<B>>,
@@ -120,6 +197,10 @@ pub struct Hat<B: StoreBackend, G: gc::Gc<GcBackend>> {
     blob_index: Arc<blob::BlobIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
     blob_max_size: usize,
+    uploads: blob::UploadConfig,
+    keys: Arc<crypto::keys::Keeper>,
+    chunking: key::Chunking,
+    tree_order: usize,
     gc: G,
 }
 
@@ -134,6 +215,263 @@ fn hash_index_name(root: PathBuf) -> String {
     concat_filename(root, "hash_index.sqlite3")
 }
 
+fn key_file_name(root: PathBuf) -> String {
+    concat_filename(root, "hat.key")
+}
+
+fn config_file_name(root: PathBuf) -> String {
+    concat_filename(root, "hat.config")
+}
+
+fn load_config(root: &PathBuf) -> Result<Config, HatError> {
+    let config_path = PathBuf::from(config_file_name(root.clone()));
+    if !config_path.exists() {
+        return Err(From::from(format!("Not an initialized hat repository: {} (see `hatbin init`)",
+                                      root.display())));
+    }
+    Config::load(&config_path)
+}
+
+/// Create a repository in `repository_root` that stores its blobs in `backend`.
+/// Directories holding a repository from before configuration files keep their original
+/// settings and key, and only have the backend recorded; `passphrase` unlocks such a key, while
+/// `new_passphrase` protects a newly created one.
+pub fn init_repository(repository_root: PathBuf,
+                       backend: BackendConfig,
+                       passphrase: &Fn() -> Option<String>,
+                       new_passphrase: &Fn() -> Option<String>)
+                       -> Result<(), HatError> {
+    let config_path = PathBuf::from(config_file_name(repository_root.clone()));
+    if config_path.exists() {
+        return Err(From::from(format!("Repository is already initialized: {}",
+                                      repository_root.display())));
+    }
+
+    let legacy = PathBuf::from(hash_index_name(repository_root.clone())).exists();
+    let mut config = if legacy {
+        Config::legacy()
+    } else {
+        Config::default()
+    };
+    config.backend = backend;
+    if !legacy && config.backend.kind == "file" {
+        // Blobs of new repositories go into a sharded layout, unless asked otherwise.
+        config.backend
+            .params
+            .entry("shard_levels".to_owned())
+            .or_insert_with(|| config::DEFAULT_SHARD_LEVELS.to_string());
+    }
+    config.validate()?;
+
+    let keys = if PathBuf::from(key_file_name(repository_root.clone())).exists() {
+        load_or_create_keys(&repository_root, passphrase)?
+    } else {
+        load_or_create_keys(&repository_root, new_passphrase)?
+    };
+    config.public_key_fingerprint = Some(keys.fingerprint());
+    config.backend.init(&repository_root)?;
+    config.store(&config_path)?;
+    Ok(())
+}
+
+/// Load the repository keys. Repositories from before key files keep using the key pair that was
+/// built into hat back then; it is moved into a new key file protected by `passphrase`.
+fn load_keys(root: &PathBuf,
+             passphrase: &Fn() -> Option<String>)
+             -> Result<crypto::keys::Keeper, HatError> {
+    let key_path = PathBuf::from(key_file_name(root.clone()));
+    if !key_path.exists() && PathBuf::from(hash_index_name(root.clone())).exists() {
+        let passphrase = passphrase().ok_or("A passphrase is required to protect the key of \
+                                             this repository")?;
+        let keys = crypto::keys::Keeper::legacy();
+        keys.store(&key_path, &passphrase)?;
+        return Ok(keys);
+    }
+    Ok(crypto::keys::Keeper::load(&key_path, passphrase)?)
+}
+
+/// Refuse keys other than the current keys of the repository, so that nothing gets sealed with a
+/// key that has since been rotated. Configurations from before key fingerprints learn the
+/// fingerprint from the first client that holds the secret key.
+fn check_key_fingerprint(root: &PathBuf,
+                         config: &Config,
+                         keys: &crypto::keys::Keeper)
+                         -> Result<(), HatError> {
+    let fingerprint = keys.fingerprint();
+    match config.public_key_fingerprint {
+        Some(ref current) if *current == fingerprint => return Ok(()),
+        // A rotation stopped after replacing the key file, but before recording the new key.
+        Some(ref current) if keys.retired_fingerprint().as_ref() == Some(current) => (),
+        Some(ref current) => {
+            return Err(From::from(format!("The key {} is not the current key {} of this \
+                                           repository; it may have been rotated since",
+                                          fingerprint,
+                                          current)))
+        }
+        None if keys.is_write_only() => return Ok(()),
+        None => (),
+    }
+    record_key_fingerprint(root, keys)
+}
+
+fn record_key_fingerprint(root: &PathBuf, keys: &crypto::keys::Keeper) -> Result<(), HatError> {
+    let config_path = PathBuf::from(config_file_name(root.clone()));
+    let mut config = Config::load(&config_path)?;
+    config.public_key_fingerprint = Some(keys.fingerprint());
+    config.store(&config_path)
+}
+
+fn load_or_create_keys(root: &PathBuf,
+                       passphrase: &Fn() -> Option<String>)
+                       -> Result<crypto::keys::Keeper, HatError> {
+    // Only a brand new repository is allowed to get fresh keys. Generating new keys for an
+    // existing index would leave every blob it references unreadable.
+    if PathBuf::from(key_file_name(root.clone())).exists() ||
+       PathBuf::from(hash_index_name(root.clone())).exists() {
+        return load_keys(root, passphrase);
+    }
+
+    let key_path = PathBuf::from(key_file_name(root.clone()));
+    let passphrase = passphrase().ok_or("A passphrase is required to create a repository")?;
+    fs::create_dir_all(root)?;
+    let keys = crypto::keys::Keeper::generate();
+    keys.store(&key_path, &passphrase)?;
+    Ok(keys)
+}
+
+/// Write the public half of the repository key to a new key file.
+/// Installed in place of the real key file, it allows committing new snapshots but not reading
+/// anything back from the repository.
+pub fn export_public_key(repository_root: PathBuf,
+                         passphrase: &Fn() -> Option<String>,
+                         output: &Path)
+                         -> Result<(), HatError> {
+    // The hashing key is protected by the passphrase, and write-only clients need it too.
+    let key_path = PathBuf::from(key_file_name(repository_root));
+    crypto::keys::Keeper::load(&key_path, passphrase)?.store_public(output)?;
+    Ok(())
+}
+
+/// All snapshots in the local index of the repository, ordered by family and snapshot ID.
+/// Only the index is read: this needs neither the key nor the backend, and leaves unfinished
+/// commands for the next time the repository is opened.
+pub fn list_snapshots(repository_root: PathBuf) -> Result<Vec<SnapshotListing>, HatError> {
+    load_config(&repository_root)?;
+    let db = Arc::new(db::Index::new(&hash_index_name(repository_root))?);
+    snapshot_listings(&mut snapshot::SnapshotIndex::new(db))
+}
+
+fn snapshot_listings(index: &mut snapshot::SnapshotIndex)
+                     -> Result<Vec<SnapshotListing>, HatError> {
+    let mut listings = vec![];
+    for snapshot in index.list_all() {
+        let timestamp = match snapshot.hash_ref {
+            Some(ref bytes) => {
+                hash::tree::HashRef::from_bytes(&mut &bytes[..])?
+                    .info
+                    .map(|info| info.hat_snapshot_ts)
+                    .and_then(|ts| if ts > 0 { Some(ts) } else { None })
+            }
+            None => None,
+        };
+        listings.push(SnapshotListing {
+            family_name: snapshot.family_name,
+            snapshot_id: snapshot.info.snapshot_id,
+            state: SnapshotState::from_work_status(&snapshot.status),
+            timestamp: timestamp,
+            msg: snapshot.msg,
+            hash: snapshot.hash.map(|h| h.bytes.to_hex()),
+        });
+    }
+    listings.sort_by(|a, b| (&a.family_name, a.snapshot_id).cmp(&(&b.family_name, b.snapshot_id)));
+    Ok(listings)
+}
+
+/// Protect the repository key with a new passphrase.
+/// Only the key file is rewritten; the key itself and therefore all blobs stay the same.
+pub fn change_passphrase(repository_root: PathBuf,
+                         passphrase: &Fn() -> Option<String>,
+                         new_passphrase: &str)
+                         -> Result<(), HatError> {
+    let key_path = PathBuf::from(key_file_name(repository_root));
+    let keys = crypto::keys::Keeper::load(&key_path, passphrase)?;
+    keys.store(&key_path, new_passphrase)?;
+    Ok(())
+}
+
+/// Move the blobs of a file backend to a layout with `shard_levels` directory levels, and
+/// return how many were moved. The new layout is recorded first, along with the old one until
+/// every blob has moved; blobs remain readable in either meanwhile, so an interrupted migration
+/// can simply be run again. The repository must not be in use meanwhile.
+pub fn migrate_blobs(repository_root: PathBuf, shard_levels: usize) -> Result<usize, HatError> {
+    let config_path = PathBuf::from(config_file_name(repository_root.clone()));
+    let mut config = load_config(&repository_root)?;
+    let current = config.backend.shard_levels()?;
+    if config.backend.params.contains_key("previous_shard_levels") {
+        if current != shard_levels {
+            return Err(From::from(format!("An earlier migration to {} shard levels is \
+                                           unfinished; run it again first",
+                                          current)));
+        }
+    } else if current != shard_levels {
+        config.backend.params.insert("previous_shard_levels".to_owned(), current.to_string());
+    }
+    config.backend.params.insert("shard_levels".to_owned(), shard_levels.to_string());
+    let backend = config.backend.file_backend(&repository_root)?;
+    config.store(&config_path)?;
+
+    let moved = backend.migrate()?;
+    if config.backend.params.remove("previous_shard_levels").is_some() {
+        config.store(&config_path)?;
+    }
+    Ok(moved)
+}
+
+/// Copy blobs that are missing on a replica of a mirror backend to that replica, and return how
+/// many copies were made. `params` override the parameters of every replica, as limits apply to
+/// the traffic with each of them.
+pub fn repair_mirror(repository_root: PathBuf,
+                     params: &BTreeMap<String, String>)
+                     -> Result<usize, HatError> {
+    let mut config = load_config(&repository_root)?.backend;
+    config.override_replica_params(params);
+    let backend = config.mirror_backend(&repository_root)?;
+    let copies = backend.repair()?;
+    backend.flush()?;
+    Ok(copies)
+}
+
+/// Rebuild lost, damaged and outdated shards of an erasure-coded backend. Returns the number of
+/// shards rebuilt, the number of blobs left degraded because a backend failed, and the number of
+/// blobs with too few shards left to rebuild them. `params` override the parameters of every
+/// replica, like for `repair_mirror`.
+pub fn scrub(repository_root: PathBuf,
+             params: &BTreeMap<String, String>)
+             -> Result<(usize, usize, usize), HatError> {
+    let mut config = load_config(&repository_root)?.backend;
+    config.override_replica_params(params);
+    let backend = config.erasure_backend(&repository_root)?;
+    let counts = backend.scrub()?;
+    backend.flush()?;
+    Ok(counts)
+}
+
+/// Rewrite the packs of a pack backend without the space of deleted blobs. Returns the number of
+/// bytes freed.
+pub fn compact_packs(repository_root: PathBuf) -> Result<u64, HatError> {
+    let config = load_config(&repository_root)?;
+    let backend = config.backend.pack_backend(&repository_root)?;
+    let freed = backend.compact()?;
+    backend.flush()?;
+    Ok(freed)
+}
+
+/// Recreate the index of a pack backend from its packs. Returns the number of blobs found.
+pub fn rebuild_pack_index(repository_root: PathBuf) -> Result<usize, HatError> {
+    let config = load_config(&repository_root)?;
+    config.backend.rebuild_pack_index(&repository_root)
+}
+
 fn synthetic_roots_family() -> String {
     From::from("__hat__roots__")
 }
@@ -186,18 +524,53 @@ fn list_snapshot<'a, B: StoreBackend>(backend: &'a key::HashStoreBackend<B>,
 }
 
 
+impl HatRc<Box<StoreBackend>> {
+    /// Open a repository with the backend named in its configuration.
+    pub fn open(repository_root: PathBuf,
+                passphrase: &Fn() -> Option<String>)
+                -> Result<HatRc<Box<StoreBackend>>, HatError> {
+        HatRc::open_with_params(repository_root, &BTreeMap::new(), passphrase)
+    }
+
+    /// Open a repository with the backend named in its configuration, with `params` in place of
+    /// the backend parameters of the same names (e.g. to limit bandwidth for this run only).
+    pub fn open_with_params(repository_root: PathBuf,
+                            params: &BTreeMap<String, String>,
+                            passphrase: &Fn() -> Option<String>)
+                            -> Result<HatRc<Box<StoreBackend>>, HatError> {
+        let mut config = load_config(&repository_root)?.backend;
+        config.params.extend(params.iter().map(|(k, v)| (k.clone(), v.clone())));
+        let backend = config.open(&repository_root)?;
+        HatRc::open_repository(repository_root, Arc::new(backend), passphrase)
+    }
+}
+
 impl<B: StoreBackend> HatRc<B> {
     pub fn open_repository(repository_root: PathBuf,
                            backend: Arc<B>,
-                           max_blob_size: usize)
+                           passphrase: &Fn() -> Option<String>)
                            -> Result<HatRc<B>, HatError> {
+        let config = load_config(&repository_root)?;
+        let keys = Arc::new(load_keys(&repository_root, passphrase)?);
+
         let hash_index_path = hash_index_name(repository_root.clone());
         let db_p = Arc::new(db::Index::new(&hash_index_path)?);
         let si_p = snapshot::SnapshotIndex::new(db_p.clone());
         let hi_p = Arc::new(hash::HashIndex::new(db_p.clone())?);
-        let bi_p = Arc::new(blob::BlobIndex::new(db_p.clone())?);
-
-        let bs_p = Arc::new(blob::BlobStore::new(bi_p.clone(), backend.clone(), max_blob_size));
+        let bi_p = Arc::new(blob::BlobIndex::new(db_p.clone(), keys.clone())?);
+
+        let bs_p = Arc::new(blob::BlobStore::with_uploads(bi_p.clone(),
+                                                          backend.clone(),
+                                                          config.max_blob_size,
+                                                          keys.clone(),
+                                                          &config.uploads()));
+
+        // Refuse to work with a key that cannot read the blobs we already know about.
+        // Write-only keys cannot read anything, so there is nothing to check them against.
+        if !keys.is_write_only() {
+            bs_p.verify_key()?;
+        }
+        check_key_fingerprint(&repository_root, &config, &keys)?;
 
         let gc_backend = GcBackend { hash_index: hi_p.clone() };
         let gc = gc::Gc::new(gc_backend);
@@ -211,7 +584,11 @@ impl<B: StoreBackend> HatRc<B> {
             backend: backend,
             blob_index: bi_p,
             blob_store: bs_p,
-            blob_max_size: max_blob_size,
+            blob_max_size: config.max_blob_size,
+            uploads: config.uploads(),
+            keys: keys,
+            chunking: config.chunking()?,
+            tree_order: config.tree_order,
             gc: gc,
         };
 
@@ -222,13 +599,19 @@ impl<B: StoreBackend> HatRc<B> {
     }
 
     #[cfg(test)]
-    pub fn new_for_testing(backend: Arc<B>, max_blob_size: usize) -> Result<HatRc<B>, HatError> {
+    pub fn new_for_testing(backend: Arc<B>,
+                           max_blob_size: usize,
+                           keys: Arc<crypto::keys::Keeper>)
+                           -> Result<HatRc<B>, HatError> {
         let db_p = Arc::new(db::Index::new_for_testing());
         let si_p = snapshot::SnapshotIndex::new(db_p.clone());
-        let bi_p = Arc::new(blob::BlobIndex::new(db_p.clone()).unwrap());
+        let bi_p = Arc::new(blob::BlobIndex::new(db_p.clone(), keys.clone()).unwrap());
         let hi_p = Arc::new(hash::HashIndex::new(db_p.clone()).unwrap());
 
-        let bs_p = Arc::new(blob::BlobStore::new(bi_p.clone(), backend.clone(), max_blob_size));
+        let bs_p = Arc::new(blob::BlobStore::new(bi_p.clone(),
+                                                 backend.clone(),
+                                                 max_blob_size,
+                                                 keys.clone()));
 
         let gc_backend = GcBackend { hash_index: hi_p.clone() };
         let gc = gc::Gc::new(gc_backend);
@@ -242,7 +625,11 @@ impl<B: StoreBackend> HatRc<B> {
             blob_index: bi_p,
             blob_store: bs_p,
             blob_max_size: max_blob_size,
+            uploads: blob::UploadConfig::default(),
             backend: backend,
+            keys: keys,
+            chunking: key::Chunking::default(),
+            tree_order: LEGACY_TREE_ORDER,
             gc: gc,
         };
 
@@ -255,7 +642,7 @@ impl<B: StoreBackend> HatRc<B> {
     pub fn hash_tree_writer(&self,
                             leaf: blob::LeafType)
                             -> hash::tree::SimpleHashTreeWriter<key::HashStoreBackend<B>> {
-        hash::tree::SimpleHashTreeWriter::new(leaf, 8, self.hash_backend())
+        hash::tree::SimpleHashTreeWriter::new(leaf, self.tree_order, self.hash_backend())
     }
 
     pub fn open_family(&mut self, name: String) -> Result<Family<B>, HatError> {
@@ -280,15 +667,25 @@ impl<B: StoreBackend> HatRc<B> {
         for _ in 0..2 {
             // To avoid mixing chunks from different files, each key store gets its own dedicated
             // blob store.
-            let bs = Arc::new(blob::BlobStore::new(self.blob_index.clone(),
-                                                   self.backend.clone(),
-                                                   self.blob_max_size));
-            kss.push(Process::new(key::Store::new(ki_p.clone(), self.hash_index.clone(), bs)));
+            let bs = Arc::new(blob::BlobStore::with_uploads(self.blob_index.clone(),
+                                                            self.backend.clone(),
+                                                            self.blob_max_size,
+                                                            self.keys.clone(),
+                                                            &self.uploads));
+            kss.push(Process::new(key::Store::new(ki_p.clone(),
+                                                  self.hash_index.clone(),
+                                                  bs,
+                                                  self.keys.clone(),
+                                                  self.chunking,
+                                                  self.tree_order)));
         }
 
         let ks = key::Store::new(ki_p.clone(),
                                  self.hash_index.clone(),
-                                 self.blob_store.clone());
+                                 self.blob_store.clone(),
+                                 self.keys.clone(),
+                                 self.chunking,
+                                 self.tree_order);
         kss.push(Process::new(ks.clone()));
 
         let family = Family {
@@ -383,7 +780,16 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(None)
     }
 
+    fn require_read_access(&self) -> Result<(), HatError> {
+        if self.keys.is_write_only() {
+            return Err(From::from("This repository was opened with a write-only key; reading \
+                                   requires the secret key"));
+        }
+        Ok(())
+    }
+
     pub fn recover(&mut self) -> Result<(), HatError> {
+        self.require_read_access()?;
         self.blob_store.recover()?;
         let root_href = self.recover_root()?.expect("Failed to find a commit-ed root.");
 
@@ -522,7 +928,7 @@ impl<B: StoreBackend> HatRc<B> {
                 self.hash_index.set_tag(id, tags::Tag::Reserved);
             }
         }
-        self.flush_blob_store();
+        self.flush_blob_store()?;
 
         let final_id = self.hash_index.get_id(&final_hash.hash).expect("final hash has no id");
         self.gc.register_final(&info, final_id)?;
@@ -530,7 +936,114 @@ impl<B: StoreBackend> HatRc<B> {
         Ok(())
     }
 
+    /// Replace the repository key pair and reseal all blob footers with the new key.
+    /// Chunk data is sealed with per-chunk keys, so only the footers have to be rewritten; blob
+    /// names are left as they are. If interrupted, `resume()` completes the rotation.
+    pub fn rotate_key(&mut self, passphrase: &str) -> Result<(), HatError> {
+        self.require_read_access()?;
+        if self.keys.is_rotating() {
+            return Err(From::from("A key rotation is already in progress"));
+        }
+
+        // Make sure every blob we know of has been committed.
+        self.data_flush()?;
+
+        // Tag the blobs to reseal before switching keys, so that an interrupted rotation can
+        // always find them again.
+        self.blob_index.retag(tags::Tag::Done, tags::Tag::KeyRotation);
+        self.blob_index.flush();
+
+        self.keys.rotate();
+        if let Some(ref root) = self.repository_root {
+            self.keys.store(&PathBuf::from(key_file_name(root.clone())), passphrase)?;
+            // Clients holding the old public key must not seal anything with it anymore.
+            record_key_fingerprint(root, &self.keys)?;
+        }
+
+        self.finish_key_rotation()
+    }
+
+    fn finish_key_rotation(&mut self) -> Result<(), HatError> {
+        let blobs = self.blob_index.list_by_tag(tags::Tag::KeyRotation);
+        info!("Resealing {} blobs with the new key", blobs.len());
+        for blob in blobs {
+            self.blob_store.reseal(&blob)?;
+            self.blob_index.tag(&blob, tags::Tag::Done);
+            self.blob_index.flush();
+        }
+        // Dictionaries are sealed like blob footers; resealing them twice is harmless.
+        self.blob_store.reseal_dictionaries()?;
+
+        // Every blob can be read with the new key now; the retired key can go.
+        if let Some(ref root) = self.repository_root {
+            crypto::keys::remove_retired_key(&PathBuf::from(key_file_name(root.clone())))?;
+        }
+        self.keys.finish_rotation();
+
+        Ok(())
+    }
+
+    /// Train a compression dictionary from the tree listings already in the repository.
+    /// New tree listings are packed with it; returns `None` if there are too few listings to
+    /// train from.
+    pub fn train_dictionary(&mut self) -> Result<Option<i64>, HatError> {
+        self.require_read_access()?;
+        self.data_flush()?;
+
+        // Group the sampled listings by blob, so that each blob is fetched and decrypted once.
+        let mut by_blob = BTreeMap::new();
+        let mut stored_bytes = 0;
+        let listings = self.hash_index
+            .list_recent_leaves(blob::LeafType::TreeList, DICTIONARY_MAX_SAMPLES);
+        for entry in listings {
+            if stored_bytes >= DICTIONARY_MAX_SAMPLE_BYTES {
+                break;
+            }
+            if let Some(cref) = entry.persistent_ref {
+                if cref.length == 0 {
+                    continue;
+                }
+                stored_bytes += cref.length;
+                by_blob.entry(cref.blob_name.clone())
+                    .or_insert_with(Vec::new)
+                    .push((entry.hash, cref));
+            }
+        }
+
+        let mut samples = vec![];
+        let mut sample_bytes = 0;
+        for (name, chunks) in by_blob {
+            if sample_bytes >= DICTIONARY_MAX_SAMPLE_BYTES {
+                break;
+            }
+            if let Some(listings) = self.blob_store.retrieve_from_blob(&name[..], &chunks[..])? {
+                for listing in listings {
+                    if sample_bytes >= DICTIONARY_MAX_SAMPLE_BYTES {
+                        break;
+                    }
+                    sample_bytes += listing.len();
+                    samples.push(listing);
+                }
+            }
+        }
+
+        if samples.len() < DICTIONARY_MIN_SAMPLES {
+            return Ok(None);
+        }
+        info!("Training compression dictionary from {} tree listings", samples.len());
+        Ok(Some(self.blob_store.train_dictionary(&samples)?))
+    }
+
     pub fn resume(&mut self) -> Result<(), HatError> {
+        if self.keys.is_rotating() {
+            println!("Resuming key rotation");
+            self.finish_key_rotation()?;
+        } else if !self.blob_index.list_by_tag(tags::Tag::KeyRotation).is_empty() {
+            // We never got to replace the key; nothing has been resealed.
+            self.blob_index.retag(tags::Tag::KeyRotation, tags::Tag::Done);
+            self.blob_index.flush();
+        }
+
         let need_work = self.snapshot_index.list_not_done();
 
         for snapshot in need_work {
@@ -710,7 +1223,7 @@ impl<B: StoreBackend> HatRc<B> {
         for family in &self.families {
             family.flush()?
         }
-        self.blob_store.flush();
+        self.blob_store.flush()?;
         self.meta_flush();
         Ok(())
     }
@@ -719,30 +1232,98 @@ impl<B: StoreBackend> HatRc<B> {
         self.snapshot_index.flush();
     }
 
-    pub fn flush_blob_store(&self) {
-        self.blob_store.flush();
+    pub fn flush_blob_store(&self) -> Result<(), HatError> {
+        self.blob_store.flush()?;
+        Ok(())
+    }
+
+    /// All snapshots in the local index, ordered by family and snapshot ID.
+    pub fn list_snapshots(&mut self) -> Result<Vec<SnapshotListing>, HatError> {
+        snapshot_listings(&mut self.snapshot_index)
     }
 
     pub fn checkout_in_dir(&mut self,
                            family_name: String,
                            output_dir: PathBuf)
                            -> Result<(), HatError> {
-        // Extract latest snapshot info:
-        let (_info, _dir_hash, dir_ref) = match self.snapshot_index.latest(&family_name) {
-            Some((i, h, Some(r))) => (i, h, r),
-            _ => {
-                panic!("Tried to checkout family '{}' before first completed commit",
-                       family_name)
-            }
-        };
+        self.checkout_snapshot_in_dir(family_name, SnapshotChoice::Latest, output_dir)
+    }
 
-        let family = self.open_family(family_name.clone())
-            .expect(&format!("Could not open family '{}'", family_name));
+    pub fn checkout_snapshot_in_dir(&mut self,
+                                    family_name: String,
+                                    choice: SnapshotChoice,
+                                    output_dir: PathBuf)
+                                    -> Result<(), HatError> {
+        self.require_read_access()?;
+
+        let dir_ref = self.find_snapshot(&family_name, choice)?;
+        let family = self.open_family(family_name)?;
 
         let mut output_dir = output_dir;
         self.checkout_dir_ref(&family, &mut output_dir, dir_ref)
     }
 
+    /// The tree of a completed snapshot of `family_name`.
+    fn find_snapshot(&mut self,
+                     family_name: &str,
+                     choice: SnapshotChoice)
+                     -> Result<hash::tree::HashRef, HatError> {
+        let found = match choice {
+            SnapshotChoice::Latest => self.snapshot_index.latest(family_name),
+            SnapshotChoice::Id(id) => self.snapshot_index.lookup(family_name, id),
+            SnapshotChoice::Before(ts) => {
+                // The top of each snapshot tree records when the snapshot was taken.
+                let mut best: Option<(u64, i64)> = None;
+                for snapshot in self.snapshot_index.list_all() {
+                    match snapshot.status {
+                        db::SnapshotWorkStatus::CommitComplete => (),
+                        _ => continue,
+                    }
+                    if snapshot.family_name != family_name {
+                        continue;
+                    }
+                    let taken = snapshot.hash_ref
+                        .and_then(|r| hash::tree::HashRef::from_bytes(&mut &r[..]).ok())
+                        .and_then(|r| r.info)
+                        .map(|info| info.hat_snapshot_ts);
+                    let key = match taken {
+                        Some(taken) if taken <= ts => (taken, snapshot.info.snapshot_id),
+                        _ => continue,
+                    };
+                    if best.map_or(true, |best| key > best) {
+                        best = Some(key);
+                    }
+                }
+                match best {
+                    Some((_, id)) => self.snapshot_index.lookup(family_name, id),
+                    None => {
+                        return Err(From::from(format!("Family '{}' has no snapshot from before \
+                                                       {}",
+                                                      family_name,
+                                                      ts)))
+                    }
+                }
+            }
+        };
+
+        match found {
+            Some((_, _, Some(dir_ref))) => Ok(dir_ref),
+            Some((info, _, None)) => {
+                Err(From::from(format!("Snapshot {} of family '{}' is not complete",
+                                       info.snapshot_id,
+                                       family_name)))
+            }
+            None => {
+                Err(From::from(match choice {
+                    SnapshotChoice::Id(id) => {
+                        format!("Family '{}' has no snapshot {}", family_name, id)
+                    }
+                    _ => format!("Family '{}' has no snapshots", family_name),
+                }))
+            }
+        }
+    }
+
     fn checkout_dir_ref(&self,
                         family: &Family<B>,
                         output: &mut PathBuf,
@@ -890,12 +1471,14 @@ impl<B: StoreBackend> HatRc<B> {
         // Anything still marked "in progress" is not referenced by any hash.
         self.blob_store.delete_by_tag(tags::Tag::InProgress)?;
         self.blob_store.tag_all(tags::Tag::Done);
-        self.blob_store.flush();
+        self.blob_store.flush()?;
 
         Ok((deleted_hashes, live_blobs))
     }
 
     fn hash_backend(&self) -> key::HashStoreBackend<B> {
-        key::HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone())
+        key::HashStoreBackend::new(self.hash_index.clone(),
+                                   self.blob_store.clone(),
+                                   self.keys.clone())
     }
 }
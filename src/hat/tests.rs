@@ -14,18 +14,32 @@
 
 
 use backend::{MemoryBackend, StoreBackend};
+use blob;
+use crypto::keys::Keeper;
+use db;
 use errors::HatError;
-use hat::HatRc;
+use hat::{HatRc, SnapshotChoice, SnapshotState, config_file_name, export_public_key,
+          init_repository, key_file_name, list_snapshots};
+use hat::config::{BackendConfig, Config};
 use hat::family::Family;
 use key;
-use std::collections::HashMap;
+use rustc_serialize::json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::io::Read;
+use std::path::PathBuf;
 use std::sync::Arc;
-use util::FileIterator;
+use tags;
+use util::{FileIterator, TempDir};
 
 
 pub fn setup_hat<B: StoreBackend>(backend: Arc<B>) -> HatRc<B> {
+    setup_hat_with_keys(backend, Arc::new(Keeper::generate()))
+}
+
+fn setup_hat_with_keys<B: StoreBackend>(backend: Arc<B>, keys: Arc<Keeper>) -> HatRc<B> {
     let max_blob_size = 4 * 1024 * 1024;
-    HatRc::new_for_testing(backend, max_blob_size).unwrap()
+    HatRc::new_for_testing(backend, max_blob_size, keys).unwrap()
 }
 
 fn setup_family() -> (Arc<MemoryBackend>, HatRc<MemoryBackend>, Family<MemoryBackend>) {
@@ -42,9 +56,9 @@ pub fn entry(name: Vec<u8>) -> key::Entry {
     key::Entry::new(None, name, None)
 }
 
-fn snapshot_files<B: StoreBackend>(family: &Family<B>,
-                                   files: Vec<(&str, Vec<u8>)>)
-                                   -> Result<(), HatError> {
+pub fn snapshot_files<B: StoreBackend>(family: &Family<B>,
+                                       files: Vec<(&str, Vec<u8>)>)
+                                       -> Result<(), HatError> {
     let mut dirs = HashMap::new();
     for (name, contents) in files {
         let mut parent = None;
@@ -217,6 +231,28 @@ fn snapshot_reuse_index() {
     assert_eq!(live, 0);
 }
 
+#[test]
+fn list_recent_tree_listings() {
+    let (_, mut hat, mut fam) = setup_family();
+
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.data_flush().unwrap();
+
+    let is_listing = |e: &db::Entry| {
+        e.leaf == blob::LeafType::TreeList && e.node == blob::NodeType::Leaf
+    };
+    let listings = hat.hash_index.list().into_iter().filter(|e| is_listing(e)).count();
+    assert!(listings > 5);
+
+    let recent = hat.hash_index.list_recent_leaves(blob::LeafType::TreeList, 5);
+    assert_eq!(5, recent.len());
+    assert!(recent.iter().all(|e| is_listing(e)));
+    let all = hat.hash_index.list_recent_leaves(blob::LeafType::TreeList, listings + 1);
+    assert_eq!(listings, all.len());
+}
+
 #[test]
 fn snapshot_gc() {
     let (_, mut hat, fam) = setup_family();
@@ -246,7 +282,7 @@ fn recover() {
     assert!(live1 > 0);
 
     // Create a new hat to wipe the index states.
-    let mut hat2 = setup_hat(backend);
+    let mut hat2 = setup_hat_with_keys(backend, hat.keys.clone());
 
     // Recover index states.
     hat2.recover().unwrap();
@@ -269,3 +305,298 @@ fn recover() {
     assert!(deleted > 0);
     assert_eq!(live4, 0);
 }
+
+#[test]
+fn recover_with_wrong_key_fails() {
+    let (backend, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // A hat with freshly generated keys cannot read the blob names.
+    let mut hat2 = setup_hat(backend);
+    assert!(hat2.recover().is_err());
+}
+
+#[test]
+fn write_only_key_can_commit_but_not_read() {
+    let backend = Arc::new(MemoryBackend::new());
+    let keys = Keeper::generate();
+
+    let mut hat = setup_hat_with_keys(backend.clone(), Arc::new(keys.public_only()));
+    let mut fam = hat.open_family("familyname".to_string()).unwrap();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // Reading anything back requires the secret key.
+    assert!(hat.checkout_in_dir("familyname".to_string(), PathBuf::from("/nonexistent"))
+        .is_err());
+    assert!(hat.recover().is_err());
+
+    // The full key can read what the write-only client committed.
+    let mut hat2 = setup_hat_with_keys(backend, Arc::new(keys));
+    hat2.recover().unwrap();
+}
+
+#[test]
+fn rotate_key() {
+    let (backend, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    let old_keys = Arc::new(Keeper::new(hat.keys.master(),
+                                        hat.keys.hash_key().map(|k| k.to_vec())));
+    hat.rotate_key("passphrase").unwrap();
+    assert!(!hat.keys.is_rotating());
+    assert!(hat.blob_index.list_by_tag(tags::Tag::KeyRotation).is_empty());
+
+    // The new key reads everything, including blobs named under the old key.
+    let mut hat2 = setup_hat_with_keys(backend.clone(), hat.keys.clone());
+    hat2.recover().unwrap();
+    let (deleted, live) = hat2.gc().unwrap();
+    assert_eq!(deleted, 0);
+    assert!(live > 0);
+
+    // The old key no longer can.
+    let mut hat3 = setup_hat_with_keys(backend, old_keys);
+    assert!(hat3.recover().is_err());
+}
+
+#[test]
+fn rotate_key_resumes_after_interruption() {
+    let (backend, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.data_flush().unwrap();
+
+    // Stop right after switching keys, before any blob has been resealed.
+    hat.blob_index.retag(tags::Tag::Done, tags::Tag::KeyRotation);
+    hat.keys.rotate();
+
+    hat.resume().unwrap();
+    assert!(!hat.keys.is_rotating());
+    assert!(hat.blob_index.list_by_tag(tags::Tag::KeyRotation).is_empty());
+
+    let mut hat2 = setup_hat_with_keys(backend, hat.keys.clone());
+    hat2.recover().unwrap();
+}
+
+#[test]
+fn rotate_key_through_key_file() {
+    let root = TempDir::new("hat-rotate-test");
+    let root_path = root.path().to_path_buf();
+    let passphrase = || Some("passphrase".to_owned());
+    let key_path = PathBuf::from(key_file_name(root_path.clone()));
+    let config_path = PathBuf::from(config_file_name(root_path.clone()));
+    let public_path = root.join("public.key");
+
+    init_repository(root_path.clone(),
+                    BackendConfig::file("blobs"),
+                    &passphrase,
+                    &passphrase)
+        .unwrap();
+    export_public_key(root_path.clone(), &passphrase, &public_path).unwrap();
+    let old = Keeper::load(&key_path, &passphrase).unwrap();
+    assert_eq!(Some(old.fingerprint()),
+               Config::load(&config_path).unwrap().public_key_fingerprint);
+    {
+        let mut hat = HatRc::open_with_params(root_path.clone(), &BTreeMap::new(), &passphrase)
+            .unwrap();
+        let mut fam = hat.open_family("familyname".to_string()).unwrap();
+        snapshot_files(&fam, vec![("file", b"contents".to_vec())]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.meta_commit().unwrap();
+        hat.data_flush().unwrap();
+
+        // Stop right after writing the new key file, before any blob has been resealed.
+        hat.blob_index.retag(tags::Tag::Done, tags::Tag::KeyRotation);
+        hat.blob_index.flush();
+        hat.keys.rotate();
+        hat.keys.store(&key_path, "passphrase").unwrap();
+    }
+
+    // Until the rotation is done, the key file also holds the retired key pair.
+    let rotating = Keeper::load(&key_path, &passphrase).unwrap();
+    assert!(rotating.is_rotating());
+    assert_eq!(Some(old.fingerprint()), rotating.retired_fingerprint());
+    assert!(rotating.fingerprint() != old.fingerprint());
+
+    // Opening the repository finishes the rotation, which removes the retired key pair.
+    {
+        let mut hat = HatRc::open_with_params(root_path.clone(), &BTreeMap::new(), &passphrase)
+            .unwrap();
+        let output = root.join("output");
+        hat.checkout_in_dir("familyname".to_string(), output.clone()).unwrap();
+        let mut contents = vec![];
+        fs::File::open(output.join("file")).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(b"contents".to_vec(), contents);
+    }
+    let rotated = Keeper::load(&key_path, &passphrase).unwrap();
+    assert!(!rotated.is_rotating());
+    assert_eq!(rotating.fingerprint(), rotated.fingerprint());
+    assert_eq!(Some(rotated.fingerprint()),
+               Config::load(&config_path).unwrap().public_key_fingerprint);
+
+    // The public key exported before the rotation can no longer be used.
+    fs::copy(&public_path, &key_path).unwrap();
+    assert!(HatRc::open_with_params(root_path, &BTreeMap::new(), &passphrase).is_err());
+}
+
+#[test]
+fn checkout_chosen_snapshot() {
+    let (_, mut hat, _) = setup_family();
+    for version in 1..3 {
+        let mut fam = hat.open_family("familyname".to_string()).unwrap();
+        snapshot_files(&fam, vec![("version", version.to_string().into_bytes())]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.meta_commit().unwrap();
+    }
+    hat.data_flush().unwrap();
+
+    let dir = TempDir::new("hat-checkout-test");
+    let output = dir.join("output");
+    {
+        let mut checkout = |choice| -> Result<String, HatError> {
+            hat.checkout_snapshot_in_dir("familyname".to_string(), choice, output.clone())?;
+            let mut version = String::new();
+            fs::File::open(output.join("version"))?.read_to_string(&mut version)?;
+            Ok(version)
+        };
+        assert_eq!("1", checkout(SnapshotChoice::Id(1)).unwrap());
+        assert_eq!("2", checkout(SnapshotChoice::Id(2)).unwrap());
+        assert_eq!("2", checkout(SnapshotChoice::Latest).unwrap());
+        assert_eq!("2", checkout(SnapshotChoice::Before(u64::max_value())).unwrap());
+
+        // Missing snapshots are errors.
+        let mut message = |choice| checkout(choice).unwrap_err().to_string();
+        assert!(message(SnapshotChoice::Id(3)).contains("has no snapshot 3"));
+        assert!(message(SnapshotChoice::Before(0)).contains("has no snapshot from before 0"));
+    }
+    let empty = hat.checkout_snapshot_in_dir("empty".to_string(),
+                                             SnapshotChoice::Latest,
+                                             output.clone());
+    assert!(empty.unwrap_err().to_string().contains("Family 'empty' has no snapshots"));
+    assert!(hat.checkout_in_dir("nonexistent".to_string(), output).is_err());
+}
+
+#[test]
+fn list_snapshots() {
+    let (_, mut hat, mut fam) = setup_family();
+    basic_snapshot(&fam);
+    fam.flush().unwrap();
+    hat.commit(&mut fam, None).unwrap();
+    hat.meta_commit().unwrap();
+    hat.snapshot_index.reserve("another".to_string());
+
+    let snapshots = hat.list_snapshots().unwrap();
+    assert_eq!(2, snapshots.len());
+
+    // Snapshots are ordered by family.
+    let pending = &snapshots[0];
+    assert_eq!("another", pending.family_name);
+    assert_eq!(1, pending.snapshot_id);
+    assert_eq!(SnapshotState::InProgress, pending.state);
+    assert_eq!(None, pending.timestamp);
+
+    let done = &snapshots[1];
+    assert_eq!("familyname", done.family_name);
+    assert_eq!(1, done.snapshot_id);
+    assert_eq!(SnapshotState::Complete, done.state);
+    assert!(done.timestamp.unwrap() > 0);
+    assert!(done.hash.is_some());
+
+    let encoded = json::encode(done).unwrap();
+    assert!(encoded.contains("\"state\":\"complete\""), "{}", encoded);
+}
+
+#[test]
+fn list_snapshots_without_key() {
+    let root = TempDir::new("hat-list-test");
+    let root_path = root.path().to_path_buf();
+    let passphrase = || Some("passphrase".to_owned());
+    assert!(list_snapshots(root_path.clone()).is_err());
+
+    init_repository(root_path.clone(),
+                    BackendConfig::file("blobs"),
+                    &passphrase,
+                    &passphrase)
+        .unwrap();
+    {
+        let mut hat = HatRc::open_with_params(root_path.clone(), &BTreeMap::new(), &passphrase)
+            .unwrap();
+        let mut fam = hat.open_family("familyname".to_string()).unwrap();
+        basic_snapshot(&fam);
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.meta_commit().unwrap();
+        hat.data_flush().unwrap();
+        hat.snapshot_index.reserve("another".to_string());
+    }
+
+    // Listing neither asks for the passphrase nor resumes the unfinished snapshot.
+    fs::remove_file(PathBuf::from(key_file_name(root_path.clone()))).unwrap();
+    let snapshots = list_snapshots(root_path).unwrap();
+    assert_eq!(vec![("another".to_owned(), SnapshotState::InProgress),
+                    ("familyname".to_owned(), SnapshotState::Complete)],
+               snapshots.into_iter().map(|s| (s.family_name, s.state)).collect::<Vec<_>>());
+}
+
+#[test]
+fn open_repository_without_key_file() {
+    let root = TempDir::new("hat-legacy-test");
+    let root_path = root.path().to_path_buf();
+    let passphrase = || Some("legacy".to_owned());
+    let key_path = PathBuf::from(key_file_name(root_path.clone()));
+    let config_path = PathBuf::from(config_file_name(root_path.clone()));
+
+    // Write a snapshot the way hat did before repositories had a key file and a configuration:
+    // with the built-in key pair, into blobs next to the index.
+    Keeper::legacy().store(&key_path, "legacy").unwrap();
+    let mut config = Config::legacy();
+    config.backend = BackendConfig::file("blobs");
+    config.store(&config_path).unwrap();
+    {
+        let mut hat = HatRc::open_with_params(root_path.clone(), &BTreeMap::new(), &passphrase)
+            .unwrap();
+        let mut fam = hat.open_family("familyname".to_string()).unwrap();
+        snapshot_files(&fam, vec![("legacy", b"contents".to_vec())]).unwrap();
+        fam.flush().unwrap();
+        hat.commit(&mut fam, None).unwrap();
+        hat.meta_commit().unwrap();
+        hat.data_flush().unwrap();
+    }
+    fs::remove_file(&key_path).unwrap();
+    fs::remove_file(&config_path).unwrap();
+
+    // Initializing the old repository moves the built-in key pair into a key file.
+    init_repository(root_path.clone(),
+                    BackendConfig::file("blobs"),
+                    &passphrase,
+                    &passphrase)
+        .unwrap();
+    assert!(key_path.exists());
+
+    let mut hat = HatRc::open_with_params(root_path.clone(), &BTreeMap::new(), &passphrase)
+        .unwrap();
+    let output = root.join("output");
+    hat.checkout_in_dir("familyname".to_string(), output.clone()).unwrap();
+    let mut contents = vec![];
+    fs::File::open(output.join("legacy")).unwrap().read_to_end(&mut contents).unwrap();
+    assert_eq!(b"contents".to_vec(), contents);
+}
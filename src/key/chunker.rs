@@ -0,0 +1,261 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Splitting file contents into chunks.
+//!
+//! Content-defined chunking places chunk boundaries where a rolling hash of the last few bytes
+//! hits a given pattern (FastCDC-style, with a gear hash). Inserting or removing bytes in a file
+//! therefore only changes the chunks around the edit, and the rest still deduplicates.
+
+use std::cmp;
+use std::io;
+
+
+/// The chunk size used before chunking became configurable.
+pub const LEGACY_CHUNK_SIZE: usize = 128 * 1024;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Chunking {
+    /// Chunks of exactly this size (except for the last chunk of a file).
+    Fixed(usize),
+    /// Chunks of at least `min` and at most `max` bytes, around `avg` bytes on average.
+    ContentDefined { min: usize, avg: usize, max: usize },
+}
+
+impl Default for Chunking {
+    fn default() -> Chunking {
+        Chunking::ContentDefined {
+            min: 32 * 1024,
+            avg: 128 * 1024,
+            max: 512 * 1024,
+        }
+    }
+}
+
+impl Chunking {
+    pub fn content_defined(min: usize, avg: usize, max: usize) -> Result<Chunking, String> {
+        if min == 0 || min > avg || avg > max {
+            return Err(format!("Invalid chunk sizes (need 0 < min <= avg <= max): {}, {}, {}",
+                               min,
+                               avg,
+                               max));
+        }
+        Ok(Chunking::ContentDefined {
+            min: min,
+            avg: avg,
+            max: max,
+        })
+    }
+
+    pub fn fixed(size: usize) -> Result<Chunking, String> {
+        if size == 0 {
+            return Err("Invalid chunk size: 0".into());
+        }
+        Ok(Chunking::Fixed(size))
+    }
+
+    pub fn max_chunk_len(&self) -> usize {
+        match *self {
+            Chunking::Fixed(size) => size,
+            Chunking::ContentDefined { max, .. } => max,
+        }
+    }
+}
+
+/// The gear table maps each byte to a pseudo-random 64-bit value. It must never change: every
+/// client of a repository has to find the same chunk boundaries to deduplicate.
+fn gear_table() -> Vec<u64> {
+    // SplitMix64 with a fixed seed.
+    let mut state = 0x6861_7420_6261_636bu64;
+    (0..256)
+        .map(|_| {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        })
+        .collect()
+}
+
+/// A mask of the `bits` highest bits; the gear hash mixes the most history into those.
+fn high_bits_mask(bits: u32) -> u64 {
+    let bits = cmp::max(1, cmp::min(63, bits));
+    !0u64 << (64 - bits)
+}
+
+pub struct Chunker<R> {
+    reader: R,
+    chunking: Chunking,
+    gear: Vec<u64>,
+    buf: Vec<u8>,
+    pos: usize,
+    len: usize,
+    eof: bool,
+}
+
+impl<R: io::Read> Chunker<R> {
+    pub fn new(reader: R, chunking: Chunking) -> Chunker<R> {
+        Chunker {
+            reader: reader,
+            chunking: chunking,
+            gear: match chunking {
+                Chunking::Fixed(..) => vec![],
+                Chunking::ContentDefined { .. } => gear_table(),
+            },
+            buf: vec![0; 2 * chunking.max_chunk_len()],
+            pos: 0,
+            len: 0,
+            eof: false,
+        }
+    }
+
+    /// Top up the buffer so that it holds a full chunk, unless the reader runs dry.
+    /// Like a short read, a read error ends the data.
+    fn fill(&mut self) {
+        let size = self.buf.len();
+        if size - self.pos < self.chunking.max_chunk_len() {
+            // Move the unconsumed data to the front; the buffer is twice the maximum chunk length,
+            // so this happens at most once per maximum chunk length of data.
+            self.buf.drain(..self.pos);
+            self.buf.resize(size, 0);
+            self.len -= self.pos;
+            self.pos = 0;
+        }
+        while !self.eof && self.len < size {
+            match self.reader.read(&mut self.buf[self.len..]) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                Ok(0) | Err(_) => self.eof = true,
+                Ok(n) => self.len += n,
+            }
+        }
+    }
+
+    fn cut_point(&self, data: &[u8]) -> usize {
+        let (min, avg, max) = match self.chunking {
+            Chunking::Fixed(size) => return cmp::min(size, data.len()),
+            Chunking::ContentDefined { min, avg, max } => (min, avg, max),
+        };
+        if data.len() <= min {
+            return data.len();
+        }
+
+        // Normalized chunking: boundaries are harder to hit before the average size and easier
+        // after it, which narrows the spread of chunk sizes.
+        let bits = (avg as f64).log2().round() as u32;
+        let mask_small = high_bits_mask(bits + 1);
+        let mask_large = high_bits_mask(bits.saturating_sub(1));
+
+        let limit = cmp::min(max, data.len());
+        let normal = cmp::min(avg, limit);
+
+        let mut hash = 0u64;
+        for i in min..limit {
+            hash = (hash << 1).wrapping_add(self.gear[data[i] as usize]);
+            let mask = if i < normal { mask_small } else { mask_large };
+            if hash & mask == 0 {
+                return i + 1;
+            }
+        }
+        limit
+    }
+
+    /// The next chunk of data, or `None` when the reader is exhausted.
+    pub fn next_chunk(&mut self) -> Option<&[u8]> {
+        if !self.eof && self.len - self.pos < self.chunking.max_chunk_len() {
+            self.fill();
+        }
+        if self.pos == self.len {
+            return None;
+        }
+
+        let start = self.pos;
+        let size = self.cut_point(&self.buf[start..self.len]);
+        self.pos += size;
+        Some(&self.buf[start..start + size])
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chunks(data: &[u8], chunking: Chunking) -> Vec<Vec<u8>> {
+        let mut chunker = Chunker::new(data, chunking);
+        let mut out = vec![];
+        while let Some(chunk) = chunker.next_chunk() {
+            out.push(chunk.to_vec());
+        }
+        out
+    }
+
+    fn pseudo_random(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len)
+            .map(|_| {
+                state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+                (state >> 56) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn fixed_chunks() {
+        let data = pseudo_random(10000, 1);
+        let out = chunks(&data[..], Chunking::Fixed(4096));
+        assert_eq!(vec![4096, 4096, 1808], out.iter().map(|c| c.len()).collect::<Vec<_>>());
+        assert_eq!(data, out.concat());
+    }
+
+    #[test]
+    fn content_defined_chunk_sizes() {
+        let chunking = Chunking::content_defined(256, 1024, 4096).unwrap();
+        let data = pseudo_random(200000, 2);
+        let out = chunks(&data[..], chunking);
+
+        assert_eq!(data, out.concat());
+        for chunk in &out[..out.len() - 1] {
+            assert!(chunk.len() >= 256 && chunk.len() <= 4096);
+        }
+        let avg = data.len() / out.len();
+        assert!(avg > 512 && avg < 2048, "average chunk size: {}", avg);
+    }
+
+    #[test]
+    fn content_defined_resyncs_after_insert() {
+        let chunking = Chunking::content_defined(256, 1024, 4096).unwrap();
+        let data = pseudo_random(100000, 3);
+        let mut edited = data.clone();
+        edited.insert(10, 42);
+
+        let before = chunks(&data[..], chunking);
+        let after = chunks(&edited[..], chunking);
+
+        // Only the chunks around the edit differ.
+        let shared = after.iter().filter(|c| before.contains(c)).count();
+        assert!(shared + 2 >= after.len(),
+                "{} of {} chunks shared",
+                shared,
+                after.len());
+    }
+
+    #[test]
+    fn invalid_sizes() {
+        assert!(Chunking::content_defined(0, 1024, 4096).is_err());
+        assert!(Chunking::content_defined(2048, 1024, 4096).is_err());
+        assert!(Chunking::content_defined(256, 8192, 4096).is_err());
+        assert!(Chunking::fixed(0).is_err());
+    }
+}
@@ -15,6 +15,7 @@
 
 use backend::StoreBackend;
 use blob;
+use crypto;
 use errors::RetryError;
 use hash;
 use hash::tree::HashTreeBackend;
@@ -25,23 +26,35 @@ use std::sync::{Arc, Mutex};
 pub struct HashStoreBackend<B> {
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
+    keys: Arc<crypto::keys::Keeper>,
 }
 impl<B> Clone for HashStoreBackend<B> {
     fn clone(&self) -> HashStoreBackend<B> {
         HashStoreBackend {
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
+            keys: self.keys.clone(),
         }
     }
 }
 
 impl<B: StoreBackend> HashStoreBackend<B> {
     pub fn new(hash_index: Arc<hash::HashIndex>,
-               blob_store: Arc<blob::BlobStore<B>>)
+               blob_store: Arc<blob::BlobStore<B>>,
+               keys: Arc<crypto::keys::Keeper>)
                -> HashStoreBackend<B> {
         HashStoreBackend {
             hash_index: hash_index,
             blob_store: blob_store,
+            keys: keys,
+        }
+    }
+
+    fn hash(&self, chunk: &[u8]) -> hash::Hash {
+        match self.keys.hash_key() {
+            Some(key) => hash::Hash::new_keyed(key, chunk),
+            // Repository from before keyed hashing; keep its hashes stable.
+            None => hash::Hash::new(chunk),
         }
     }
 
@@ -78,7 +91,7 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
         };
 
         Ok(data_opt.and_then(|data| {
-            let actual_hash = hash::Hash::new(&data[..]);
+            let actual_hash = self.hash(&data[..]);
             if *hash == actual_hash {
                 Some(data)
             } else {
@@ -116,7 +129,7 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                     info: Option<&key::Info>)
                     -> Result<(i64, hash::tree::HashRef), MsgError> {
         let mut hash_entry = hash::Entry {
-            hash: hash::Hash::new(chunk),
+            hash: self.hash(chunk),
             node: node,
             leaf: leaf,
             childs: childs,
@@ -157,7 +170,7 @@ impl<B: StoreBackend> HashTreeBackend for HashStoreBackend<B> {
                 });
 
                 let href = self.blob_store
-                    .store(chunk, hash_entry.hash.clone(), node, leaf, info, callback);
+                    .store(chunk, hash_entry.hash.clone(), node, leaf, info, callback)?;
 
                 // Update the hash entry now to enable reuse before the hash is fully committed.
                 hash_entry.persistent_ref = Some(href.persistent_ref.clone());
@@ -17,6 +17,7 @@
 
 use backend::StoreBackend;
 use blob;
+use crypto;
 use errors::{DieselError, RetryError};
 use hash;
 use hash::tree::{LeafIterator, SimpleHashTreeWriter};
@@ -29,12 +30,14 @@ use util::{FnBox, MsgHandler, Process};
 mod schema;
 mod index;
 mod hash_store_backend;
+mod chunker;
 
 #[cfg(test)]
 mod tests;
 #[cfg(all(test, feature = "benchmarks"))]
 mod benchmarks;
 
+pub use self::chunker::{Chunker, Chunking, LEGACY_CHUNK_SIZE};
 pub use self::hash_store_backend::HashStoreBackend;
 pub use self::index::{Entry, Info, KeyIndex};
 
@@ -68,11 +71,12 @@ pub struct HashTreeReaderInitializer<B> {
     hash_ref: hash::tree::HashRef,
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
+    keys: Arc<crypto::keys::Keeper>,
 }
 
 impl<B: StoreBackend> HashTreeReaderInitializer<B> {
     pub fn init(self) -> Result<Option<LeafIterator<HashStoreBackend<B>>>, MsgError> {
-        let backend = HashStoreBackend::new(self.hash_index, self.blob_store);
+        let backend = HashStoreBackend::new(self.hash_index, self.blob_store, self.keys);
         LeafIterator::new(backend, self.hash_ref.clone())
     }
 }
@@ -103,6 +107,9 @@ pub struct Store<B> {
     index: Arc<index::KeyIndex>,
     hash_index: Arc<hash::HashIndex>,
     blob_store: Arc<blob::BlobStore<B>>,
+    keys: Arc<crypto::keys::Keeper>,
+    chunking: Chunking,
+    tree_order: usize,
 }
 impl<B> Clone for Store<B> {
     fn clone(&self) -> Store<B> {
@@ -110,6 +117,9 @@ impl<B> Clone for Store<B> {
             index: self.index.clone(),
             hash_index: self.hash_index.clone(),
             blob_store: self.blob_store.clone(),
+            keys: self.keys.clone(),
+            chunking: self.chunking,
+            tree_order: self.tree_order,
         }
     }
 }
@@ -118,32 +128,43 @@ impl<B> Clone for Store<B> {
 impl<B: StoreBackend> Store<B> {
     pub fn new(index: Arc<index::KeyIndex>,
                hash_index: Arc<hash::HashIndex>,
-               blob_store: Arc<blob::BlobStore<B>>)
+               blob_store: Arc<blob::BlobStore<B>>,
+               keys: Arc<crypto::keys::Keeper>,
+               chunking: Chunking,
+               tree_order: usize)
                -> Store<B> {
         Store {
             index: index,
             hash_index: hash_index,
             blob_store: blob_store,
+            keys: keys,
+            chunking: chunking,
+            tree_order: tree_order,
         }
     }
 
     #[cfg(test)]
     pub fn new_for_testing(backend: Arc<B>, max_blob_size: usize) -> Result<Store<B>, DieselError> {
         use db;
+        use hat::LEGACY_TREE_ORDER;
+        let keys = Arc::new(crypto::keys::Keeper::generate());
         let db_p = Arc::new(db::Index::new_for_testing());
         let ki_p = Arc::new(index::KeyIndex::new_for_testing()?);
         let hi_p = Arc::new(hash::HashIndex::new(db_p.clone())?);
-        let blob_index = Arc::new(blob::BlobIndex::new(db_p)?);
-        let bs_p = Arc::new(blob::BlobStore::new(blob_index, backend, max_blob_size));
+        let blob_index = Arc::new(blob::BlobIndex::new(db_p, keys.clone())?);
+        let bs_p = Arc::new(blob::BlobStore::new(blob_index, backend, max_blob_size, keys.clone()));
         Ok(Store {
             index: ki_p,
             hash_index: hi_p,
             blob_store: bs_p,
+            keys: keys,
+            chunking: Chunking::default(),
+            tree_order: LEGACY_TREE_ORDER,
         })
     }
 
     pub fn flush(&mut self) -> Result<(), MsgError> {
-        self.blob_store.flush();
+        self.blob_store.flush()?;
         self.hash_index.flush();
         self.index.flush()?;
 
@@ -153,8 +174,10 @@ impl<B: StoreBackend> Store<B> {
     pub fn hash_tree_writer(&mut self,
                             leaf: blob::LeafType)
                             -> SimpleHashTreeWriter<HashStoreBackend<B>> {
-        let backend = HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone());
-        SimpleHashTreeWriter::new(leaf, 8, backend)
+        let backend = HashStoreBackend::new(self.hash_index.clone(),
+                                            self.blob_store.clone(),
+                                            self.keys.clone());
+        SimpleHashTreeWriter::new(leaf, self.tree_order, backend)
     }
 }
 
@@ -211,6 +234,7 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
                                     hash_ref: r.clone(),
                                     hash_index: self.hash_index.clone(),
                                     blob_store: self.blob_store.clone(),
+                                    keys: self.keys.clone(),
                                 }
                             });
 
@@ -265,24 +289,11 @@ impl<IT: io::Read, B: StoreBackend> MsgHandler<Msg<IT>, Reply<B>> for Store<B> {
 
                 // Read and insert all file chunks:
                 // (see HashStoreBackend::insert_chunk above)
-                let max_chunk_len = 128 * 1024;
-                let mut chunk = vec![0; max_chunk_len];
-                let mut reader = it_opt.unwrap();
+                let mut chunker = Chunker::new(it_opt.unwrap(), self.chunking);
                 let mut file_len = 0u64;
-                loop {
-                    let mut chunk_len = 0;
-                    while chunk_len < max_chunk_len {
-                        chunk_len += match reader.read(&mut chunk[chunk_len..]) {
-                            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
-                            Ok(0) | Err(_) => break,
-                            Ok(size) => size,
-                        }
-                    }
-                    if chunk_len == 0 {
-                        break;
-                    }
-                    file_len += chunk_len as u64;
-                    tree.append(&chunk[..chunk_len])?
+                while let Some(chunk) = chunker.next_chunk() {
+                    file_len += chunk.len() as u64;
+                    tree.append(chunk)?
                 }
 
                 // Warn the user if we did not read the expected size:
@@ -34,6 +34,11 @@ extern crate rustc_serialize;
 extern crate scoped_pool;
 extern crate void;
 extern crate filetime;
+extern crate flate2;
+extern crate snap;
+extern crate zstd;
+extern crate curl;
+extern crate ssh2;
 
 // Error definition macros.
 #[macro_use]
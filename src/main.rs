@@ -17,25 +17,162 @@ extern crate hat;
 
 // Rust crates.
 extern crate env_logger;
+extern crate rustc_serialize;
 extern crate sodiumoxide;
+extern crate time;
 
 // We use Clap for argument parsing.
 #[macro_use]
 extern crate clap;
 
 
-use clap::{App, SubCommand};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use rustc_serialize::json;
 
-use hat::backend;
 use std::borrow::ToOwned;
+use std::collections::BTreeMap;
 use std::convert::From;
+use std::env;
+use std::io::{self, Write};
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::process::{Command, Stdio};
 
-static MAX_BLOB_SIZE: usize = 4 * 1024 * 1024;
+/// The value of global argument `name`, given on the command or any of its subcommands.
+fn global_value<'a>(matches: &'a ArgMatches, name: &str) -> Option<&'a str> {
+    let mut value = matches.value_of(name);
+    let mut m = matches;
+    while let (_, Some(sub)) = m.subcommand() {
+        value = sub.value_of(name).or(value);
+        m = sub;
+    }
+    value
+}
+
+/// The repository named by `--repo` (on any subcommand) or `HAT_REPO`; by default "repo".
+fn repo_dir(matches: &ArgMatches) -> PathBuf {
+    match global_value(matches, "repo") {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(env::var("HAT_REPO").unwrap_or("repo".to_owned())),
+    }
+}
+
+/// Refuse limits for commands that only work on local files, where they would have no effect.
+fn reject_limits(command: &str, params: &BTreeMap<String, String>) {
+    if !params.is_empty() {
+        fail(&format!("Bandwidth and request limits do not apply to `{}`, which only works on \
+                       local files",
+                      command));
+    }
+}
+
+/// Backend parameters given on the command line, which override those in the configuration.
+fn backend_params(matches: &ArgMatches) -> BTreeMap<String, String> {
+    let mut params = BTreeMap::new();
+    for &(arg, param) in &[("upload-limit", "upload_limit"),
+                           ("download-limit", "download_limit"),
+                           ("request-limit", "request_limit")] {
+        if let Some(value) = global_value(matches, arg) {
+            params.insert(param.to_owned(), value.to_owned());
+        }
+    }
+    params
+}
+
+/// A backend of type `kind`, with parameters given as `KEY=VALUE` pairs.
+fn backend_config(kind: &str,
+                  params: Option<clap::Values>)
+                  -> Result<hat::hat::BackendConfig, clap::Error> {
+    let mut config = hat::hat::BackendConfig {
+        kind: kind.to_owned(),
+        params: BTreeMap::new(),
+        replicas: None,
+    };
+    for param in params.into_iter().flat_map(|p| p) {
+        let mut parts = param.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) => {
+                config.params.insert(key.to_owned(), value.to_owned());
+            }
+            _ => {
+                let msg = format!("Backend parameters must look like KEY=VALUE, got: {}", param);
+                return Err(clap::Error::with_description(&msg, clap::ErrorKind::InvalidValue));
+            }
+        }
+    }
+    Ok(config)
+}
 
-fn blob_dir() -> PathBuf {
-    PathBuf::from("blobs")
+/// A point in time given as seconds since the epoch, or as a UTC date with an optional time of
+/// day, e.g. "2017-03-01" or "2017-03-01 12:30:00".
+fn parse_timestamp(text: &str) -> Option<u64> {
+    if let Ok(secs) = text.parse() {
+        return Some(secs);
+    }
+    for format in &["%Y-%m-%d %H:%M:%S", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%d"] {
+        if let Ok(tm) = time::strptime(text, format) {
+            let secs = tm.to_timespec().sec;
+            if secs >= 0 {
+                return Some(secs as u64);
+            }
+        }
+    }
+    None
+}
+
+/// `secs` since the epoch as a UTC date and time, e.g. "2017-03-01T12:30:00Z".
+fn format_timestamp(secs: u64) -> String {
+    time::at_utc(time::Timespec::new(secs as i64, 0)).rfc3339().to_string()
+}
+
+fn prompt(message: &str) -> Option<String> {
+    write!(io::stderr(), "{}", message).unwrap();
+    io::stderr().flush().unwrap();
+
+    let mut line = String::new();
+    match io::stdin().read_line(&mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) => Some(line.trim_right_matches(&['\r', '\n'][..]).to_owned()),
+    }
+}
+
+/// Like `prompt`, but without echoing what is typed. Echo is turned off with `stty`, which fails
+/// harmlessly when stdin is not a terminal.
+fn prompt_hidden(message: &str) -> Option<String> {
+    let stty = |arg: &str| {
+        Command::new("stty").arg(arg).stderr(Stdio::null()).status().map_or(false, |s| s.success())
+    };
+    let hidden = stty("-echo");
+    let line = prompt(message);
+    if hidden {
+        stty("echo");
+        writeln!(io::stderr(), "").unwrap();
+    }
+    line
+}
+
+fn passphrase() -> Option<String> {
+    env::var("HAT_PASSPHRASE").ok().or_else(|| prompt_hidden("Passphrase: "))
+}
+
+fn new_passphrase() -> Option<String> {
+    if let Ok(p) = env::var("HAT_NEW_PASSPHRASE") {
+        return Some(p);
+    }
+    for _ in 0..3 {
+        let first = prompt_hidden("New passphrase: ");
+        let second = prompt_hidden("Repeat new passphrase: ");
+        if first.is_none() || first == second {
+            return first;
+        }
+        writeln!(io::stderr(), "Passphrases do not match, try again.").unwrap();
+    }
+    None
+}
+
+/// Print `message` and exit with a failure status.
+fn fail(message: &str) -> ! {
+    writeln!(io::stderr(), "{}", message).unwrap();
+    std::process::exit(1);
 }
 
 fn license() {
@@ -58,12 +195,68 @@ fn main() {
         .version(&format!("v{}", crate_version!())[..])
         .about("Create backup snapshots")
         .arg_from_usage("--license 'Display the license'")
+        .arg(Arg::with_name("repo")
+            .long("repo")
+            .value_name("PATH")
+            .takes_value(true)
+            .global(true)
+            .help("The repository to use (default: $HAT_REPO or 'repo')"))
+        .arg(Arg::with_name("upload-limit")
+            .long("upload-limit")
+            .value_name("RATE")
+            .takes_value(true)
+            .global(true)
+            .help("Limit uploads to the backend to this many bytes per second (e.g. 5MiB/s)"))
+        .arg(Arg::with_name("download-limit")
+            .long("download-limit")
+            .value_name("RATE")
+            .takes_value(true)
+            .global(true)
+            .help("Limit downloads from the backend to this many bytes per second"))
+        .arg(Arg::with_name("request-limit")
+            .long("request-limit")
+            .value_name("RATE")
+            .takes_value(true)
+            .global(true)
+            .help("Limit requests to the backend to this many per second (e.g. 10/s)"))
+        .subcommand(SubCommand::with_name("init")
+            .about("Create a new repository")
+            .args_from_usage("-b --backend=[KIND] 'Type of backend to store blobs in (default: \
+                              file)'
+                              [PARAM]... 'Backend parameters as KEY=VALUE (default for the file \
+                              backend: path=blobs)'"))
         .subcommand(SubCommand::with_name("commit")
             .about("Commit a new snapshot")
             .args_from_usage(arg_template))
         .subcommand(SubCommand::with_name("checkout")
-            .about("Checkout a snapshot")
-            .args_from_usage(arg_template))
+            .about("Checkout a snapshot (by default, the latest one)")
+            .args_from_usage(arg_template)
+            .arg(Arg::with_name("id")
+                .long("id")
+                .value_name("ID")
+                .takes_value(true)
+                .conflicts_with("before")
+                .validator(|id| {
+                    id.parse::<i64>().map(|_| ()).map_err(|_| {
+                        format!("The snapshot id must be a number, got: {}", id)
+                    })
+                })
+                .help("The snapshot id to checkout"))
+            .arg(Arg::with_name("before")
+                .long("before")
+                .value_name("TIME")
+                .takes_value(true)
+                .validator(|time| {
+                    parse_timestamp(&time)
+                        .map(|_| ())
+                        .ok_or_else(|| format!("Could not parse the time: {}", time))
+                })
+                .help("Checkout the newest snapshot taken at or before TIME, given in seconds \
+                       since the epoch or as a UTC date such as \"2017-03-01 12:30:00\"")))
+        .subcommand(SubCommand::with_name("list")
+            .about("List the snapshots of every family")
+            .args_from_usage("--family=[NAME] 'Only list the snapshots of this family'
+                              --json 'Print the snapshots as a JSON array'"))
         .subcommand(SubCommand::with_name("recover").about("Recover list of commit'ed snapshots"))
         .subcommand(SubCommand::with_name("delete")
             .about("Delete a snapshot")
@@ -74,6 +267,36 @@ fn main() {
             .about("Garbage collect: identify and remove unused data blocks.")
             .args_from_usage("-p --pretend 'Do not modify any data'"))
         .subcommand(SubCommand::with_name("resume").about("Resume previous failed command."))
+        .subcommand(SubCommand::with_name("train-dictionary")
+            .about("Train a compression dictionary for directory listings"))
+        .subcommand(SubCommand::with_name("migrate-blobs")
+            .about("Move the blobs of a file backend to a sharded directory layout, and remove \
+                    the temporary files of interrupted stores (run while the repository is not \
+                    in use)")
+            .arg(Arg::with_name("shard-levels")
+                .long("shard-levels")
+                .value_name("N")
+                .takes_value(true)
+                .validator(|n| hat::hat::parse_shard_levels(&n).map(|_| ()))
+                .help("Directory levels of the new layout (default: 2)")))
+        .subcommand(SubCommand::with_name("repair-mirror")
+            .about("Copy blobs that are missing on a replica of a mirror backend to that replica"))
+        .subcommand(SubCommand::with_name("scrub")
+            .about("Rebuild lost and damaged shards of an erasure-coded backend"))
+        .subcommand(SubCommand::with_name("compact")
+            .about("Free the space of deleted blobs in a pack backend (run while the repository \
+                    is not in use)"))
+        .subcommand(SubCommand::with_name("rebuild-index")
+            .about("Recreate the index of a pack backend from its packs"))
+        .subcommand(SubCommand::with_name("key")
+            .about("Manage the repository key")
+            .subcommand(SubCommand::with_name("change-passphrase")
+                .about("Protect the repository key with a new passphrase"))
+            .subcommand(SubCommand::with_name("rotate")
+                .about("Replace the repository key and reseal all blobs with the new key"))
+            .subcommand(SubCommand::with_name("export-public")
+                .about("Write a key file for write-only clients")
+                .args_from_usage("<PATH> 'Where to write the public key file'")))
         .get_matches();
 
     // Check for license flag
@@ -85,19 +308,34 @@ fn main() {
     // Initialize sodium (must only be called once)
     sodiumoxide::init();
 
+    let repo = repo_dir(&matches);
+    let params = backend_params(&matches);
+
     match matches.subcommand() {
+        ("init", Some(cmd)) => {
+            let backend = match (cmd.value_of("backend"), cmd.values_of("PARAM")) {
+                (None, None) => hat::hat::Config::default().backend,
+                (kind, params) => {
+                    backend_config(kind.unwrap_or("file"), params).unwrap_or_else(|e| e.exit())
+                }
+            };
+            if let Err(e) = hat::hat::init_repository(repo.clone(),
+                                                      backend,
+                                                      &passphrase,
+                                                      &new_passphrase) {
+                fail(&format!("Could not initialize {}: {}", repo.display(), e));
+            }
+            println!("Initialized repository in {}", repo.display());
+        }
         ("resume", Some(_cmd)) => {
             // Setting up the repository triggers automatic resume.
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE).unwrap();
+            hat::Hat::open_with_params(repo.clone(), &params, &passphrase).unwrap();
         }
         ("commit", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let path = cmd.value_of("PATH").unwrap();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            let mut hat = hat::Hat::open_with_params(repo.clone(), &params, &passphrase).unwrap();
 
             // Update the family index.
             let mut family = hat.open_family(name.clone())
@@ -116,17 +354,48 @@ fn main() {
         ("checkout", Some(cmd)) => {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let path = cmd.value_of("PATH").unwrap();
+            // Clap has validated both flags and rejects them together.
+            let choice = match (cmd.value_of("id"), cmd.value_of("before")) {
+                (Some(id), _) => hat::hat::SnapshotChoice::Id(id.parse().unwrap()),
+                (None, Some(before)) => {
+                    hat::hat::SnapshotChoice::Before(parse_timestamp(before).unwrap())
+                }
+                (None, None) => hat::hat::SnapshotChoice::Latest,
+            };
+
+            let mut hat = hat::Hat::open_with_params(repo.clone(), &params, &passphrase).unwrap();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            if let Err(e) = hat.checkout_snapshot_in_dir(name, choice, PathBuf::from(path)) {
+                fail(&format!("Could not checkout the snapshot: {}", e));
+            }
+        }
+        ("list", Some(cmd)) => {
+            let mut snapshots = hat::hat::list_snapshots(repo.clone()).unwrap();
+            if let Some(family) = cmd.value_of("family") {
+                snapshots.retain(|s| s.family_name == family);
+            }
 
-            hat.checkout_in_dir(name, PathBuf::from(path)).unwrap();
+            if cmd.is_present("json") {
+                println!("{}", json::encode(&snapshots).unwrap());
+            } else {
+                let mut family = None;
+                for s in &snapshots {
+                    if family != Some(&s.family_name) {
+                        println!("{}", s.family_name);
+                        family = Some(&s.family_name);
+                    }
+                    let timestamp = s.timestamp.map_or("-".to_owned(), format_timestamp);
+                    println!("  {:>4}  {:<11}  {:<20}  {}  {}",
+                             s.snapshot_id,
+                             s.state.name(),
+                             timestamp,
+                             s.hash.as_ref().map_or("-", |h| &h[..]),
+                             s.msg.as_ref().map_or("", |m| &m[..]));
+                }
+            }
         }
         ("recover", Some(_cmd)) => {
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            let mut hat = hat::Hat::open_with_params(repo.clone(), &params, &passphrase).unwrap();
 
             hat.recover().unwrap();
         }
@@ -134,21 +403,91 @@ fn main() {
             let name = cmd.value_of("NAME").unwrap().to_owned();
             let id = cmd.value_of("ID").unwrap().to_owned();
 
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            let mut hat = hat::Hat::open_with_params(repo.clone(), &params, &passphrase).unwrap();
 
             hat.deregister_by_name(name, id.parse::<i64>().unwrap()).unwrap();
         }
         ("gc", Some(_cmd)) => {
-            let backend = Arc::new(backend::FileBackend::new(blob_dir()));
-            let mut hat = hat::Hat::open_repository(PathBuf::from("repo"), backend, MAX_BLOB_SIZE)
-                .unwrap();
+            let mut hat = hat::Hat::open_with_params(repo.clone(), &params, &passphrase).unwrap();
             let (deleted_hashes, live_blobs) = hat.gc().unwrap();
             println!("Deleted hashes: {:?}", deleted_hashes);
             println!("Live data blobs after deletion: {:?}", live_blobs);
 
         }
+        ("train-dictionary", Some(_cmd)) => {
+            let mut hat = hat::Hat::open_with_params(repo.clone(), &params, &passphrase).unwrap();
+            match hat.train_dictionary().unwrap() {
+                Some(id) => println!("Trained compression dictionary {}", id),
+                None => println!("Not enough directory listings to train a dictionary yet"),
+            }
+        }
+        ("migrate-blobs", Some(cmd)) => {
+            reject_limits("migrate-blobs", &params);
+            let levels = match cmd.value_of("shard-levels") {
+                Some(n) => hat::hat::parse_shard_levels(n).unwrap(),
+                None => hat::hat::DEFAULT_SHARD_LEVELS,
+            };
+            let moved = hat::hat::migrate_blobs(repo.clone(), levels).unwrap();
+            println!("Moved {} blobs", moved);
+        }
+        ("repair-mirror", Some(_cmd)) => {
+            let copies = hat::hat::repair_mirror(repo.clone(), &params).unwrap();
+            println!("Copied {} blobs", copies);
+        }
+        ("scrub", Some(_cmd)) => {
+            let (rebuilt, degraded, lost) = hat::hat::scrub(repo.clone(), &params).unwrap();
+            println!("Rebuilt {} shards", rebuilt);
+            if degraded > 0 {
+                println!("{} blobs could not be fully checked or repaired because a backend \
+                          failed; run scrub again when it is back",
+                         degraded);
+            }
+            if lost > 0 {
+                println!("{} blobs have too few shards left to rebuild them", lost);
+            }
+        }
+        ("compact", Some(_cmd)) => {
+            reject_limits("compact", &params);
+            let freed = hat::hat::compact_packs(repo.clone()).unwrap();
+            println!("Freed {} bytes", freed);
+        }
+        ("rebuild-index", Some(_cmd)) => {
+            reject_limits("rebuild-index", &params);
+            let blobs = hat::hat::rebuild_pack_index(repo.clone()).unwrap();
+            println!("Found {} blobs", blobs);
+        }
+        ("key", Some(cmd)) => {
+            match cmd.subcommand() {
+                ("change-passphrase", Some(_cmd)) => {
+                    let new = new_passphrase().unwrap_or_else(|| fail("No new passphrase given"));
+                    hat::hat::change_passphrase(repo.clone(), &passphrase, &new).unwrap();
+                }
+                ("rotate", Some(_cmd)) => {
+                    // Ask once; we need the passphrase both to open and to rewrite the key file.
+                    let pass = passphrase()
+                        .unwrap_or_else(|| fail("A passphrase is required to rotate the key"));
+
+                    let mut hat =
+                        hat::Hat::open_with_params(repo.clone(), &params, &|| Some(pass.clone()))
+                            .unwrap();
+                    hat.rotate_key(&pass).unwrap();
+
+                    println!("Key rotated. Write-only clients need the new configuration and a \
+                              newly exported public key.");
+                }
+                ("export-public", Some(cmd)) => {
+                    let path = cmd.value_of("PATH").unwrap();
+                    hat::hat::export_public_key(repo.clone(), &passphrase, &PathBuf::from(path))
+                        .unwrap();
+                }
+                _ => {
+                    println!("No key subcommand specified\n{}\nFor more information re-run with \
+                              --help",
+                             cmd.usage());
+                    std::process::exit(1);
+                }
+            }
+        }
         _ => {
             println!("No subcommand specified\n{}\nFor more information re-run with --help",
                      matches.usage());
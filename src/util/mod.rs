@@ -18,6 +18,8 @@ mod fnbox;
 mod infowriter;
 mod listdir;
 mod sync_pool;
+#[cfg(test)]
+mod temp_dir;
 mod ordered_collection;
 mod periodic_timer;
 mod process;
@@ -31,4 +33,6 @@ pub use self::listdir::{HasPath, PathHandler};
 pub use self::periodic_timer::PeriodicTimer;
 pub use self::process::{MsgHandler, Process};
 pub use self::sync_pool::{SyncPool, SyncPoolGuard};
+#[cfg(test)]
+pub use self::temp_dir::TempDir;
 pub use self::unique_priority_queue::UniquePriorityQueue;
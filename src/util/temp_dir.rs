@@ -0,0 +1,49 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+//! Scratch directories for tests.
+
+use rand::{self, Rng};
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+
+/// A fresh directory under the system temporary directory. It is removed with everything in it
+/// when dropped, also when a test fails.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    /// Create a directory whose name starts with `prefix`.
+    pub fn new(prefix: &str) -> TempDir {
+        let path = env::temp_dir().join(format!("{}-{}", prefix, rand::thread_rng().next_u64()));
+        fs::create_dir_all(&path).unwrap();
+        TempDir(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        self.0.join(name)
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}